@@ -5,8 +5,23 @@ endif
 let g:loaded_graphical_preview = 1
 
 let s:path = resolve(expand('<sfile>:p:h') . "/../")
-let s:inst = libcallex#load(s:path . "/target/release/libvim_graphical_preview.so")
+let s:lib = has('win32') ? "/target/release/vim_graphical_preview.dll" : "/target/release/libvim_graphical_preview.so"
+let s:inst = libcallex#load(s:path . s:lib)
 let s:folds = []
+" extmarks following the first lines of the nodes through edits, by node id
+let s:anchors = {}
+let s:ns = has('nvim') ? nvim_create_namespace('graphical_preview') : -1
+" lines changed since the last update, as [start, old end, new end] counted from zero
+let s:region = []
+let s:region_timer = -1
+let s:diagnostics_ns = has('nvim') ? nvim_create_namespace('graphical_preview_diagnostics') : -1
+" filler lines below images taller than their lines, as [line, count] like the extmarks
+let s:fill_ns = has('nvim') ? nvim_create_namespace('graphical_preview_fill') : -1
+let s:reserved = []
+let s:jobs_timer = -1
+if !has('nvim')
+    call sign_define('GraphicalPreviewError', {'text': 'E>', 'texthl': 'ErrorMsg'})
+endif
 
 function! PrintError(msg) abort
     execute 'normal! \<Esc>'
@@ -15,13 +30,27 @@ function! PrintError(msg) abort
     echohl None
 endfunction
 
+" call an exported function, every reply is `{"ok": ...}` or `{"err": ...}` as for the Neovim
+" binary; returns the result, errors are printed unless quiet and give v:null
+function! s:Call(method, arg, ...) abort
+    let res = json_decode(s:inst.call(a:method, [a:arg], "string"))
+    if type(res) != v:t_dict || !has_key(res, 'ok')
+        if !get(a:, 1, 0)
+            call PrintError("Error: " . (type(res) == v:t_dict ? get(res, 'err', '') : 'invalid reply of ' . a:method))
+        endif
+        return v:null
+    endif
+
+    return res['ok']
+endfunction
+
 function! DrawInner(id)
-    let res = s:inst.call("draw", [""], "string")
-    let res = json_decode(res)
+    let res = s:Call("draw", "")
+    call s:WatchJobs()
 
-    if has_key(res, 'err')
-	call PrintError("Error: " . res['err'])
-    elseif has_key(res, 'ok') && res['ok'] == 1
+    if res is v:null
+	call s:UpdateDiagnostics()
+    elseif res == 1
 	call Draw()
     endif
 endfunction
@@ -34,21 +63,47 @@ function! Draw()
     let g:timer = timer_start(50, "DrawInner")
 endfunction
 
-function! s:UpdateMetadata()
-    let winpos = win_screenpos("0")
-    if exists('&number') && &number == 1
-        let winpos[1] += &numberwidth
+" first and last line of the closed folds between two lines of the current window
+function! s:ClosedFolds(top, bottom)
+    let folds = []
+    let lnum = a:top
+    while lnum <= a:bottom
+        if foldclosed(lnum) != -1
+            call add(folds, [foldclosed(lnum), foldclosedend(lnum)])
+            let lnum = foldclosedend(lnum)
+        endif
+        let lnum += 1
+    endwhile
+    return folds
+endfunction
+
+function! s:WindowMetadata(winid)
+    let info = getwininfo(a:winid)[0]
+    let folds = win_execute(a:winid, 'echon json_encode(s:ClosedFolds(' . info['topline'] . ', ' . info['botline'] . '))')
+    let winpos = [info['winrow'], info['wincol']]
+    let width = info['width']
+    if getwinvar(a:winid, '&number') == 1
+        let winpos[1] += getwinvar(a:winid, '&numberwidth')
+        let width -= getwinvar(a:winid, '&numberwidth')
     endif
 
-    let metadata = {
-       \'file_range': [line("w0"), line("w$") - &cmdheight + 1],
-       \'viewport': [&lines - &cmdheight - 1, &columns],
-       \'cursor': getcurpos()[1],
+    return {
+       \'winid': a:winid,
+       \'file_range': [info['topline'], info['botline'] - &cmdheight + 1],
+       \'viewport': [info['height'], width],
+       \'cursor': line('.', a:winid),
        \'winpos': winpos,
        \'char_height': 0,
+       \'closed_folds': json_decode(folds),
+       \'virtual_lines': s:reserved,
        \}
+endfunction
+
+" all windows showing the current buffer get previews
+function! s:UpdateMetadata()
+    let metadata = map(win_findbuf(bufnr('%')), {_, winid -> s:WindowMetadata(winid)})
 
-    call s:inst.call("update_metadata", [json_encode(metadata)], "")
+    call s:Call("update_metadata", json_encode(metadata))
     call Draw()
 endfunction
 
@@ -59,17 +114,92 @@ function! s:UpdateFolds()
         call add(l:folding_state, [lnum, foldclosedend(lnum)])
     endfor
     mode
-    let any_changed =  s:inst.call("set_folds", [json_encode(folding_state)], "")
+    let any_changed = s:Call("set_folds", json_encode(folding_state))
     if any_changed
         call Draw()
     endif
 endfunction
 
+" idle time before changes are sent while typing, negative if disabled
+function! s:Debounce()
+    let debounce = get(get(g:, 'graphical_preview_config', {}), 'debounce', '')
+    return type(debounce) == v:t_number ? debounce : -1
+endfunction
+
+" the markup languages parsed follow the filetype, like `rst` of `rst.graphics`
+function! s:SetFiletype()
+    call s:Call("set_filetype", &filetype)
+endfunction
+
 function! s:TextChanged()
     call s:UpdateMetadata()
+    let s:region = []
     let current_buf = join(getline(1,'$'), "\n")
-    let res = s:inst.call("update_content", [current_buf], "string")
-    let res = json_decode(res)['ok']
+    let res = s:Call("update_content", current_buf)
+    if res isnot v:null
+        call s:ContentUpdated(res)
+    endif
+endfunction
+
+" merge a change of the lines `first..last_old`, now `first..last_new`, into the pending region
+function! GraphicalPreviewChanged(first, last_old, last_new)
+    if empty(s:region)
+        let s:region = [a:first, a:last_old, a:last_new]
+    else
+        let [start, old_end, new_end] = s:region
+        let s:region = [
+           \min([start, a:first]),
+           \old_end + max([0, a:last_old - new_end]),
+           \max([new_end, a:last_old]) + a:last_new - a:last_old,
+           \]
+    endif
+
+    call timer_stop(s:region_timer)
+    let s:region_timer = timer_start(s:Debounce(), function('s:SendRegion'))
+endfunction
+
+function! s:SendRegion(id)
+    if empty(s:region)
+        return
+    endif
+
+    let [start, old_end, new_end] = s:region
+    let s:region = []
+    let region = {'start': start, 'end': old_end, 'lines': getline(start + 1, new_end)}
+    let res = s:Call("update_region", json_encode(region), 1)
+
+    " out of sync with the renderer, send everything
+    if res is v:null
+        return s:TextChanged()
+    endif
+
+    call s:UpdateMetadata()
+    call s:ContentUpdated(res)
+endfunction
+
+" report changes of the current buffer, once per buffer
+function! s:AttachBuffer()
+    if s:Debounce() < 0 || exists('b:graphical_preview_attached')
+        return
+    endif
+    let b:graphical_preview_attached = 1
+
+    if has('nvim')
+        lua vim.api.nvim_buf_attach(0, false, {
+           \ on_lines = function(_, buf, _, first, last_old, last_new)
+           \     if buf == vim.api.nvim_get_current_buf() then
+           \         vim.schedule(function() vim.fn.GraphicalPreviewChanged(first, last_old, last_new) end)
+           \     end
+           \ end })
+    else
+        call listener_add({bufnr, start, end, added, changes -> bufnr != bufnr('%') ? 0 : map(changes, {_, x -> GraphicalPreviewChanged(x['lnum'] - 1, x['end'] - 1, x['end'] - 1 + x['added'])})})
+    endif
+endfunction
+
+function! s:ContentUpdated(res)
+    let res = a:res
+    call s:WatchJobs()
+    call s:SetAnchors()
     if has_key(res, 'update_folding')
         let s:folds = res['update_folding']
         call s:UpdateFolds()
@@ -77,17 +207,221 @@ function! s:TextChanged()
     if res['should_redraw']
         call Draw()
     endif
+    if get(get(g:, 'graphical_preview_config', {}), 'mode', 'inline') ==# 'document' && &filetype =~# 'markdown'
+        call s:PreviewDocument()
+    endif
+    call s:ReserveLines()
+    call s:UpdateDiagnostics()
+endfunction
+
+" failed renders as diagnostics in Neovim, otherwise as signs and in the location list
+function! s:UpdateDiagnostics()
+    let res = s:Call("diagnostics", "", 1)
+    if res is v:null
+        return
+    endif
+
+    if has('nvim')
+        call luaeval('vim.diagnostic.set(_A[1], 0, vim.tbl_map(function(x) return {lnum = x.line - 1, col = x.column, message = x.message, source = "graphical-preview"} end, _A[2]))', [s:diagnostics_ns, res])
+    else
+        call sign_unplace('graphical_preview', {'buffer': bufnr('%')})
+        for diagnostic in res
+            call sign_place(0, 'graphical_preview', 'GraphicalPreviewError', bufnr('%'), {'lnum': diagnostic['line']})
+        endfor
+        call setloclist(0, map(copy(res), {_, x -> {'bufnr': bufnr('%'), 'lnum': x['line'], 'col': x['column'] + 1, 'text': x['message'], 'type': 'E'}}), 'r')
+    endif
+endfunction
+
+" with `virtual_lines` the text below tall images is pushed down by Neovim virtual lines
+function! s:ReserveLines()
+    if !has('nvim')
+        return
+    endif
+
+    let res = s:Call("virtual_lines", string(win_getid()), 1)
+    if res is v:null
+        return
+    endif
+
+    call nvim_buf_clear_namespace(0, s:fill_ns, 0, -1)
+    let reserved = []
+    for fill in res
+        let lines = repeat([[['', 'Normal']]], fill['count'])
+        if fill['line'] <= line('$')
+            call nvim_buf_set_extmark(0, s:fill_ns, fill['line'] - 1, 0, {'virt_lines': lines, 'virt_lines_above': v:true})
+        else
+            call nvim_buf_set_extmark(0, s:fill_ns, line('$') - 1, 0, {'virt_lines': lines})
+        endif
+        call add(reserved, [fill['line'], fill['count']])
+    endfor
+
+    if reserved != s:reserved
+        let s:reserved = reserved
+        call s:UpdateMetadata()
+    endif
+endfunction
+
+function! s:SetAnchors()
+    if !has('nvim')
+        return
+    endif
+
+    call nvim_buf_clear_namespace(0, s:ns, 0, -1)
+    let s:anchors = {}
+    let anchors = s:Call("anchors", "", 1)
+    for anchor in anchors is v:null ? [] : anchors
+        let s:anchors[anchor['id']] = nvim_buf_set_extmark(0, s:ns, anchor['line'] - 1, 0, {})
+    endfor
+endfunction
+
+" move the images along with the text while typing, without parsing the buffer again
+function! s:FollowAnchors()
+    let moved = []
+    for [id, mark] in items(s:anchors)
+        let pos = nvim_buf_get_extmark_by_id(0, s:ns, mark, {})
+        if !empty(pos)
+            call add(moved, {'id': id, 'line': pos[0] + 1})
+        endif
+    endfor
+
+    if s:Call("update_anchors", json_encode(moved), 1) == 1
+        call s:UpdateMetadata()
+    endif
+endfunction
+
+function! s:Cursor()
+    return {
+       \'winid': win_getid(),
+       \'line': line('.'),
+       \'col': charcol('.') - 1,
+       \'screen': [screenrow(), screencol()],
+       \}
+endfunction
+
+" in hover mode only the node under the cursor is shown, next to it
+function! s:PreviewAt()
+    if s:Call("preview_at", json_encode(s:Cursor()), 1) == 1
+        mode
+    endif
+endfunction
+
+" scale or play the image under the cursor, or all images with a bang
+function! s:AtCursor(method, all)
+    if s:Call(a:method, a:all ? "" : json_encode(s:Cursor())) == 1
+        mode
+        call Draw()
+        call s:ReserveLines()
+    endif
+endfunction
+
+command! -bang GraphicalPreviewZoomIn call <SID>AtCursor("zoom_in", <bang>0)
+command! -bang GraphicalPreviewZoomOut call <SID>AtCursor("zoom_out", <bang>0)
+command! -bang GraphicalPreviewZoomReset call <SID>AtCursor("zoom_reset", <bang>0)
+command! -bang GraphicalPreviewPlay call <SID>AtCursor("play", <bang>0)
+command! -bang GraphicalPreviewPause call <SID>AtCursor("pause", <bang>0)
+
+" in document mode the compiled buffer is shown in an empty window on the right
+function! s:PageWindow()
+    if exists('s:page_win') && win_id2win(s:page_win) > 0
+        return s:page_win
+    endif
+
+    vertical botright new
+    setlocal buftype=nofile bufhidden=wipe noswapfile nonumber norelativenumber nocursorline signcolumn=no
+    let s:page_win = win_getid()
+    wincmd p
+
+    return s:page_win
+endfunction
+
+function! s:PreviewDocument()
+    let winid = s:PageWindow()
+    let [row, col] = win_screenpos(winid)
+    let window = {
+       \'winid': winid,
+       \'line': line('.'),
+       \'winpos': [row, col],
+       \'viewport': [winheight(winid), winwidth(winid)],
+       \'dir': expand('%:p:h'),
+       \}
+
+    if s:Call("preview_document", json_encode(window), 1) == 1
+        mode
+    endif
+    call Draw()
+endfunction
+
+function! s:CursorMoved()
+    let mode = get(get(g:, 'graphical_preview_config', {}), 'mode', 'inline')
+    if mode ==# 'hover'
+        call s:PreviewAt()
+    elseif mode ==# 'document' && &filetype =~# 'markdown'
+        call s:PreviewDocument()
+    endif
+    call s:UpdateMetadata()
+endfunction
+
+function! s:UpdateConfig()
+    let config = extend({'colorscheme': &background}, get(g:, 'graphical_preview_config', {}))
+    if s:Call("update_config", json_encode(config)) == 1
+        mode
+        call Draw()
+    endif
+    call s:WatchJobs()
+endfunction
+
+function! s:SyncDocument()
+    call s:Call("sync_document", "")
+    call s:WatchJobs()
+endfunction
+
+" poll finished renders while jobs are outstanding, the timer stops once none are left
+function! s:WatchJobs()
+    if s:jobs_timer == -1
+        let s:jobs_timer = timer_start(100, function('s:PollJobs'), {'repeat': -1})
+    endif
+endfunction
+
+function! s:PollJobs(id)
+    let res = s:Call("finished_jobs", "", 1)
+    if res is v:null || !res['pending']
+        call timer_stop(s:jobs_timer)
+        let s:jobs_timer = -1
+    endif
+
+    if res isnot v:null && !empty(res['ids'])
+        call Draw()
+        call s:ReserveLines()
+        call s:UpdateDiagnostics()
+    endif
 endfunction
 
 function! s:ClearAll()
-    call s:inst.call("clear_all", [""], "")
+    call s:Call("clear_all", "")
     mode
 endfunction
 
-:autocmd VimEnter,TextChanged,InsertLeave * call <SID>TextChanged()
-:autocmd VimResized * call <SID>UpdateMetadata()
-:autocmd CursorMoved * call <SID>UpdateMetadata()
+function! s:CancelJobs()
+    call s:Call("cancel_jobs", "")
+endfunction
+
+
+:autocmd VimEnter * call <SID>UpdateConfig()
+:autocmd OptionSet background call <SID>UpdateConfig()
+:autocmd VimEnter,BufEnter * call <SID>SetFiletype()
+:autocmd VimEnter,BufEnter * call <SID>TextChanged()
+:autocmd FileType * call <SID>SetFiletype() | call <SID>TextChanged()
+:autocmd VimEnter,BufEnter * call <SID>AttachBuffer()
+" with a debounce the changed regions are sent while typing instead
+:autocmd TextChanged,InsertLeave * if <SID>Debounce() < 0 | call <SID>TextChanged() | endif
+:autocmd BufWritePost * call <SID>SyncDocument()
+:autocmd VimResized,WinEnter,WinScrolled * call <SID>UpdateMetadata()
+:autocmd CursorMoved * call <SID>CursorMoved()
 :autocmd InsertEnter * call <SID>ClearAll()
+if has('nvim')
+    :autocmd TextChangedI * call <SID>FollowAnchors()
+endif
+:autocmd VimLeavePre * call <SID>CancelJobs()
 
 map zo :foldopen<CR>:call <SID>UpdateFolds()<CR>
 map zc :foldclose<CR>:call <SID>UpdateFolds()<CR>
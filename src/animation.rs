@@ -0,0 +1,133 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::{AnimationDecoder, ImageError, ImageResult, RgbaImage};
+
+use crate::error::{Error, Result};
+
+/// Frames decoded at most, longer animations end early
+const MAX_FRAMES: usize = 256;
+
+/// Pixels of all decoded frames together at most, 128 MB of RGBA, large animations end early
+const MAX_PIXELS: u64 = 32 * 1024 * 1024;
+
+/// Id of the next decoded animation
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Browsers show frames with shorter delays for 100 ms, files rely on that
+const MIN_DELAY: Duration = Duration::from_millis(20);
+const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+
+/// Frames of an animated GIF or APNG with the time each is shown, all as large as the image
+pub struct Animation {
+    /// unique per decoded animation, terminals keeping images by id replace the previous upload
+    pub id: u32,
+    pub frames: Vec<(RgbaImage, Duration)>,
+}
+
+impl Animation {
+    /// Index of the frame shown some time after the start, the animation loops forever
+    pub fn frame_at(&self, elapsed: Duration) -> usize {
+        let total = self.frames.iter().map(|x| x.1.as_millis()).sum::<u128>().max(1);
+        let mut time = elapsed.as_millis() % total;
+
+        for (idx, (_, delay)) in self.frames.iter().enumerate() {
+            if time < delay.as_millis() {
+                return idx;
+            }
+            time -= delay.as_millis();
+        }
+
+        self.frames.len() - 1
+    }
+}
+
+/// Decode the frames of an animated GIF or APNG, `None` for still images and other formats
+pub fn decode(path: &Path) -> Result<Option<Animation>> {
+    let invalid = |_: ImageError| Error::InvalidImage(path.to_string_lossy().to_string());
+    let reader = || File::open(path).map(BufReader::new).map_err(Error::Io);
+    let extension = path.extension()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    let frames = match extension.as_str() {
+        "gif" => GifDecoder::new(reader()?).map_err(invalid)?.into_frames(),
+        "png" => {
+            let decoder = PngDecoder::new(reader()?).map_err(invalid)?;
+            if !decoder.is_apng().map_err(invalid)? {
+                return Ok(None);
+            }
+
+            decoder.apng().map_err(invalid)?.into_frames()
+        },
+        _ => return Ok(None),
+    };
+
+    // frames are composited onto the canvas by the decoders already
+    let mut pixels = 0;
+    let frames = frames.take(MAX_FRAMES)
+        .map(|x| x.map(|frame| {
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            let delay = Duration::from_millis((numer / denom.max(1)) as u64);
+
+            (frame.into_buffer(), if delay < MIN_DELAY { DEFAULT_DELAY } else { delay })
+        }))
+        .take_while(|x| {
+            pixels += x.as_ref().map_or(0, |(image, _)| image.width() as u64 * image.height() as u64);
+            pixels <= MAX_PIXELS
+        })
+        .collect::<ImageResult<Vec<_>>>()
+        .map_err(invalid)?;
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    Ok((frames.len() > 1).then_some(Animation { id, frames }))
+}
+
+/// How a terminal backend shows animations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Playback {
+    /// all frames are sent at once and played by the terminal, like the kitty animation protocol
+    Terminal,
+    /// frames are drawn over each other by the editor, at most `animation_fps` times a second
+    Frames,
+    /// only the first frame is shown
+    Still,
+}
+
+/// Position in an animation, standing still while paused
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    elapsed: Duration,
+    /// start of the current run, `None` while paused
+    resumed: Option<Instant>,
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock { elapsed: Duration::ZERO, resumed: Some(Instant::now()) }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed + self.resumed.map_or(Duration::ZERO, |x| x.elapsed())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.resumed.is_some()
+    }
+
+    pub fn pause(&mut self) {
+        self.elapsed = self.elapsed();
+        self.resumed = None;
+    }
+
+    pub fn play(&mut self) {
+        self.resumed.get_or_insert_with(Instant::now);
+    }
+}
@@ -0,0 +1,110 @@
+use image::imageops::{self, FilterType};
+use image::{Rgba, RgbaImage};
+
+/// Pixels with less opacity are treated as background
+const ALPHA_THRESHOLD: u32 = 64;
+
+/// Render an image with colored half blocks, for terminals without graphics support
+///
+/// Each character cell shows two vertically stacked pixels, the upper one as foreground of `▀`
+/// and the lower one as background, both in 24-bit color. The image is downsampled by averaging
+/// to at most `max_cols` columns, transparent regions keep the terminal background.
+pub fn to_ansi_blocks(image: &RgbaImage, max_cols: usize) -> String {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 || max_cols == 0 {
+        return String::new();
+    }
+
+    // every column covers `step` pixels, every cell two rows of `step` pixels
+    let cols = width.min(max_cols as u32);
+    let step = width as f32 / cols as f32;
+    let rows = ((height as f32 / step) / 2.0).ceil() as u32;
+
+    let mut out = String::new();
+    for row in 0..rows {
+        for col in 0..cols {
+            let upper = average(image, col as f32 * step, (2 * row) as f32 * step, step);
+            let lower = average(image, col as f32 * step, (2 * row + 1) as f32 * step, step);
+
+            match (upper, lower) {
+                (Some(upper), Some(lower)) => out.push_str(&format!(
+                    "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m▀",
+                    upper[0], upper[1], upper[2], lower[0], lower[1], lower[2]
+                )),
+                (Some(upper), None) => out.push_str(&format!(
+                    "\x1b[38;2;{};{};{}m\x1b[49m▀", upper[0], upper[1], upper[2]
+                )),
+                (None, Some(lower)) => out.push_str(&format!(
+                    "\x1b[38;2;{};{};{}m\x1b[49m▄", lower[0], lower[1], lower[2]
+                )),
+                (None, None) => out.push_str("\x1b[0m "),
+            }
+        }
+        out.push_str("\x1b[0m\n");
+    }
+
+    out
+}
+
+/// Render an image into exactly `cols` by `rows` terminal cells
+///
+/// Cells are usually twice as high as wide, the image is first resized to two pixels per cell
+/// vertically and one horizontally so that it keeps its shape.
+pub fn to_cells(image: &RgbaImage, cols: usize, rows: usize) -> String {
+    if cols == 0 || rows == 0 {
+        return String::new();
+    }
+
+    let resized = imageops::resize(image, cols as u32, 2 * rows as u32, FilterType::Triangle);
+
+    to_ansi_blocks(&resized, cols)
+}
+
+/// Average color of the square of size `step` at (x, y), `None` if mostly transparent
+fn average(image: &RgbaImage, x: f32, y: f32, step: f32) -> Option<Rgba<u8>> {
+    let (width, height) = image.dimensions();
+    let (x0, y0) = (x as u32, y as u32);
+
+    // the lower half of the last row may lie below the image
+    if y0 >= height {
+        return None;
+    }
+
+    let x1 = ((x + step).ceil() as u32).clamp(x0 + 1, width);
+    let y1 = ((y + step).ceil() as u32).clamp(y0 + 1, height);
+
+    // weigh colors by opacity, so that antialiased edges don't darken
+    let (mut sum, mut alpha, mut count) = ([0u32; 3], 0u32, 0u32);
+    for py in y0..y1 {
+        for px in x0..x1 {
+            let pixel = image.get_pixel(px, py);
+            let a = pixel[3] as u32;
+            for idx in 0..3 {
+                sum[idx] += pixel[idx] as u32 * a;
+            }
+            alpha += a;
+            count += 1;
+        }
+    }
+
+    if alpha == 0 || alpha / count < ALPHA_THRESHOLD {
+        return None;
+    }
+
+    Some(Rgba([(sum[0] / alpha) as u8, (sum[1] / alpha) as u8, (sum[2] / alpha) as u8, 255]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_fill_their_cells() {
+        let image = RgbaImage::from_pixel(40, 90, Rgba([255, 0, 0, 255]));
+        let blocks = to_cells(&image, 4, 5);
+
+        assert_eq!(blocks.lines().count(), 5);
+        assert!(blocks.lines().all(|x| x.matches('▀').count() == 4));
+        assert_eq!(to_cells(&image, 0, 5), "");
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+
+use miniserde::{json, Serialize};
+
+use crate::config::Config;
+use crate::content::{CacheStatus, ContentType, Memory, RenderInfo};
+use crate::error::{Error, Result};
+use crate::render::art_path;
+use crate::{jobs, svg, utils};
+
+/// Render snippets concurrently, returning the results in the order of `items`
+///
+/// Results of earlier renders are taken from `memory` without touching the disk. Linked files are
+/// always resolved again, they may change at any time. However the compilations interleave, the
+/// outcome is the same as rendering one after another.
+pub fn render_all(items: &[(String, ContentType)], config: &Config, memory: &Memory) -> Vec<Result<RenderInfo>> {
+    let mut results = items.iter().map(|_| None).collect::<Vec<_>>();
+    for (idx, res) in render_stream(items, config, memory) {
+        results[idx] = Some(res);
+    }
+
+    // the stream yields every index exactly once
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+/// Results of a batch in the order they become available
+///
+/// Yields `(index, result)` pairs, where `index` is the position of the snippet in the batch.
+/// Cached snippets come first, the others follow in the order their compilation finishes.
+pub struct Stream<'a> {
+    ready: VecDeque<(usize, Result<RenderInfo>)>,
+    pending: Receiver<(usize, Result<RenderInfo>)>,
+    keys: Vec<Option<String>>,
+    memory: &'a Memory,
+}
+
+impl Iterator for Stream<'_> {
+    type Item = (usize, Result<RenderInfo>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(res) = self.ready.pop_front() {
+            return Some(res);
+        }
+
+        // the channel is closed once every worker has sent its result
+        let (idx, res) = self.pending.recv().ok()?;
+        if let (Ok(info), Some(key)) = (&res, self.keys[idx].take()) {
+            self.memory.lock().unwrap().insert(key, info.clone());
+        }
+
+        Some((idx, res))
+    }
+}
+
+/// Render snippets concurrently, streaming the results
+///
+/// Snippets found in `memory` or the cache directory are resolved right away, every other one is
+/// queued in the worker pool. The number of processes is still bounded by `max_processes`.
+pub fn render_stream<'a>(items: &[(String, ContentType)], config: &Config, memory: &'a Memory) -> Stream<'a> {
+    let (sender, pending) = mpsc::channel();
+    let mut ready = VecDeque::new();
+    let mut keys = vec![None; items.len()];
+
+    for (idx, (content, kind)) in items.iter().enumerate() {
+        if *kind != ContentType::File {
+            let key = kind.key(content, config);
+            if let Some(mut info) = memory.lock().unwrap().get(&key) {
+                info.status = CacheStatus::Hit;
+                ready.push_back((idx, Ok(info)));
+                continue;
+            }
+
+            keys[idx] = Some(key);
+        }
+
+        if kind.path(content, config).exists() {
+            let res = kind.render(content, config);
+            if let (Ok(info), Some(key)) = (&res, keys[idx].take()) {
+                memory.lock().unwrap().insert(key, info.clone());
+            }
+
+            ready.push_back((idx, res));
+            continue;
+        }
+
+        let id = kind.key(content, config);
+        let (content, kind, config, sender) = (content.clone(), kind.clone(), config.clone(), sender.clone());
+        jobs::queue().submit(&id, move || {
+            let _ = sender.send((idx, jobs::catch(|| kind.render(&content, &config))));
+        });
+    }
+
+    Stream { ready, pending, keys, memory }
+}
+
+/// Entry of the manifest, describing the outcome of a single snippet
+#[derive(Serialize)]
+struct ManifestEntry {
+    key: String,
+    status: &'static str,
+    path: Option<String>,
+    width: Option<f32>,
+    height: Option<f32>,
+    depth: Option<f32>,
+    raster: Option<String>,
+    warnings: Vec<String>,
+    error: Option<String>,
+}
+
+/// JSON manifest mapping the inputs of a batch to their outputs
+///
+/// The manifest is an array of objects with the fields `key`, `status` (`hit`, `miss` or
+/// `error`), `path`, `width`, `height`, `depth`, `raster`, `warnings` and `error`. Fields not
+/// applying are `null`.
+pub fn manifest(items: &[(String, ContentType)], results: &[Result<RenderInfo>], config: &Config) -> String {
+    let entries = items.iter().zip(results)
+        .map(|((content, kind), res)| match res {
+            Ok(info) => ManifestEntry {
+                key: info.key.clone(),
+                status: match info.status {
+                    CacheStatus::Hit => "hit",
+                    CacheStatus::Miss => "miss",
+                },
+                path: Some(info.path.clone()),
+                width: Some(info.width),
+                height: Some(info.height),
+                depth: info.depth,
+                raster: info.raster.clone(),
+                warnings: info.warnings.clone(),
+                error: None,
+            },
+            Err(err) => ManifestEntry {
+                key: kind.key(content, config),
+                status: "error",
+                path: None,
+                width: None,
+                height: None,
+                depth: None,
+                raster: None,
+                warnings: Vec::new(),
+                error: Some(err.to_string()),
+            },
+        })
+        .collect::<Vec<_>>();
+
+    json::to_string(&entries)
+}
+
+/// Render a batch of snippets and store the manifest at the given path
+pub fn write_manifest(dest: &str, items: &[(String, ContentType)], config: &Config, memory: &Memory) -> Result<String> {
+    let results = render_all(items, config, memory);
+    let manifest = manifest(items, &results, config);
+
+    fs::write(dest, &manifest).map_err(Error::Io)?;
+
+    Ok(manifest)
+}
+
+const REPORT_FONT_SIZE: f32 = 10.0;
+const REPORT_LINE_HEIGHT: f32 = 12.0;
+const REPORT_PADDING: f32 = 6.0;
+
+/// Stack the sources of failed snippets and their errors in a single SVG
+///
+/// The report is stored in the cache directory, named after the digest of its content.
+pub fn render_error_report(failures: &[(String, Error)], config: &Config) -> Result<PathBuf> {
+    let mut lines = Vec::new();
+    for (idx, (content, err)) in failures.iter().enumerate() {
+        if idx > 0 {
+            lines.push((String::new(), "none"));
+        }
+
+        lines.extend(content.lines().map(|x| (x.to_string(), "#555555")));
+        lines.extend(err.to_string().lines().map(|x| (x.to_string(), "#cc0000")));
+    }
+
+    // a character of a monospace font is about 0.6 times as wide as high
+    let columns = lines.iter().map(|x| x.0.chars().count()).max().unwrap_or(0);
+    let width = columns as f32 * REPORT_FONT_SIZE * 0.6 + 2.0 * REPORT_PADDING;
+    let height = lines.len() as f32 * REPORT_LINE_HEIGHT + 2.0 * REPORT_PADDING;
+    let background = if config.background.is_empty() { "white" } else { &config.background };
+
+    let mut report = format!(
+        "<svg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 {0} {1}' width='{0}pt' height='{1}pt'>\n<rect width='{0}' height='{1}' fill='{2}'/>\n",
+        width, height, svg::escape(background)
+    );
+
+    for (idx, (text, color)) in lines.iter().enumerate() {
+        let y = REPORT_PADDING + (idx + 1) as f32 * REPORT_LINE_HEIGHT - 2.0;
+
+        report.push_str(&format!(
+            "<text x='{}' y='{}' fill='{}' font-family='monospace' font-size='{}' xml:space='preserve'>{}</text>\n",
+            REPORT_PADDING, y, color, REPORT_FONT_SIZE, svg::escape(text)
+        ));
+    }
+    report.push_str("</svg>");
+
+    let path = art_path()
+        .join(format!("report-{}", utils::hash(&report)))
+        .with_extension("svg");
+    fs::write(&path, report).map_err(Error::Io)?;
+
+    Ok(path)
+}
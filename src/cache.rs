@@ -0,0 +1,527 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::hash::Hash;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use miniserde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::content::ContentType;
+use crate::error::{Error, Result};
+use crate::render::art_path;
+use crate::runner::{self, CommandRunner};
+use crate::{gc, utils};
+
+/// Derives the name under which a rendered snippet is cached
+pub trait CacheKey {
+    fn key(&self, content: &str, config: &Config) -> String;
+}
+
+/// Only the content is hashed, options are ignored
+pub struct ContentKey;
+
+impl CacheKey for ContentKey {
+    fn key(&self, content: &str, _: &Config) -> String {
+        utils::hash(content)
+    }
+}
+
+/// Content and all options deviating from their default are hashed
+///
+/// With default options this reproduces the plain content hash.
+pub struct ConfigKey;
+
+impl CacheKey for ConfigKey {
+    fn key(&self, content: &str, config: &Config) -> String {
+        let fingerprint = config.fingerprint();
+
+        if fingerprint.is_empty() {
+            utils::hash(content)
+        } else {
+            utils::hash(&format!("{}\n{}", fingerprint, content))
+        }
+    }
+}
+
+/// Like `ConfigKey`, but also invalidated by updates of latex or dvisvgm
+pub struct ToolchainKey;
+
+impl CacheKey for ToolchainKey {
+    fn key(&self, content: &str, config: &Config) -> String {
+        utils::hash(&format!("{}\n{}", toolchain_fingerprint(config), ConfigKey.key(content, config)))
+    }
+}
+
+/// Prefixes the key of another strategy, for example to separate documents
+pub struct Namespaced<'a, K: CacheKey>(pub &'a str, pub K);
+
+impl<K: CacheKey> CacheKey for Namespaced<'_, K> {
+    fn key(&self, content: &str, config: &Config) -> String {
+        format!("{}-{}", self.0, self.1.key(content, config))
+    }
+}
+
+/// Selects one of the provided key strategies from the configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyStrategy {
+    Content,
+    Config,
+    Toolchain,
+}
+
+impl KeyStrategy {
+    pub fn key(&self, content: &str, config: &Config) -> String {
+        match self {
+            KeyStrategy::Content => namespaced(ContentKey, content, config),
+            KeyStrategy::Config => namespaced(ConfigKey, content, config),
+            KeyStrategy::Toolchain => namespaced(ToolchainKey, content, config),
+        }
+    }
+}
+
+impl FromStr for KeyStrategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> result::Result<Self, ()> {
+        match s {
+            "content" => Ok(KeyStrategy::Content),
+            "config" => Ok(KeyStrategy::Config),
+            "toolchain" => Ok(KeyStrategy::Toolchain),
+            _ => Err(()),
+        }
+    }
+}
+
+fn namespaced<K: CacheKey>(strategy: K, content: &str, config: &Config) -> String {
+    match config.cache_namespace.as_str() {
+        "" => strategy.key(content, config),
+        namespace => Namespaced(namespace, strategy).key(content, config),
+    }
+}
+
+/// Version output of the external binaries, queried once per session
+fn toolchain_fingerprint(config: &Config) -> &'static str {
+    static FINGERPRINT: OnceLock<String> = OnceLock::new();
+
+    FINGERPRINT.get_or_init(|| fingerprint(&*runner::runner(config)))
+}
+
+/// First lines of the version output of latex and dvisvgm
+fn fingerprint(runner: &dyn CommandRunner) -> String {
+    ["latex", "dvisvgm"].iter()
+        .filter_map(|binary| runner::version(runner, binary))
+        .map(|out| out.lines().next().unwrap_or("").to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Whether artifacts rendered from a source file are reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreshnessPolicy {
+    /// Reuse artifacts whenever present
+    AlwaysUseCache,
+    /// Render again every time
+    NeverUseCache,
+    /// Render again if the source file was modified after the artifact
+    UseCacheUnlessSourceNewer,
+}
+
+impl FreshnessPolicy {
+    /// Whether the artifact at `artifact` may be used for the file at `source`
+    pub fn is_fresh(&self, artifact: &Path, source: &Path) -> bool {
+        let modified = |path: &Path| fs::metadata(path).and_then(|x| x.modified()).ok();
+
+        match self {
+            FreshnessPolicy::AlwaysUseCache => true,
+            FreshnessPolicy::NeverUseCache => false,
+            FreshnessPolicy::UseCacheUnlessSourceNewer => match (modified(artifact), modified(source)) {
+                (Some(artifact), Some(source)) => artifact >= source,
+                _ => false,
+            },
+        }
+    }
+}
+
+impl FromStr for FreshnessPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> result::Result<Self, ()> {
+        match s {
+            "always" => Ok(FreshnessPolicy::AlwaysUseCache),
+            "never" => Ok(FreshnessPolicy::NeverUseCache),
+            "mtime" => Ok(FreshnessPolicy::UseCacheUnlessSourceNewer),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Keys of all entries currently stored in the cache directory
+pub fn cached_keys() -> Result<BTreeSet<String>> {
+    let entries = fs::read_dir(art_path()).map_err(Error::Io)?;
+
+    Ok(entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| key_of(&entry.path()).map(|x| x.to_string()))
+        .collect())
+}
+
+/// Key a file of the cache directory belongs to, the name up to the first dot
+///
+/// Sidecars carry several extensions, like `<key>.metrics.json`, keys never contain a dot.
+fn key_of(path: &Path) -> Option<&str> {
+    path.file_name()?.to_str()?.split('.').next()
+}
+
+/// Artifacts stored in the cache under a single key
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub key: String,
+    pub files: Vec<PathBuf>,
+    /// Total size of all files in bytes
+    pub size: u64,
+    /// Last modification of any of the files
+    pub modified: SystemTime,
+    /// Last access of any of the files, see `touch`
+    pub accessed: SystemTime,
+}
+
+/// Callback invoked for every entry removed from the cache
+pub type EvictHook = Box<dyn FnMut(&CacheEntry) + Send>;
+
+/// Collect the artifacts of a cache entry, if there are any
+pub fn entry(key: &str) -> Result<Option<CacheEntry>> {
+    Ok(grouped(|x| x == key)?.pop())
+}
+
+/// All entries of the cache directory, grouped by key
+pub fn entries() -> Result<Vec<CacheEntry>> {
+    grouped(|_| true)
+}
+
+/// Entries of the keys matching `filter`, reading the cache directory once
+fn grouped(filter: impl Fn(&str) -> bool) -> Result<Vec<CacheEntry>> {
+    let mut entries: BTreeMap<String, CacheEntry> = BTreeMap::new();
+
+    for file in fs::read_dir(art_path()).map_err(Error::Io)?.filter_map(|entry| entry.ok()) {
+        let path = file.path();
+        let Some(key) = key_of(&path).filter(|x| filter(x)) else {
+            continue;
+        };
+
+        let entry = entries.entry(key.to_string()).or_insert_with(|| CacheEntry {
+            key: key.to_string(),
+            files: Vec::new(),
+            size: 0,
+            modified: SystemTime::UNIX_EPOCH,
+            accessed: SystemTime::UNIX_EPOCH,
+        });
+
+        let meta = file.metadata().map_err(Error::Io)?;
+        entry.size += meta.len();
+        entry.modified = meta.modified().map_err(Error::Io)?.max(entry.modified);
+        entry.accessed = meta.accessed().unwrap_or(entry.modified).max(entry.accessed);
+        entry.files.push(path);
+    }
+
+    Ok(entries.into_values().collect())
+}
+
+/// Record an access of an artifact
+///
+/// Most file systems are mounted with `relatime` or `noatime`, so the access time is set
+/// explicitly. The modification time is left alone, the freshness policies depend on it.
+pub fn touch(path: &Path) {
+    if let Ok(file) = fs::File::options().append(true).open(path) {
+        let _ = file.set_times(fs::FileTimes::new().set_accessed(SystemTime::now()));
+    }
+}
+
+/// Remove all artifacts of a cache entry, returning what was removed
+pub fn evict(key: &str) -> Result<Option<CacheEntry>> {
+    let entry = entry(key)?;
+
+    for path in entry.iter().flat_map(|x| &x.files) {
+        fs::remove_file(path).map_err(Error::Io)?;
+    }
+
+    Ok(entry)
+}
+
+/// Whether the cached artifact of a snippet really was rendered from it
+///
+/// The key is derived anew and, if the latex source of the entry was stored, the processed source
+/// has to be part of it. This detects hash collisions and tampering without rendering again.
+/// Sources written by gnuplot don't contain the script and are not compared.
+pub fn verify_entry(kind: &ContentType, content: &str, config: &Config) -> Result<bool> {
+    if !kind.path(content, config).exists() {
+        return Ok(false);
+    }
+
+    let Some(key) = kind.source_key(content, config) else {
+        return Ok(true);
+    };
+
+    match fs::read_to_string(art_path().join(key).with_extension("tex")) {
+        Ok(source) if source.starts_with("% GNUPLOT") => Ok(true),
+        Ok(source) => Ok(source.contains(&*kind.source(content, config))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(true),
+        Err(err) => Err(Error::Io(err)),
+    }
+}
+
+/// Bounded in-memory map, dropping the least recently used entry when full
+pub struct LruCache<K, V> {
+    capacity: usize,
+    entries: HashMap<K, V>,
+    /// keys from least to most recently used
+    order: VecDeque<K>,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> LruCache<K, V> {
+        LruCache {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let value = self.entries.get(key)?.clone();
+        self.touch(key);
+
+        Some(value)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.entries.insert(key.clone(), value).is_some() {
+            self.touch(&key);
+            return;
+        }
+
+        self.order.push_back(key);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if self.entries.remove(key).is_some() {
+            self.order.retain(|x| x != key);
+        }
+    }
+
+    fn touch(&mut self, key: &K) {
+        if let Some(idx) = self.order.iter().position(|x| x == key) {
+            let key = self.order.remove(idx).unwrap();
+            self.order.push_back(key);
+        }
+    }
+}
+
+/// Outcome of synchronizing the cache with a document
+#[derive(Debug, Default, Serialize)]
+pub struct SyncReport {
+    pub rendered: Vec<String>,
+    pub evicted: Vec<String>,
+    /// Entries removed to stay below the size limit
+    pub collected: Vec<String>,
+}
+
+/// Keys of the blocks seen at the last synchronization of a document
+#[derive(Default)]
+pub struct DocumentSync {
+    seen: BTreeSet<String>,
+    on_evict: Option<EvictHook>,
+}
+
+impl DocumentSync {
+    /// Invoke `hook` for every entry evicted by a synchronization or `collect`
+    pub fn on_evict(&mut self, hook: EvictHook) {
+        self.on_evict = Some(hook);
+    }
+
+    /// Evict the least recently used entries beyond `limit` bytes, see `gc::collect`
+    pub fn collect(&mut self, limit: u64, keep: &BTreeSet<String>) -> Result<Vec<CacheEntry>> {
+        let hook = &mut self.on_evict;
+
+        gc::collect(limit, keep, |entry| {
+            if let Some(hook) = hook.as_mut() {
+                hook(entry);
+            }
+        })
+    }
+
+    /// Diff the current blocks against the last synchronization
+    ///
+    /// New blocks are handed to `render` (which should not block) and reported if it started a job,
+    /// new blocks found in the cache are not. The artifacts of removed blocks are evicted.
+    pub fn sync<'a, F>(&mut self, blocks: impl Iterator<Item = &'a str>, mut render: F) -> Result<SyncReport>
+    where F: FnMut(&str) -> bool
+    {
+        let current = blocks.map(|x| x.to_string()).collect::<BTreeSet<_>>();
+        let mut report = SyncReport::default();
+
+        for key in current.difference(&self.seen) {
+            if render(key) {
+                report.rendered.push(key.clone());
+            }
+        }
+
+        for key in self.seen.difference(&current) {
+            if art_path().exists() {
+                if let (Some(entry), Some(hook)) = (evict(key)?, self.on_evict.as_mut()) {
+                    hook(&entry);
+                }
+            }
+            report.evicted.push(key.clone());
+        }
+
+        self.seen = current;
+
+        Ok(report)
+    }
+}
+
+const BASE32: &[u8] = b"0123456789abcdefghjkmnpqrstvwxyz";
+const DISPLAY_ID_LEN: usize = 8;
+
+/// Crockford base32 digest of a cache key
+fn base32_digest(key: &str) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+
+    // use five bits per character, 160 bits of the digest are plenty
+    (0..32)
+        .map(|idx| {
+            let bit = idx * 5;
+            let word = ((digest[bit / 8] as u16) << 8) | digest[bit / 8 + 1] as u16;
+            BASE32[((word >> (11 - bit % 8)) & 0x1f) as usize] as char
+        })
+        .collect()
+}
+
+/// Short and human friendly label of a cache key
+///
+/// The label is the first eight characters of the key's base32 digest, so that it stays the same
+/// however the cache grows. In the rare case that another entry shares them, the whole digest is
+/// used. The key itself stays the file name.
+pub fn display_id(key: &str) -> Result<String> {
+    let others = cached_keys()?.into_iter()
+        .filter(|other| other != key)
+        .map(|other| base32_digest(&other))
+        .collect::<Vec<_>>();
+
+    Ok(label(&base32_digest(key), &others))
+}
+
+fn label(digest: &str, others: &[String]) -> String {
+    let prefix = &digest[..DISPLAY_ID_LEN];
+
+    if others.iter().any(|other| other.starts_with(prefix)) {
+        digest.to_string()
+    } else {
+        prefix.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::render;
+
+    #[cfg(unix)]
+    #[test]
+    fn fingerprint_of_versions() {
+        let runner = runner::VersionRunner(vec![
+            ("latex", "pdfTeX 3.141592653-2.6-1.40.25 (TeX Live 2023)\nkpathsea version 6.3.5\n"),
+            ("dvisvgm", "dvisvgm 3.1.2\n"),
+        ]);
+
+        assert_eq!(fingerprint(&runner), "pdfTeX 3.141592653-2.6-1.40.25 (TeX Live 2023)\ndvisvgm 3.1.2");
+    }
+
+    #[test]
+    fn sidecars_belong_to_their_key() {
+        render::test_art_path();
+        for name in ["sidecars.svg", "sidecars.metrics.json", "sidecars.svg.png", "sidecars-zoom150.svg"] {
+            fs::write(art_path().join(name), "x").unwrap();
+        }
+
+        let grouped = entries().unwrap().into_iter().find(|x| x.key == "sidecars").unwrap();
+        assert_eq!(grouped.files.len(), 3);
+        assert_eq!(grouped.size, 3);
+        assert_eq!(entry("sidecars").unwrap().unwrap().files.len(), 3);
+        assert!(cached_keys().unwrap().contains("sidecars-zoom150"));
+
+        evict("sidecars").unwrap();
+        assert!(!art_path().join("sidecars.metrics.json").exists());
+        assert!(art_path().join("sidecars-zoom150.svg").exists());
+    }
+
+    #[test]
+    fn evictions_are_reported() {
+        render::test_art_path();
+        fs::write(art_path().join("evicted-by-sync.svg"), "<svg/>").unwrap();
+
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let mut sync = DocumentSync::default();
+        let log = evicted.clone();
+        sync.on_evict(Box::new(move |entry| log.lock().unwrap().push(entry.key.clone())));
+
+        sync.sync(["evicted-by-sync"].into_iter(), |_| true).unwrap();
+        sync.sync([].into_iter(), |_| true).unwrap();
+
+        assert_eq!(*evicted.lock().unwrap(), ["evicted-by-sync"]);
+        assert!(!art_path().join("evicted-by-sync.svg").exists());
+    }
+
+    #[test]
+    fn cache_hits_are_not_rendered() {
+        render::test_art_path();
+        let mut sync = DocumentSync::default();
+
+        let report = sync.sync(["hit", "miss"].into_iter(), |key| key == "miss").unwrap();
+        assert_eq!(report.rendered, ["miss"]);
+
+        // blocks seen before are neither rendered nor evicted
+        let report = sync.sync(["hit", "miss", "new"].into_iter(), |_| true).unwrap();
+        assert_eq!((report.rendered, report.evicted), (vec!["new".to_string()], Vec::new()));
+    }
+
+    #[test]
+    fn labels_are_stable() {
+        let digest = base32_digest("key");
+        let others = (0..1000).map(|idx| base32_digest(&idx.to_string())).collect::<Vec<_>>();
+
+        assert_eq!(label(&digest, &[]), digest[..DISPLAY_ID_LEN]);
+        assert_eq!(label(&digest, &others), digest[..DISPLAY_ID_LEN]);
+
+        let clash = format!("{}{}", &digest[..DISPLAY_ID_LEN], "0".repeat(24));
+        assert_eq!(label(&digest, &[clash]), digest);
+    }
+
+    #[test]
+    fn entries_are_verified() {
+        render::test_art_path();
+        let config = Config::new();
+        let kind = ContentType::Math;
+        let source = art_path().join(kind.source_key("a^2", &config).unwrap()).with_extension("tex");
+
+        assert!(!verify_entry(&kind, "a^2", &config).unwrap());
+
+        fs::write(kind.path("a^2", &config), "<svg/>").unwrap();
+        fs::write(&source, "$$a^2$$").unwrap();
+        assert!(verify_entry(&kind, "a^2", &config).unwrap());
+
+        fs::write(&source, "$$b^2$$").unwrap();
+        assert!(!verify_entry(&kind, "a^2", &config).unwrap());
+    }
+}
@@ -0,0 +1,1072 @@
+use std::fs;
+use std::result;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use miniserde::json::{self, Value};
+
+use crate::cache::{FreshnessPolicy, KeyStrategy};
+use crate::error::{Error, Result};
+use crate::render::BackendKind;
+use crate::utils;
+
+/// Options controlling how snippets are rendered
+///
+/// Every option changing the rendered output has to show up in the fingerprint, otherwise stale
+/// artifacts are served from the cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// TeX engine producing the DVI or PDF file
+    pub engine: LatexEngine,
+    /// Cropper run on PDF output before dvisvgm, for example `pdfcrop`, empty for none
+    pub pdf_crop: String,
+    /// Margin in pt left by the PDF cropper
+    pub pdf_crop_margin: f32,
+    /// Crop equations with the `tightpage` option of the `preview` package
+    pub tightpage: bool,
+    /// Border of equations and circuits in pt as left, right, top and bottom
+    pub border_lrtb: Option<[f32; 4]>,
+    /// Leave out amsmath and amsfonts for equations not needing them, which compiles faster
+    pub minimal_preamble: bool,
+    /// Typeset equations with `\displaystyle`, also in inline mode
+    pub force_displaystyle: bool,
+    /// Number the lines of multi-line equations
+    pub line_numbers: bool,
+    /// Preview `$...$` and `\(...\)` in prose, placed over their source in the line
+    pub inline_math: bool,
+    /// Whether all blocks are shown in place, or only the one under the cursor next to it
+    pub mode: PreviewMode,
+    /// Width and height of a terminal cell in pixels, none to ask the terminal
+    pub cell_size: Option<(f32, f32)>,
+    /// Handling of SVG transparency groups produced by dvisvgm
+    pub transparency: Transparency,
+    /// Whether dvisvgm embeds fonts or converts glyphs to paths
+    pub fonts: FontMode,
+    /// Whether content outside of the declared bounding box is shown or cut off
+    pub bbox: BboxPolicy,
+    /// Rotation of the content in degrees, counterclockwise
+    pub rotation_deg: f32,
+    /// Border drawn around the content
+    pub frame: Option<FrameStyle>,
+    /// Rounded, possibly gradient and translucent rectangle behind the content
+    pub backdrop: Option<Backdrop>,
+    /// Fixed outer size in pt, the content is centered within
+    pub canvas: Option<(f32, f32)>,
+    /// SVG color of text and lines, empty to follow the color scheme
+    pub foreground: String,
+    /// Color scheme of the editor, dark ones get white content by default
+    pub colorscheme: ColorScheme,
+    /// Keep the background transparent, also when rasterizing, overriding `background`
+    pub transparent: bool,
+    /// SVG color filled behind the content, empty for none
+    pub background: String,
+    /// Image stretched behind the content, like a watermark, empty for none
+    pub background_image: String,
+    /// Terminal graphics protocol showing the images
+    pub backend: BackendKind,
+    /// Anti-alias edges when rasterizing, disabling can help small equations
+    pub antialias: bool,
+    /// Maximum size of raster images in pixels, larger ones are downscaled
+    pub max_size: Option<(usize, usize)>,
+    /// Maximum height and width of images on screen, larger ones take fewer rows
+    pub max_rows: Option<CellLimit>,
+    pub max_cols: Option<CellLimit>,
+    /// Reserve filler lines below images taller than their lines, instead of covering the text
+    pub virtual_lines: bool,
+    /// Play animated GIF and APNG files, otherwise their first frame is shown
+    pub animate: bool,
+    /// Frames shown per second at most, if the terminal can't play animations by itself
+    pub animation_fps: u32,
+    /// Rasterize every SVG to a PNG next to it, for callers needing both
+    pub emit_png: bool,
+    /// Pad images to whole terminal cells, for terminals misplacing partial cells
+    pub snap_to_cells: bool,
+    /// Draw a red placard with the error in place of snippets which failed to render
+    pub error_badge: bool,
+    /// What is drawn in place of snippets while they render
+    pub placeholder: Placeholder,
+    /// Latex added to the preamble of equations, like packages and macros
+    pub preamble: String,
+    /// Math spacing registers assigned in the preamble, like `thinmuskip=2mu`
+    pub math_spacing: Vec<(String, String)>,
+    /// Significant figures of tick labels in gnuplot plots
+    pub plot_significant_figures: Option<usize>,
+    /// Remove latex comments before hashing and rendering, editing a comment then hits the cache
+    pub strip_comments: bool,
+    /// When artifacts of linked latex and gnuplot files are rendered again
+    pub freshness: FreshnessPolicy,
+    /// Ghostscript library passed to dvisvgm, empty to let dvisvgm search it
+    pub libgs: String,
+    /// Page of linked PDF files which is shown, counted from one
+    pub pdf_page: usize,
+    /// Resolution at which PDF pages are rasterized
+    pub pdf_dpi: usize,
+    /// Time into linked videos from which their thumbnail is taken
+    pub video_seek: Duration,
+    /// Jar of PlantUML run with `java -jar`, empty to use the `plantuml` binary
+    pub plantuml_jar: String,
+    /// Time after which latex and dvisvgm are killed when rendering equations
+    pub timeout_equation: Duration,
+    /// Time after which plots, circuits and latex documents are killed
+    pub timeout_plot: Duration,
+    /// Equations rendered in the background on startup, so that the first real one is fast
+    pub prewarm: Vec<String>,
+    /// File receiving a JSON line per render, empty for none
+    pub stats_log: String,
+    /// Maximum number of latex, dvisvgm and gnuplot processes running at the same time
+    pub max_processes: usize,
+    /// Command line prefixed to all subprocesses, for example `firejail --quiet`
+    pub sandbox: String,
+    /// Allow `\write18` in latex, which lets documents run arbitrary commands
+    pub shell_escape: bool,
+    /// Execute the code of script fences like `python`, which can do anything the user can
+    pub run_scripts: bool,
+    /// Interpreter running `python` fences, for example the one of a virtualenv
+    pub python: String,
+    /// Command lines of script languages by fence name, replacing the built-in ones
+    pub script_commands: Vec<ScriptCommand>,
+    /// Shell commands converting fences or linked files to images, by fence name or extension
+    pub converters: Vec<Converter>,
+    /// Directory of the cache, empty for `$XDG_CACHE_HOME/vim-graphical-preview`
+    pub cache_dir: String,
+    /// Size in MB above which the least recently used entries are evicted, none for no limit
+    pub cache_size_limit: Option<u64>,
+    /// Keep DVI, log and aux files after rendering, for debugging latex
+    pub keep_intermediates: bool,
+    /// Strategy deriving the cache key of a snippet
+    pub cache_key: KeyStrategy,
+    /// Prefix of all cache keys, empty for none
+    pub cache_namespace: String,
+}
+
+/// Options which magic comments of a document may set, all others only the editor configuration
+///
+/// These only change how a snippet looks. Options naming files, binaries or commands, or deciding
+/// what runs at all, would let any opened document read files or execute code. For the same reason
+/// latex runs without shell escape, content like minted reports `ShellEscapeRequired` until the
+/// editor configuration sets `shell_escape`.
+const MAGIC_OPTIONS: [&str; 26] = [
+    "tightpage", "border_lrtb", "minimal_preamble", "force_displaystyle", "line_numbers",
+    "inline_math", "transparency", "fonts", "bbox", "rotation_deg", "frame", "backdrop", "canvas",
+    "foreground", "colorscheme", "transparent", "background", "antialias", "snap_to_cells",
+    "error_badge", "placeholder", "math_spacing", "plot_significant_figures", "pdf_page",
+    "video_seek", "animate",
+];
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            engine: LatexEngine::Latex,
+            pdf_crop: String::new(),
+            pdf_crop_margin: 1.0,
+            tightpage: true,
+            border_lrtb: None,
+            minimal_preamble: false,
+            force_displaystyle: false,
+            line_numbers: false,
+            inline_math: false,
+            mode: PreviewMode::Inline,
+            cell_size: None,
+            transparency: Transparency::Preserve,
+            fonts: FontMode::Paths,
+            bbox: BboxPolicy::Expand,
+            rotation_deg: 0.0,
+            frame: None,
+            backdrop: None,
+            canvas: None,
+            foreground: String::new(),
+            colorscheme: ColorScheme::Light,
+            transparent: false,
+            background: String::new(),
+            background_image: String::new(),
+            backend: BackendKind::Auto,
+            antialias: true,
+            max_size: None,
+            max_rows: None,
+            max_cols: None,
+            virtual_lines: false,
+            animate: true,
+            animation_fps: 10,
+            emit_png: false,
+            snap_to_cells: false,
+            error_badge: true,
+            placeholder: Placeholder::None,
+            preamble: String::new(),
+            math_spacing: Vec::new(),
+            plot_significant_figures: None,
+            strip_comments: false,
+            freshness: FreshnessPolicy::AlwaysUseCache,
+            libgs: String::new(),
+            pdf_page: 1,
+            pdf_dpi: 150,
+            video_seek: Duration::from_secs(1),
+            plantuml_jar: String::new(),
+            timeout_equation: Duration::from_secs(10),
+            timeout_plot: Duration::from_secs(60),
+            prewarm: Vec::new(),
+            stats_log: String::new(),
+            max_processes: thread::available_parallelism().map_or(1, |x| x.get()),
+            sandbox: String::new(),
+            shell_escape: false,
+            run_scripts: false,
+            python: "python3".to_string(),
+            script_commands: Vec::new(),
+            converters: Vec::new(),
+            cache_dir: String::new(),
+            cache_size_limit: Some(200),
+            keep_intermediates: false,
+            cache_key: KeyStrategy::Config,
+            cache_namespace: String::new(),
+        }
+    }
+
+    /// Set a single option from its textual value
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "engine" => self.engine = parse(key, value)?,
+            "pdf_crop" => self.pdf_crop = value.trim().to_string(),
+            "pdf_crop_margin" => self.pdf_crop_margin = parse(key, value)?,
+            "tightpage" => self.tightpage = parse(key, value)?,
+            "border_lrtb" => self.border_lrtb = parse_quad(key, value)?,
+            "minimal_preamble" => self.minimal_preamble = parse(key, value)?,
+            "force_displaystyle" => self.force_displaystyle = parse(key, value)?,
+            "line_numbers" => self.line_numbers = parse(key, value)?,
+            "inline_math" => self.inline_math = parse(key, value)?,
+            "mode" => self.mode = parse(key, value)?,
+            // the plugin reads the idle time itself, it is only validated here
+            "debounce" => parse_optional::<u64>(key, value).map(drop)?,
+            "cell_size" => self.cell_size = parse_cell_size(key, value)?,
+            "transparency" => self.transparency = parse(key, value)?,
+            "fonts" => self.fonts = parse(key, value)?,
+            "bbox" => self.bbox = parse(key, value)?,
+            "rotation_deg" => self.rotation_deg = parse(key, value)?,
+            "frame" => self.frame = parse_optional(key, value)?,
+            "backdrop" => self.backdrop = parse_optional(key, value)?,
+            "canvas" => self.canvas = parse_pair(key, value)?,
+            "foreground" => self.foreground = value.trim().to_string(),
+            "colorscheme" => self.colorscheme = parse(key, value)?,
+            "transparent" => self.transparent = parse(key, value)?,
+            "background" => self.background = value.trim().to_string(),
+            "background_image" => self.background_image = value.trim().to_string(),
+            "backend" => self.backend = parse(key, value)?,
+            "antialias" => self.antialias = parse(key, value)?,
+            "max_size" => self.max_size = parse_pair(key, value)?,
+            "max_rows" => self.max_rows = parse_optional(key, value)?,
+            "max_cols" => self.max_cols = parse_optional(key, value)?,
+            "virtual_lines" => self.virtual_lines = parse(key, value)?,
+            "animate" => self.animate = parse(key, value)?,
+            "animation_fps" => self.animation_fps = parse(key, value)?,
+            "emit_png" => self.emit_png = parse(key, value)?,
+            "snap_to_cells" => self.snap_to_cells = parse(key, value)?,
+            "error_badge" => self.error_badge = parse(key, value)?,
+            "placeholder" => self.placeholder = parse(key, value)?,
+            "preamble" => self.preamble = parse_preamble(value)?,
+            "math_spacing" => self.math_spacing = parse_assignments(key, value)?,
+            "plot_significant_figures" => self.plot_significant_figures = parse_optional(key, value)?,
+            "strip_comments" => self.strip_comments = parse(key, value)?,
+            "freshness" => self.freshness = parse(key, value)?,
+            "libgs" => self.libgs = value.trim().to_string(),
+            "pdf_page" => self.pdf_page = parse(key, value)?,
+            "pdf_dpi" => self.pdf_dpi = parse(key, value)?,
+            "video_seek" => self.video_seek = parse_secs(key, value)?,
+            "plantuml_jar" => self.plantuml_jar = value.trim().to_string(),
+            "timeout_equation" => self.timeout_equation = parse_secs(key, value)?,
+            "timeout_plot" => self.timeout_plot = parse_secs(key, value)?,
+            "prewarm" => self.prewarm = parse_prewarm(value),
+            "stats_log" => self.stats_log = value.trim().to_string(),
+            "max_processes" => self.max_processes = parse(key, value)?,
+            "sandbox" => self.sandbox = value.trim().to_string(),
+            "shell_escape" => self.shell_escape = parse(key, value)?,
+            "run_scripts" => self.run_scripts = parse(key, value)?,
+            "python" => self.python = value.trim().to_string(),
+            "script_commands" => self.script_commands = parse_script_commands(key, value)?,
+            "converters" => self.converters = parse_converters(key, value)?,
+            "cache_dir" => self.cache_dir = value.trim().to_string(),
+            "cache_size_limit" => self.cache_size_limit = parse_optional(key, value)?,
+            "keep_intermediates" => self.keep_intermediates = parse(key, value)?,
+            "cache_key" => self.cache_key = parse(key, value)?,
+            // the name of a cache file up to its first dot is the key
+            "cache_namespace" if value.contains(['.', '/']) => return Err(Error::InvalidOption(key.to_string(), value.to_string())),
+            "cache_namespace" => self.cache_namespace = value.to_string(),
+            _ => return Err(Error::UnknownOption(key.to_string())),
+        }
+
+        Ok(())
+    }
+
+    /// Update options from a JSON object, unknown keys are rejected
+    pub fn update(&mut self, options: &str) -> Result<()> {
+        let options = match json::from_str(options) {
+            Ok(Value::Object(options)) => options,
+            _ => return Err(Error::InvalidOption("config".to_string(), options.to_string())),
+        };
+
+        // apply to a copy, so that an invalid option leaves the configuration untouched
+        let mut config = self.clone();
+        for (key, value) in options.iter() {
+            config.set(key, &value_to_string(key, value)?)?;
+        }
+        *self = config;
+
+        Ok(())
+    }
+
+    /// Color replacing black in the output, if any
+    pub fn foreground(&self) -> Option<&str> {
+        match (self.foreground.as_str(), self.colorscheme) {
+            ("", ColorScheme::Dark) => Some("#ffffff"),
+            ("", ColorScheme::Light) => None,
+            (color, _) => Some(color),
+        }
+    }
+
+    /// Copy with the options of magic comments applied
+    ///
+    /// Invalid overrides are skipped one by one, a typo in a document should not disable its
+    /// preview. Only the options of `MAGIC_OPTIONS` can be overridden.
+    pub fn with_overrides(&self, overrides: &[(String, String)]) -> Config {
+        let mut config = self.clone();
+        for (key, value) in overrides {
+            if !MAGIC_OPTIONS.contains(&key.as_str()) {
+                continue;
+            }
+
+            let mut candidate = config.clone();
+            if candidate.set(key, value).is_ok() {
+                config = candidate;
+            }
+        }
+
+        config
+    }
+
+    /// Describe all options deviating from their default value
+    ///
+    /// Options not affecting the output, like timeouts, process limits, logging, the sandbox and
+    /// the cache policies, are left out.
+    pub fn fingerprint(&self) -> String {
+        let default = Config::new();
+        let mut parts = Vec::new();
+
+        if self.engine != default.engine {
+            parts.push(format!("engine={:?}", self.engine));
+        }
+        if self.pdf_crop != default.pdf_crop {
+            parts.push(format!("pdf_crop={}", self.pdf_crop));
+        }
+        if self.pdf_crop_margin != default.pdf_crop_margin {
+            parts.push(format!("pdf_crop_margin={}", self.pdf_crop_margin));
+        }
+        if self.tightpage != default.tightpage {
+            parts.push(format!("tightpage={}", self.tightpage));
+        }
+        if self.border_lrtb != default.border_lrtb {
+            parts.push(format!("border_lrtb={:?}", self.border_lrtb));
+        }
+        if self.minimal_preamble != default.minimal_preamble {
+            parts.push(format!("minimal_preamble={}", self.minimal_preamble));
+        }
+        if self.force_displaystyle != default.force_displaystyle {
+            parts.push(format!("force_displaystyle={}", self.force_displaystyle));
+        }
+        if self.line_numbers != default.line_numbers {
+            parts.push(format!("line_numbers={}", self.line_numbers));
+        }
+        if self.transparency != default.transparency {
+            parts.push(format!("transparency={:?}", self.transparency));
+        }
+        if self.fonts != default.fonts {
+            parts.push(format!("fonts={:?}", self.fonts));
+        }
+        if self.bbox != default.bbox {
+            parts.push(format!("bbox={:?}", self.bbox));
+        }
+        if self.rotation_deg != default.rotation_deg {
+            parts.push(format!("rotation_deg={}", self.rotation_deg));
+        }
+        if self.frame != default.frame {
+            parts.push(format!("frame={:?}", self.frame));
+        }
+        if self.backdrop != default.backdrop {
+            parts.push(format!("backdrop={:?}", self.backdrop));
+        }
+        if self.canvas != default.canvas {
+            parts.push(format!("canvas={:?}", self.canvas));
+        }
+        if self.foreground() != default.foreground() {
+            parts.push(format!("foreground={:?}", self.foreground()));
+        }
+        if self.transparent != default.transparent {
+            parts.push(format!("transparent={}", self.transparent));
+        }
+        if self.background != default.background {
+            parts.push(format!("background={}", self.background));
+        }
+        if self.background_image != default.background_image {
+            // the digest of the image makes edits to it invalidate the cache
+            let digest = fs::read(&self.background_image)
+                .map(|x| utils::hash_bytes(&x))
+                .unwrap_or_default();
+            parts.push(format!("background_image={}:{}", self.background_image, digest));
+        }
+        if self.antialias != default.antialias {
+            parts.push(format!("antialias={}", self.antialias));
+        }
+        if self.max_size != default.max_size {
+            parts.push(format!("max_size={:?}", self.max_size));
+        }
+        if self.snap_to_cells != default.snap_to_cells {
+            parts.push(format!("snap_to_cells={}", self.snap_to_cells));
+        }
+        if self.preamble != default.preamble {
+            parts.push(format!("preamble={}", utils::hash(&self.preamble)));
+        }
+        if self.math_spacing != default.math_spacing {
+            parts.push(format!("math_spacing={:?}", self.math_spacing));
+        }
+        if self.plot_significant_figures != default.plot_significant_figures {
+            parts.push(format!("plot_significant_figures={:?}", self.plot_significant_figures));
+        }
+        // documents may run commands producing different output
+        if self.shell_escape != default.shell_escape {
+            parts.push(format!("shell_escape={}", self.shell_escape));
+        }
+
+        parts.join(";")
+    }
+
+    /// Cache key of some content rendered with these options
+    ///
+    /// With default options this is the plain content hash, so existing caches stay valid.
+    pub fn key(&self, content: &str) -> String {
+        self.cache_key.key(content, self)
+    }
+
+    /// Cache key of the latex source and DVI of some content
+    ///
+    /// Only options ending up in the latex source or changing how it is compiled are part of it, so
+    /// that one DVI serves every SVG converted from it with other colors, boxes or sizes.
+    pub fn source_key(&self, content: &str) -> String {
+        let source = Config {
+            engine: self.engine,
+            pdf_crop: self.pdf_crop.clone(),
+            pdf_crop_margin: self.pdf_crop_margin,
+            tightpage: self.tightpage,
+            border_lrtb: self.border_lrtb,
+            minimal_preamble: self.minimal_preamble,
+            force_displaystyle: self.force_displaystyle,
+            line_numbers: self.line_numbers,
+            preamble: self.preamble.clone(),
+            math_spacing: self.math_spacing.clone(),
+            plot_significant_figures: self.plot_significant_figures,
+            shell_escape: self.shell_escape,
+            cache_key: self.cache_key,
+            cache_namespace: self.cache_namespace.clone(),
+            ..Config::new()
+        };
+
+        source.key(content)
+    }
+}
+
+/// Engine compiling latex sources into DVI or PDF files for dvisvgm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatexEngine {
+    /// Plain pdfTeX in DVI mode
+    Latex,
+    /// LuaTeX in DVI mode, for packages requiring lua
+    DviLuaLatex,
+    /// pdfTeX in PDF mode
+    PdfLatex,
+    /// LuaTeX in PDF mode, for unicode-math and system fonts
+    LuaLatex,
+    /// XeTeX, for unicode-math and system fonts
+    XeLatex,
+    /// Self-contained engine based on XeTeX, fetching packages on demand
+    Tectonic,
+}
+
+impl LatexEngine {
+    /// Name of the binary
+    pub fn binary(&self) -> &'static str {
+        match self {
+            LatexEngine::Latex => "latex",
+            LatexEngine::DviLuaLatex => "dvilualatex",
+            LatexEngine::PdfLatex => "pdflatex",
+            LatexEngine::LuaLatex => "lualatex",
+            LatexEngine::XeLatex => "xelatex",
+            LatexEngine::Tectonic => "tectonic",
+        }
+    }
+
+    /// Arguments passed before the source file
+    ///
+    /// Errors stop the run instead of prompting, and shell escape is off unless enabled explicitly,
+    /// whatever the distribution defaults to.
+    pub fn args(&self, shell_escape: bool) -> Vec<&'static str> {
+        match (self, shell_escape) {
+            // the log is needed for error messages and the baseline of equations, tectonic never
+            // prompts and disables shell escape by default
+            (LatexEngine::Tectonic, false) => vec!["--keep-logs"],
+            (LatexEngine::Tectonic, true) => vec!["--keep-logs", "-Z", "shell-escape"],
+            (_, false) => vec!["-interaction=nonstopmode", "-halt-on-error", "-no-shell-escape"],
+            (_, true) => vec!["-interaction=nonstopmode", "-halt-on-error", "-shell-escape"],
+        }
+    }
+
+    /// Extension of the produced file, either `dvi` or `pdf`
+    pub fn output(&self) -> &'static str {
+        match self {
+            LatexEngine::Latex | LatexEngine::DviLuaLatex => "dvi",
+            _ => "pdf",
+        }
+    }
+}
+
+impl FromStr for LatexEngine {
+    type Err = ();
+
+    fn from_str(s: &str) -> result::Result<Self, ()> {
+        match s {
+            "latex" => Ok(LatexEngine::Latex),
+            "dvilualatex" => Ok(LatexEngine::DviLuaLatex),
+            "pdflatex" => Ok(LatexEngine::PdfLatex),
+            "lualatex" => Ok(LatexEngine::LuaLatex),
+            "xelatex" => Ok(LatexEngine::XeLatex),
+            "tectonic" => Ok(LatexEngine::Tectonic),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Which blocks are previewed and where
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreviewMode {
+    /// every block in place of the lines following it
+    Inline,
+    /// only the block under the cursor, in an overlay below the cursor
+    Hover,
+    /// the page of the whole compiled buffer at the cursor, in a side window
+    Document,
+}
+
+impl FromStr for PreviewMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> result::Result<Self, ()> {
+        match s {
+            "inline" => Ok(PreviewMode::Inline),
+            "hover" => Ok(PreviewMode::Hover),
+            "document" => Ok(PreviewMode::Document),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Stand-in for a snippet which is still rendering
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placeholder {
+    /// leave the lines as they are
+    None,
+    /// a grey box of the height of the node
+    Box,
+    /// the box with a spinner, advancing with every redraw
+    Spinner,
+}
+
+impl FromStr for Placeholder {
+    type Err = ();
+
+    fn from_str(s: &str) -> result::Result<Self, ()> {
+        match s {
+            "none" => Ok(Placeholder::None),
+            "box" => Ok(Placeholder::Box),
+            "spinner" => Ok(Placeholder::Spinner),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Limit of the size of images, in terminal cells or relative to the window like `50%`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CellLimit {
+    Cells(usize),
+    Percent(f32),
+}
+
+impl CellLimit {
+    /// Cells allowed in a window `size` cells large, at least one
+    pub fn cells(&self, size: usize) -> usize {
+        match self {
+            CellLimit::Cells(cells) => *cells,
+            CellLimit::Percent(percent) => (size as f32 * percent / 100.0) as usize,
+        }.max(1)
+    }
+}
+
+impl FromStr for CellLimit {
+    type Err = ();
+
+    fn from_str(s: &str) -> result::Result<Self, ()> {
+        match s.strip_suffix('%') {
+            Some(percent) => percent.trim().parse().ok().filter(|x| *x > 0.0).map(CellLimit::Percent).ok_or(()),
+            None => s.parse().map(CellLimit::Cells).map_err(|_| ()),
+        }
+    }
+}
+
+/// Brightness of the editor background, as in `&background` of Vim
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorScheme {
+    Light,
+    Dark,
+}
+
+impl FromStr for ColorScheme {
+    type Err = ();
+
+    fn from_str(s: &str) -> result::Result<Self, ()> {
+        match s {
+            "light" => Ok(ColorScheme::Light),
+            "dark" => Ok(ColorScheme::Dark),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How group opacity in the SVG output is treated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transparency {
+    /// Keep transparency groups as emitted by dvisvgm
+    Preserve,
+    /// Push group opacity down to the fill and stroke of its elements
+    Flatten,
+}
+
+impl FromStr for Transparency {
+    type Err = ();
+
+    fn from_str(s: &str) -> result::Result<Self, ()> {
+        match s {
+            "preserve" => Ok(Transparency::Preserve),
+            "flatten" => Ok(Transparency::Flatten),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Treatment of content reaching beyond the bounding box declared by the document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BboxPolicy {
+    /// Grow the box to all visible content
+    Expand,
+    /// Keep the declared box, cutting off what lies outside
+    Clip,
+}
+
+impl FromStr for BboxPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> result::Result<Self, ()> {
+        match s {
+            "expand" => Ok(BboxPolicy::Expand),
+            "clip" => Ok(BboxPolicy::Clip),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Representation of glyphs in the SVG output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontMode {
+    /// Convert glyphs to paths with `--no-fonts`
+    Paths,
+    /// Embed the fonts as WOFF2, scales best when zooming
+    Woff2,
+}
+
+impl FromStr for FontMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> result::Result<Self, ()> {
+        match s {
+            "paths" => Ok(FontMode::Paths),
+            "woff2" => Ok(FontMode::Woff2),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Border drawn around the content, set as `color,width,padding` with sizes in pt
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameStyle {
+    pub color: String,
+    pub width: f32,
+    pub padding: f32,
+}
+
+impl FromStr for FrameStyle {
+    type Err = ();
+
+    fn from_str(s: &str) -> result::Result<Self, ()> {
+        let mut parts = s.split(',').map(|x| x.trim());
+        let color = parts.next().filter(|x| !x.is_empty()).ok_or(())?.to_string();
+        let width = parts.next().map_or(Ok(0.5), |x| x.parse()).map_err(|_| ())?;
+        let padding = parts.next().map_or(Ok(1.0), |x| x.parse()).map_err(|_| ())?;
+
+        Ok(FrameStyle { color, width, padding })
+    }
+}
+
+/// Rectangle behind the content, set as `color[:color],opacity,radius` with the radius in pt
+///
+/// Two colors give a vertical gradient from top to bottom.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backdrop {
+    pub top: String,
+    pub bottom: String,
+    pub opacity: f32,
+    pub radius: f32,
+}
+
+impl FromStr for Backdrop {
+    type Err = ();
+
+    fn from_str(s: &str) -> result::Result<Self, ()> {
+        let mut parts = s.split(',').map(|x| x.trim());
+        let colors = parts.next().filter(|x| !x.is_empty()).ok_or(())?;
+        let (top, bottom) = colors.split_once(':').unwrap_or((colors, colors));
+        let opacity = parts.next().map_or(Ok(0.5), |x| x.parse()).map_err(|_| ())?;
+        let radius = parts.next().map_or(Ok(2.0), |x| x.parse()).map_err(|_| ())?;
+
+        Ok(Backdrop { top: top.trim().to_string(), bottom: bottom.trim().to_string(), opacity, radius })
+    }
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T> {
+    value.trim().parse()
+        .map_err(|_| Error::InvalidOption(key.to_string(), value.to_string()))
+}
+
+fn parse_secs(key: &str, value: &str) -> Result<Duration> {
+    let secs: f32 = parse(key, value)?;
+
+    Duration::try_from_secs_f32(secs)
+        .map_err(|_| Error::InvalidOption(key.to_string(), value.to_string()))
+}
+
+/// Parse an optional value, an empty value disables the option
+fn parse_optional<T: FromStr>(key: &str, value: &str) -> Result<Option<T>> {
+    match value.trim() {
+        "" => Ok(None),
+        value => parse(key, value).map(Some),
+    }
+}
+
+/// Parse an optional pair `a,b`, an empty value disables the option
+fn parse_pair<T: FromStr>(key: &str, value: &str) -> Result<Option<(T, T)>> {
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+
+    match value.split_once(',') {
+        Some((a, b)) => Ok(Some((parse(key, a)?, parse(key, b)?))),
+        None => Err(Error::InvalidOption(key.to_string(), value.to_string())),
+    }
+}
+
+/// Parse the pixel size of a terminal cell, sizes are divided by it so it is at least one pixel
+fn parse_cell_size(key: &str, value: &str) -> Result<Option<(f32, f32)>> {
+    let size = parse_pair::<f32>(key, value)?;
+    let valid = |x: f32| x.is_finite() && x >= 1.0;
+
+    match size {
+        Some((width, height)) if !valid(width) || !valid(height) => Err(Error::InvalidOption(key.to_string(), value.to_string())),
+        size => Ok(size),
+    }
+}
+
+/// Command line running the fences of a script language
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptCommand {
+    pub fence: String,
+    /// binary and arguments, with the placeholders `{script}`, `{svg}` and `{png}`
+    pub args: Vec<String>,
+}
+
+/// Parse lines like `octave: octave --no-gui {script}`, one per language
+///
+/// Fence names are lowercase letters, at least three of them as for all fences.
+fn parse_script_commands(key: &str, value: &str) -> Result<Vec<ScriptCommand>> {
+    value.lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|line| {
+            let (fence, command) = line.split_once(':')
+                .map(|(a, b)| (a.trim(), b.split_whitespace().map(|x| x.to_string()).collect::<Vec<_>>()))
+                .filter(|(a, b)| a.len() >= 3 && a.chars().all(|c| c.is_ascii_lowercase()) && !b.is_empty())
+                .ok_or_else(|| Error::InvalidOption(key.to_string(), line.to_string()))?;
+
+            Ok(ScriptCommand { fence: fence.to_string(), args: command })
+        })
+        .collect()
+}
+
+/// Shell command converting a fence or linked file to an image
+#[derive(Debug, Clone, PartialEq)]
+pub struct Converter {
+    /// fence name, also matched against the extension of linked files
+    pub name: String,
+    /// template with the placeholders `{input}` and `{output}`
+    pub command: String,
+}
+
+/// Parse lines like `ditaa: ditaa {input} {output}.svg --svg`, one per fence or extension
+///
+/// Names are lowercase letters and digits. Fences are named by at least three letters, other
+/// names like `ly` only match the extension of linked files.
+fn parse_converters(key: &str, value: &str) -> Result<Vec<Converter>> {
+    value.lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|line| {
+            let (name, command) = line.split_once(':')
+                .map(|(a, b)| (a.trim(), b.trim()))
+                .filter(|(a, b)| !a.is_empty() && a.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()) && b.contains("{output}"))
+                .ok_or_else(|| Error::InvalidOption(key.to_string(), line.to_string()))?;
+
+            Ok(Converter { name: name.to_string(), command: command.to_string() })
+        })
+        .collect()
+}
+
+/// Fragments warming up latex and the cache, covering the most common constructs
+const COMMON_FRAGMENTS: [&str; 6] = [
+    "x",
+    "\\frac{a}{b}",
+    "\\int_a^b f(x) \\, dx",
+    "\\sum_{i=1}^n i",
+    "\\sqrt{x}",
+    "\\alpha + \\beta = \\gamma",
+];
+
+/// Parse fragments separated by line breaks, `true` selects a built-in set
+fn parse_prewarm(value: &str) -> Vec<String> {
+    match value.trim() {
+        "true" => COMMON_FRAGMENTS.iter().map(|x| x.to_string()).collect(),
+        "false" => Vec::new(),
+        value => value.lines().map(|x| x.trim()).filter(|x| !x.is_empty()).map(|x| x.to_string()).collect(),
+    }
+}
+
+/// Opening and closing of magic comments, for latex and markdown documents
+const MAGIC_COMMENTS: [(&str, &str); 2] = [("%! preview:", ""), ("<!-- preview:", "-->")];
+
+/// Options set by magic comments at the top of a buffer
+///
+/// Lines like `%! preview: line_numbers=true foreground=#3366cc`, or the same in a
+/// `<!-- preview: ... -->` comment, are read up to the first other non-blank line. Assignments are
+/// separated by whitespace.
+pub fn magic_comments(content: &str) -> Vec<(String, String)> {
+    let mut overrides = Vec::new();
+
+    for line in content.lines().map(|x| x.trim()) {
+        if line.is_empty() {
+            continue;
+        }
+
+        let body = MAGIC_COMMENTS.iter()
+            .find_map(|(open, close)| line.strip_prefix(open).and_then(|x| x.strip_suffix(close)));
+
+        let Some(body) = body else {
+            break;
+        };
+
+        overrides.extend(body.split_whitespace()
+            .filter_map(|x| x.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string())));
+    }
+
+    overrides
+}
+
+/// Parse a preamble given inline or as path of a `.tex` file, which is read right away
+fn parse_preamble(value: &str) -> Result<String> {
+    let value = value.trim();
+    if !value.ends_with(".tex") || value.contains('\n') {
+        return Ok(value.to_string());
+    }
+
+    let path = utils::expand_home(value);
+    fs::read_to_string(&path).map_err(|_| Error::FileNotFound(path))
+}
+
+/// Parse an optional list of four numbers, an empty value disables the option
+fn parse_quad(key: &str, value: &str) -> Result<Option<[f32; 4]>> {
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+
+    let vals = value.split(',')
+        .map(|x| parse(key, x))
+        .collect::<Result<Vec<f32>>>()?;
+
+    vals.try_into()
+        .map(Some)
+        .map_err(|_| Error::InvalidOption(key.to_string(), value.to_string()))
+}
+
+/// Parse comma separated `register=value` assignments of TeX registers
+///
+/// A leading backslash of the register is optional. Names are restricted to letters and values
+/// must not contain control sequences or braces, so that nothing but the assignment is injected.
+fn parse_assignments(key: &str, value: &str) -> Result<Vec<(String, String)>> {
+    value.split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            let (name, val) = x.split_once('=')
+                .map(|(a, b)| (a.trim().trim_start_matches('\\'), b.trim()))
+                .filter(|(a, b)| {
+                    !a.is_empty() && a.chars().all(|c| c.is_ascii_alphabetic())
+                        && !b.is_empty() && !b.contains(['\\', '{', '}'])
+                })
+                .ok_or_else(|| Error::InvalidOption(key.to_string(), value.to_string()))?;
+
+            Ok((name.to_string(), val.to_string()))
+        })
+        .collect()
+}
+
+fn value_to_string(key: &str, value: &Value) -> Result<String> {
+    match value {
+        Value::Bool(val) => Ok(val.to_string()),
+        Value::Number(val) => Ok(val.to_string()),
+        Value::String(val) => Ok(val.clone()),
+        Value::Array(vals) => vals.iter()
+            .map(|val| value_to_string(key, val))
+            .collect::<Result<Vec<_>>>()
+            // latex fragments may contain commas themselves
+            .map(|vals| vals.join(if ["prewarm", "preamble", "script_commands", "converters"].contains(&key) { "\n" } else { "," })),
+        _ => Err(Error::InvalidOption(key.to_string(), "null or object".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every option of `Config::set` with a valid value differing from the default
+    const OPTIONS: [(&str, &str); 62] = [
+        ("engine", "xelatex"),
+        ("pdf_crop", "pdfcrop"),
+        ("pdf_crop_margin", "3"),
+        ("tightpage", "false"),
+        ("border_lrtb", "1,2,3,4"),
+        ("minimal_preamble", "true"),
+        ("force_displaystyle", "true"),
+        ("line_numbers", "true"),
+        ("inline_math", "true"),
+        ("mode", "hover"),
+        ("debounce", "200"),
+        ("cell_size", "10,20"),
+        ("transparency", "flatten"),
+        ("fonts", "woff2"),
+        ("bbox", "clip"),
+        ("rotation_deg", "90"),
+        ("frame", "red"),
+        ("backdrop", "white"),
+        ("canvas", "100,50"),
+        ("foreground", "#3366cc"),
+        ("colorscheme", "dark"),
+        ("transparent", "true"),
+        ("background", "white"),
+        ("background_image", "/etc/passwd"),
+        ("backend", "kitty"),
+        ("antialias", "false"),
+        ("max_size", "100,100"),
+        ("max_rows", "10"),
+        ("max_cols", "50%"),
+        ("virtual_lines", "true"),
+        ("animate", "false"),
+        ("animation_fps", "30"),
+        ("emit_png", "true"),
+        ("snap_to_cells", "true"),
+        ("error_badge", "false"),
+        ("placeholder", "box"),
+        ("preamble", "\\usepackage{bm}"),
+        ("math_spacing", "thinmuskip=2mu"),
+        ("plot_significant_figures", "3"),
+        ("strip_comments", "true"),
+        ("freshness", "never"),
+        ("libgs", "/tmp/libgs.so"),
+        ("pdf_page", "2"),
+        ("pdf_dpi", "300"),
+        ("video_seek", "5"),
+        ("plantuml_jar", "/tmp/plantuml.jar"),
+        ("timeout_equation", "1"),
+        ("timeout_plot", "1"),
+        ("prewarm", "true"),
+        ("stats_log", "/tmp/stats.log"),
+        ("max_processes", "64"),
+        ("sandbox", "firejail"),
+        ("shell_escape", "true"),
+        ("run_scripts", "true"),
+        ("python", "/tmp/python"),
+        ("script_commands", "ruby: ruby {input}"),
+        ("converters", "ditaa: ditaa {input} {output}.svg"),
+        ("cache_dir", "/tmp/cache"),
+        ("cache_size_limit", "1"),
+        ("keep_intermediates", "true"),
+        ("cache_key", "toolchain"),
+        ("cache_namespace", "other"),
+    ];
+
+    #[test]
+    fn only_output_options_are_overridden() {
+        let config = Config::new();
+
+        for (key, value) in OPTIONS {
+            assert!(Config::new().set(key, value).is_ok(), "{} has no valid test value", key);
+
+            let overridden = config.with_overrides(&[(key.to_string(), value.to_string())]);
+            if MAGIC_OPTIONS.contains(&key) {
+                assert_ne!(overridden, config, "{} should be overridden", key);
+            } else {
+                assert_eq!(overridden, config, "{} should be rejected", key);
+            }
+        }
+    }
+
+    #[test]
+    fn source_key_follows_latex_options() {
+        let config = Config::new();
+        let mut colored = Config::new();
+        colored.update(r#"{"foreground": "red", "bbox": "clip", "fonts": "woff2"}"#).unwrap();
+        let mut preamble = Config::new();
+        preamble.update(r#"{"preamble": "\\usepackage{bm}"}"#).unwrap();
+
+        assert_ne!(colored.key("x"), config.key("x"));
+        assert_eq!(colored.source_key("x"), config.source_key("x"));
+        assert_ne!(preamble.source_key("x"), config.source_key("x"));
+    }
+
+    #[test]
+    fn magic_options_are_known() {
+        for key in MAGIC_OPTIONS {
+            assert!(OPTIONS.iter().any(|x| x.0 == key), "{} is no option", key);
+        }
+    }
+
+    #[test]
+    fn cells_are_at_least_a_pixel() {
+        let mut config = Config::new();
+
+        for value in ["0,0", "0.5,0.5", "-8,16", "NaN,16", "8,inf"] {
+            assert!(config.set("cell_size", value).is_err(), "{}", value);
+        }
+        config.set("cell_size", "8.5,17").unwrap();
+        assert_eq!(config.cell_size, Some((8.5, 17.0)));
+    }
+}
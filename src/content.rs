@@ -1,14 +1,23 @@
 use regex::Regex;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::collections::{BTreeMap, HashMap};
-use std::thread;
-use std::sync::{RwLock, Arc};
-use magick_rust::MagickWand;
+use std::time::{Duration, Instant};
+use std::sync::{Mutex, RwLock, Arc};
+use image::RgbaImage;
+use magick_rust::{MagickWand, PixelWand};
+use miniserde::{json, Deserialize, Serialize};
 
+use crate::config::{Config, Placeholder};
 use crate::error::{Error, Result};
-use crate::render::{FoldState, Fold, FoldInner, ART_PATH, CodeId};
-use crate::node_view::NodeView;
-use crate::utils;
+use crate::render::{art_path, FoldState, Fold, FoldInner, CodeId, Metadata};
+use crate::{animation, cache, converter, extract, gc, jobs, render, script, stats, svg, utils};
+use crate::animation::{Animation, Clock, Playback};
+use crate::cache::LruCache;
+use crate::extract::{Buffer, Extractor, Snippet};
+#[cfg(feature = "plotters")]
+use crate::plot;
 
 pub type Sixel = Vec<u8>;
 
@@ -16,95 +25,574 @@ pub type Sixel = Vec<u8>;
 pub struct NodeDim {
     pub(crate) height: usize,
     pub(crate) crop: Option<(usize, usize)>,
+    /// pixels up to the right edge of the window
+    pub(crate) max_width: usize,
+    /// frame of an animation drawn on its own, `None` for still images and all frames at once
+    pub(crate) frame: Option<usize>,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum ContentType {
     Math,
+    InlineMath,
     Gnuplot,
     Tex,
+    Circuit,
+    Typst,
+    Dot,
+    PlantUml,
+    /// code of a language run by an interpreter, named by its fence
+    Script(String),
+    /// source converted by a command of `converters`, named by its fence
+    Converter(String),
+    #[cfg(feature = "plotters")]
+    Plot,
+    Environment,
     File,
 }
 
 impl ContentType {
-    pub fn from_fence(kind: &str) -> Result<Self> {
+    pub fn from_fence(kind: &str, config: &Config) -> Result<Self> {
         match kind {
             "math" => Ok(Self::Math),
             "gnuplot" => Ok(Self::Gnuplot),
             "latex" | "tex" => Ok(Self::Tex),
+            "circuit" | "circuitikz" => Ok(Self::Circuit),
+            "typst" | "typ" => Ok(Self::Typst),
+            "dot" | "graphviz" => Ok(Self::Dot),
+            "plantuml" | "uml" => Ok(Self::PlantUml),
+            #[cfg(feature = "plotters")]
+            "plot" => Ok(Self::Plot),
+            _ if converter::find(kind, config).is_some() => Ok(Self::Converter(kind.to_string())),
+            _ if script::is_script(kind, config) => Ok(Self::Script(kind.to_string())),
             _ => Err(Error::UnknownFence(kind.to_string())),
         }
     }
 
-    pub fn generate(&self, content: String) -> Result<WrappedWand> {
-        let mut path = self.path(&content);
+    /// Type of a name given by `name`, the types without a fence included
+    pub fn from_name(name: &str, config: &Config) -> Result<Self> {
+        match name {
+            "inline_math" => Ok(Self::InlineMath),
+            "environment" => Ok(Self::Environment),
+            _ => Self::from_fence(name, config),
+        }
+    }
+
+    /// Whether the snippet is written in a fence, its code starting on the line below
+    pub fn is_fenced(&self) -> bool {
+        !matches!(self, ContentType::File | ContentType::Environment | ContentType::InlineMath)
+    }
+
+    /// Produce the image of a snippet, unless already cached
+    pub fn render(&self, content: &str, config: &Config) -> Result<RenderInfo> {
+        let start = Instant::now();
+        let res = self.render_inner(content, config);
+
+        if !config.stats_log.is_empty() {
+            stats::record(&config.stats_log, &self.key(content, config), start.elapsed(), &res);
+        }
+
+        res
+    }
+
+    fn render_inner(&self, content: &str, config: &Config) -> Result<RenderInfo> {
+        let key = self.key(content, config);
+        let mut path = self.path(content, config);
+        let content = &*self.source(content, config);
         let missing = !path.exists();
 
         if missing {
             match self {
                 ContentType::Math => {
-                    utils::parse_equation(&content, 1.0)?;
+                    utils::parse_equation(content, 1.0, config)?;
+                },
+                ContentType::InlineMath => {
+                    utils::parse_inline_equation(content, config)?;
                 },
                 ContentType::File => {
                     return Err(Error::FileNotFound(path))
                 },
                 ContentType::Tex => {
-                    utils::parse_latex(&content)?;
+                    utils::parse_latex(content, config)?;
+                },
+                ContentType::Circuit => {
+                    utils::parse_circuit(content, config)?;
+                },
+                ContentType::Typst => {
+                    utils::parse_typst(content, 1.0, config)?;
+                },
+                ContentType::Dot => {
+                    utils::parse_dot(content, config)?;
+                },
+                ContentType::PlantUml => {
+                    utils::parse_plantuml(content, config)?;
+                },
+                ContentType::Script(fence) => {
+                    script::run(fence, content, config)?;
+                },
+                ContentType::Converter(name) => {
+                    converter::run(name, content.as_bytes(), config)?;
+                },
+                #[cfg(feature = "plotters")]
+                ContentType::Plot => {
+                    plot::parse_plot(content, config)?;
+                },
+                ContentType::Environment => {
+                    utils::parse_environment(content, config)?;
                 },
                 ContentType::Gnuplot => {
-                    let path = utils::generate_latex_from_gnuplot(&content)?;
-                    utils::generate_svg_from_latex(&path, 1.0)?;
+                    let source = utils::generate_latex_from_gnuplot(content, config)?;
+                    utils::generate_svg_from_latex(&source, &path, 1.0, utils::bbox("papersize", false, config), config.timeout_plot, config)?;
                 },
             }
         }
 
         // rewrite path if ending as tex or gnuplot file
         if *self == ContentType::File {
-            if path.extension().unwrap() == "tex" {
-                path = utils::parse_latex_from_file(&path)?;
+            if path.extension().is_some_and(|x| x == "tex") {
+                path = utils::parse_latex_from_file(&path, config)?;
+            }
+
+            if path.extension().is_some_and(|x| x == "pdf") {
+                path = utils::parse_pdf(&path, config)?;
+            }
+
+            if path.extension().is_some_and(|x| x == "dot" || x == "gv") {
+                path = utils::parse_dot_from_file(&path, config)?;
+            }
+
+            if path.extension().is_some_and(|x| utils::VIDEO_EXTENSIONS.contains(&x.to_string_lossy().to_lowercase().as_str())) {
+                path = utils::parse_video(&path, config)?;
+            }
+
+            if converter::for_file(&path, config).is_some() {
+                path = converter::run_file(&path, config)?;
+            }
+
+            if path.extension().is_some_and(|x| x == "plt") {
+                path = utils::generate_latex_from_gnuplot_file(&path, config)?;
+            }
+        }
+
+        // the log of latex sits next to the DVI, which is shared by all SVGs of the snippet
+        let source = utils::source_path(self, content, config);
+        let log = if *self == ContentType::File { path.with_extension("log") } else { source.with_extension("log") };
+
+        let status = if missing { CacheStatus::Miss } else { CacheStatus::Hit };
+        let mut info = RenderInfo::read(key, &path, &log, status)?;
+
+        // the metrics are in the sidecar now, only the DVI and log are kept for other SVGs
+        if path.starts_with(art_path()) {
+            if !missing {
+                cache::touch(&path);
+            } else if !config.keep_intermediates {
+                gc::remove_intermediates(&path)?;
+                gc::remove_intermediates(&source)?;
             }
+        }
+
+        // the baseline is meaningless for rotated content
+        if config.rotation_deg != 0.0 {
+            info.depth = None;
+        }
+
+        if config.emit_png && path.starts_with(art_path()) && path.extension().is_some_and(|x| x == "svg") {
+            info.raster = Some(rasterize(&path, config)?);
+        }
+
+        // SVGs are rasterized at 600 dpi, one pt is 1/72 inch
+        if let (Some((max_width, max_height)), true) = (config.max_size, info.path.ends_with(".svg")) {
+            let (width, height) = ((info.width * 600.0 / 72.0) as usize, (info.height * 600.0 / 72.0) as usize);
+            if width > max_width || height > max_height {
+                info.warnings.push(format!("downscaled from {}x{} pixels to fit into {}x{}", width, height, max_width, max_height));
+            }
+        }
+
+        Ok(info)
+    }
+
+    /// Load the image of a snippet, a result in `memory` saves looking the snippet up on disk
+    ///
+    /// Linked files are always resolved again, they may change at any time.
+    pub fn generate(&self, content: String, config: &Config, memory: Option<&Memory>) -> Result<WrappedWand> {
+        let key = (*self != ContentType::File).then(|| self.key(&content, config));
+        let cached = memory.zip(key.as_ref()).and_then(|(memory, key)| memory.lock().unwrap().get(key));
+
+        let info = match cached {
+            Some(info) => info,
+            None => {
+                let info = self.render(&content, config)?;
+                if let (Some(memory), Some(key)) = (memory, key) {
+                    memory.lock().unwrap().insert(key, info.clone());
+                }
+
+                info
+            },
+        };
 
-            if path.extension().unwrap() == "plt" {
-                let new_path = utils::generate_latex_from_gnuplot_file(&path)?;
-                path = new_path.with_extension("svg");
+        // photos and the rasters of scripts are letterboxed into their node once the size is known
+        if render::is_raster(Path::new(&info.path)) {
+            // animations keep all frames, the backend decides how they are played
+            if config.animate {
+                if let Some(animation) = animation::decode(Path::new(&info.path))? {
+                    return Ok(WrappedWand(MagickWand::new(), Some(Pixels::Animated(Arc::new(animation)))));
+                }
             }
+
+            let image = render::load_raster(Path::new(&info.path))?;
+
+            return Ok(WrappedWand(MagickWand::new(), Some(Pixels::Raster(image))));
         }
 
-        let wand = MagickWand::new();
-        wand.set_resolution(600.0, 600.0).unwrap();
+        // SVGs are rasterized at the size of their node, unless a raster was stored or they embed
+        // fonts, which only imagemagick draws
+        if info.raster.is_none() && info.path.ends_with(".svg") && svg::is_native(config) {
+            let tree = svg::load_tree(Path::new(&info.path))?;
+
+            return Ok(WrappedWand(MagickWand::new(), Some(Pixels::Svg(Arc::new(tree)))));
+        }
 
-        wand.read_image(path.to_str().unwrap())
-            .map_err(|_| Error::InvalidImage(path.to_str().unwrap().to_string()))?;
+        let wand = svg_wand(config)?;
+
+        // prefer the raster, it saves converting the SVG again
+        let path = info.raster.as_ref().unwrap_or(&info.path);
+        wand.read_image(path)
+            .map_err(|_| Error::InvalidImage(path.clone()))?;
+        fit_within(&wand, config.max_size);
 
         //wand.set_compression_quality(5).unwrap();
         //wand.transform_image_colorspace(ColorspaceType_GRAYColorspace).unwrap();
         //wand.quantize_image(8, ColorspaceType_GRAYColorspace, 0, DitherMethod_NoDitherMethod, 0).unwrap();
 
-        Ok(WrappedWand(wand))
+        Ok(WrappedWand(wand, None))
     }
-    
-    pub fn path(&self, content: &str) -> PathBuf {
-        let id = utils::hash(content);
+
+    /// Source which is hashed and rendered, with latex comments stripped if enabled
+    pub fn source<'a>(&self, content: &'a str, config: &Config) -> Cow<'a, str> {
+        match self {
+            ContentType::Math | ContentType::Tex | ContentType::Circuit | ContentType::Environment if config.strip_comments => {
+                Cow::Owned(utils::strip_latex_comments(content))
+            },
+            // keep the delimiters, inline and display versions of an expression differ
+            ContentType::InlineMath => Cow::Owned(format!("\\({}\\)", content)),
+            _ => Cow::Borrowed(content),
+        }
+    }
+
+    /// Name of the type, as used by fences
+    pub fn name(&self) -> &str {
+        match self {
+            ContentType::Math => "math",
+            ContentType::InlineMath => "inline_math",
+            ContentType::Gnuplot => "gnuplot",
+            ContentType::Tex => "tex",
+            ContentType::Circuit => "circuit",
+            ContentType::Typst => "typst",
+            ContentType::Dot => "dot",
+            ContentType::PlantUml => "plantuml",
+            ContentType::Script(fence) => fence,
+            ContentType::Converter(name) => name,
+            #[cfg(feature = "plotters")]
+            ContentType::Plot => "plot",
+            ContentType::Environment => "environment",
+            ContentType::File => "file",
+        }
+    }
+
+    /// Processed source prefixed with the name of the type, which is hashed into the cache keys
+    ///
+    /// Equal sources of two types, like `x` as math and as typst, so don't share their artifacts.
+    pub fn tagged(&self, source: &str) -> String {
+        format!("{}:{}", self.name(), source)
+    }
+
+    /// Key identifying the snippet, linked files are identified by their name only
+    pub fn key(&self, content: &str, config: &Config) -> String {
+        match self {
+            ContentType::File => utils::hash(content),
+            ContentType::Converter(name) => converter::key(name, self.source(content, config).as_bytes(), config),
+            _ => config.key(&self.tagged(&self.source(content, config))),
+        }
+    }
+
+    /// Key of the latex source and DVI the SVG is converted from, for snippets compiled by latex
+    pub fn source_key(&self, content: &str, config: &Config) -> Option<String> {
+        match self {
+            ContentType::Math | ContentType::InlineMath | ContentType::Tex | ContentType::Circuit
+                | ContentType::Environment | ContentType::Gnuplot => Some(config.source_key(&self.tagged(&self.source(content, config)))),
+            _ => None,
+        }
+    }
+
+    pub fn path(&self, content: &str, config: &Config) -> PathBuf {
         match self {
             ContentType::File => PathBuf::from(content),
-            _ => PathBuf::from(ART_PATH).join(id).with_extension("svg"),
+            ContentType::Script(_) | ContentType::Converter(_) => script::output_path(&self.key(content, config)),
+            _ => art_path().join(self.key(content, config)).with_extension("svg"),
+        }
+    }
+}
+
+/// Whether a snippet was served from the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum CacheStatus {
+    #[serde(rename = "hit")]
+    Hit,
+    #[serde(rename = "miss")]
+    Miss,
+}
+
+/// Wand reading SVGs at 600 dpi, onto a transparent canvas if enabled
+///
+/// The resolution grows with the cell size, so that images aren't upscaled on high-DPI screens.
+fn svg_wand(config: &Config) -> Result<MagickWand> {
+    let wand = MagickWand::new();
+    let dpi = 600.0 * utils::dpi_scale().max(1.0) as f64;
+    wand.set_resolution(dpi, dpi)
+        .map_err(|_| Error::InvalidSvg(format!("imagemagick rejected a resolution of {} dpi", dpi)))?;
+
+    // imagemagick fills the canvas of SVGs white by default
+    if config.transparent {
+        let mut none = PixelWand::new();
+        none.set_color("none")
+            .and_then(|_| wand.set_background_color(&none))
+            .map_err(|_| Error::InvalidSvg("imagemagick rejected a transparent background".to_string()))?;
+    }
+
+    Ok(wand)
+}
+
+/// Convert a cached SVG to a PNG next to it, unless already done
+fn rasterize(path: &Path, config: &Config) -> Result<String> {
+    let utf8 = |path: &Path| path.to_str().map(|x| x.to_string()).ok_or_else(|| Error::NonUtf8Path(path.to_path_buf()));
+    let png = path.with_extension("png");
+    let png_str = utf8(&png)?;
+    let modified = |path: &Path| fs::metadata(path).and_then(|x| x.modified()).ok();
+
+    if modified(&png).is_some() && modified(&png) >= modified(path) {
+        return Ok(png_str);
+    }
+
+    if svg::is_native(config) {
+        // 600 dpi, sizes of usvg are in px at 96 dpi
+        let tree = svg::load_tree(path)?;
+        let (width, height) = (tree.size().width() * 600.0 / 96.0, tree.size().height() * 600.0 / 96.0);
+        let scale = match config.max_size {
+            Some((max_width, max_height)) => f32::min(1.0, f32::min(max_width as f32 / width, max_height as f32 / height)),
+            None => 1.0,
+        };
+
+        svg::rasterize(&tree, (height * scale) as usize, config.transparent).save(&png)
+            .map_err(|_| Error::InvalidImage(png_str.clone()))?;
+
+        return Ok(png_str);
+    }
+
+    let wand = svg_wand(config)?;
+    wand.read_image(&utf8(path)?)
+        .and_then(|_| {
+            fit_within(&wand, config.max_size);
+            wand.write_image(&png_str)
+        })
+        .map_err(|_| Error::InvalidImage(png_str.clone()))?;
+
+    Ok(png_str)
+}
+
+/// Downscale an image exceeding the maximum size, preserving the aspect ratio
+fn fit_within(wand: &MagickWand, max_size: Option<(usize, usize)>) {
+    if let Some((width, height)) = max_size {
+        if wand.get_image_width() > width || wand.get_image_height() > height {
+            wand.fit(width, height);
         }
     }
 }
 
+/// Location and placement metrics of a rendered snippet
+#[derive(Debug, Clone, Serialize)]
+pub struct RenderInfo {
+    pub key: String,
+    pub path: String,
+    /// size in pt, raster images report their pixel size
+    pub width: f32,
+    pub height: f32,
+    /// distance from the baseline to the lower edge in pt, if reported by latex
+    pub depth: Option<f32>,
+    /// PNG rasterized from the same compile, if enabled
+    pub raster: Option<String>,
+    pub status: CacheStatus,
+    /// problems which didn't prevent the render, like downscaling
+    pub warnings: Vec<String>,
+}
+
+/// Metrics stored next to a cached SVG, avoiding to parse it again
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+    width: f32,
+    height: f32,
+    depth: Option<f32>,
+}
+
+impl RenderInfo {
+    pub fn read(key: String, path: &Path, log: &Path, status: CacheStatus) -> Result<RenderInfo> {
+        let path_str = path.to_str().ok_or_else(|| Error::NonUtf8Path(path.to_path_buf()))?.to_string();
+
+        // only artifacts in the cache get a sidecar, nothing is written next to linked files
+        let sidecar = path.starts_with(art_path()) && path.extension().is_some_and(|x| x == "svg");
+        if sidecar {
+            if let Some(meta) = RenderInfo::read_sidecar(path) {
+                return Ok(RenderInfo { key, path: path_str, width: meta.width, height: meta.height, depth: meta.depth, raster: None, status, warnings: Vec::new() });
+            }
+        }
+
+        let (width, height) = if path.extension().is_some_and(|x| x == "svg") {
+            let svg = fs::read_to_string(path).map_err(Error::Io)?;
+            let (_, _, width, height) = svg::view_box(&svg)?;
+
+            (width, height)
+        } else {
+            let wand = MagickWand::new();
+            wand.ping_image(&path_str)
+                .map_err(|_| Error::InvalidImage(path_str.clone()))?;
+
+            (wand.get_image_width() as f32, wand.get_image_height() as f32)
+        };
+
+        // the preview package reports the snippet dimensions in the log
+        let depth = fs::read_to_string(log).ok()
+            .and_then(|log| utils::preview_depth(&log));
+
+        if sidecar {
+            let meta = json::to_string(&Sidecar { width, height, depth });
+            fs::write(path.with_extension("meta"), meta).map_err(Error::Io)?;
+        }
+
+        Ok(RenderInfo { key, path: path_str, width, height, depth, raster: None, status, warnings: Vec::new() })
+    }
+
+    /// Metrics of the sidecar, if present and not older than the SVG
+    fn read_sidecar(path: &Path) -> Option<Sidecar> {
+        let meta_path = path.with_extension("meta");
+        let modified = |path: &Path| fs::metadata(path).and_then(|x| x.modified()).ok();
+
+        if modified(&meta_path)? < modified(path)? {
+            return None;
+        }
+
+        json::from_str(&fs::read_to_string(meta_path).ok()?).ok()
+    }
+}
+
+/// Image decoded without imagemagick, scaled once the size of the node is known
+#[derive(Clone)]
+pub enum Pixels {
+    /// photo, letterboxed into the node
+    Raster(RgbaImage),
+    /// animated GIF or APNG, every frame letterboxed like a photo
+    Animated(Arc<Animation>),
+    /// SVG, rasterized by resvg at the height of the node
+    Svg(Arc<resvg::usvg::Tree>),
+}
+
+/// Image of a node, raster files and SVGs keep their decoded form until placed
 #[derive(Clone)]
-pub struct WrappedWand(MagickWand);
+pub struct WrappedWand(MagickWand, Option<Pixels>);
 
 impl WrappedWand {
-    pub fn wand_to_sixel(self, dim: NodeDim) -> Vec<u8> {
-        self.0.fit(100000, dim.height);
+    /// Scale and crop the image to the node, encoded for the terminal backend
+    pub fn wand_to_image(mut self, dim: NodeDim, config: &Config) -> Result<Vec<u8>> {
+        let backend = render::backend(config.backend);
+
+        let image = match &self.1 {
+            Some(Pixels::Raster(image)) => Some(letterbox(image, &dim)),
+            Some(Pixels::Animated(animation)) => match dim.frame {
+                Some(frame) => Some(letterbox(&animation.frames[frame.min(animation.frames.len() - 1)].0, &dim)),
+                None => {
+                    let frames = animation.frames.iter()
+                        .map(|(image, delay)| {
+                            let mut wand = render::image_wand(&letterbox(image, &dim));
+                            fit_to_node(&mut wand, &dim, config)?;
+
+                            Ok((wand, *delay))
+                        })
+                        .collect::<Result<Vec<(MagickWand, Duration)>>>()?;
 
-        if let Some(crop) = dim.crop {
-            self.0.crop_image(self.0.get_image_width(), crop.0, 0, crop.1 as isize).unwrap();
+                    return backend.encode_animation(animation.id, &frames);
+                },
+            },
+            Some(Pixels::Svg(tree)) => Some(svg::rasterize(tree, dim.height, config.transparent)),
+            None => None,
+        };
+
+        if let Some(image) = image {
+            self.0 = render::image_wand(&image);
         }
 
-        self.0.write_image_blob("sixel").unwrap()
+        fit_to_node(&mut self.0, &dim, config)?;
+
+        backend.encode(&self.0)
     }
+
+    /// Width over height of the image, if it has a size
+    fn aspect(&self) -> Option<f32> {
+        let (width, height) = match &self.1 {
+            Some(Pixels::Raster(image)) => (image.width() as f32, image.height() as f32),
+            Some(Pixels::Animated(animation)) => (animation.frames[0].0.width() as f32, animation.frames[0].0.height() as f32),
+            Some(Pixels::Svg(tree)) => (tree.size().width(), tree.size().height()),
+            None => (self.0.get_image_width() as f32, self.0.get_image_height() as f32),
+        };
+
+        (width > 0.0 && height > 0.0).then_some(width / height)
+    }
+
+}
+
+/// Photo or frame scaled to the height of a node, at most as wide as the window
+fn letterbox(image: &RgbaImage, dim: &NodeDim) -> RgbaImage {
+    let width = utils::window_pixel_width();
+    let natural = image.width() as usize * dim.height / (image.height() as usize).max(1);
+
+    render::letterbox(image, natural.min(width), dim.height)
+}
+
+/// Scale an image to the height of a node and crop it to the visible part
+fn fit_to_node(wand: &mut MagickWand, dim: &NodeDim, config: &Config) -> Result<()> {
+    let crop = |wand: &MagickWand, width, height, y| wand.crop_image(width, height, 0, y)
+        .map_err(|_| Error::Magick("crop the image to the window".to_string()));
+
+    wand.fit(100000, dim.height);
+    fit_within(wand, config.max_size);
+
+    if config.snap_to_cells {
+        snap_to_cells(wand, if config.transparent { "" } else { &config.background })?;
+    }
+
+    if let Some((height, y)) = dim.crop {
+        crop(wand, wand.get_image_width(), height, y as isize)?;
+    }
+
+    if wand.get_image_width() > dim.max_width && dim.max_width > 0 {
+        crop(wand, dim.max_width, wand.get_image_height(), 0)?;
+    }
+
+    Ok(())
+}
+
+/// Pad the image on the right and bottom to whole terminal cells
+fn snap_to_cells(wand: &MagickWand, background: &str) -> Result<()> {
+    let (cell_width, cell_height) = (utils::char_pixel_width(), utils::char_pixel_height());
+    let width = wand.get_image_width().div_ceil(cell_width) * cell_width;
+    let height = wand.get_image_height().div_ceil(cell_height) * cell_height;
+
+    // fall back to transparent padding for colors imagemagick doesn't understand
+    let mut color = PixelWand::new();
+    if background.is_empty() || color.set_color(background).is_err() {
+        color.set_color("none").map_err(|_| Error::Magick("create a transparent color".to_string()))?;
+    }
+
+    wand.set_image_background_color(&color)
+        .and_then(|_| wand.extend_image(width, height, 0, 0))
+        .map_err(|_| Error::Magick("pad the image to whole cells".to_string()))
 }
 
 unsafe impl Send for WrappedWand {}
@@ -126,41 +614,262 @@ impl ContentState {
 
 type Shared<T> = Arc<RwLock<T>>;
 
+/// Render results of the session by cache key, shared by the nodes and batches
+pub type Memory = Arc<Mutex<LruCache<String, RenderInfo>>>;
+
 pub struct Node {
     pub id: CodeId,
     pub range: (usize, usize),
+    /// column of inline math in the line, zero for blocks
+    pub column: usize,
+    /// characters of inline math in the line with its delimiters, zero for blocks
+    pub width: usize,
     content: (String, ContentType),
+    config: Config,
     state: Shared<ContentState>,
     sixel_cache: Shared<HashMap<NodeDim, Sixel>>,
+    /// line relative to the node and message of the last failed render, kept while it is retried
+    error: Option<(usize, String)>,
+    /// scale of the image relative to the lines it covers
+    pub zoom: f32,
+    /// frames of an animated file once decoded, kept while the image is encoded again
+    animation: Option<Arc<Animation>>,
+    /// position of the animation, stopped by pausing it
+    pub clock: Clock,
+    /// results of earlier renders, consulted before the cache directory
+    pub memory: Option<Memory>,
+}
+
+/// Problem of a node, at the line of the buffer it points at
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub id: CodeId,
+    /// line in the buffer, starting at one
+    pub line: usize,
+    /// column in characters, starting at zero
+    pub column: usize,
+    pub message: String,
 }
 
 impl Node {
-    pub fn new(id: CodeId, range: (usize, usize), content: &str, kind: ContentType) -> Node {
+    pub fn new(id: CodeId, range: (usize, usize), content: &str, kind: ContentType, config: &Config) -> Node {
         let state = ContentState::new();
         let sixel_cache = Arc::new(RwLock::new(HashMap::new()));
         let content = (content.to_string(), kind);
+        let config = config.clone();
 
         Node {
-            id, range, state, sixel_cache, content
+            id, range, column: 0, width: 0, state, sixel_cache, content, config, error: None, zoom: 1.0,
+            animation: None, clock: Clock::new(), memory: None,
         }
     }
 
-    pub fn get_sixel(&mut self, dim: NodeDim) -> Option<Result<Sixel>> {
-        let Node { sixel_cache, state, content, .. } = self;
+    /// Follow changed options, images encoded for the old ones are dropped
+    ///
+    /// Only options outside of the cache key change here, others give the node another id. Jobs
+    /// still encoding for the old options fill the dropped cache.
+    pub fn set_config(&mut self, config: &Config) {
+        if *config != self.config {
+            self.config = config.clone();
+            self.sixel_cache = Arc::new(RwLock::new(HashMap::new()));
+        }
+    }
+
+    /// Rows taken by the image in a window, the lines below the start of the node scaled by its zoom
+    ///
+    /// Images are shrunk to `max_rows` and, once their aspect ratio is known, to `max_cols`. Inline
+    /// math keeps the height of its line, it would cover the text around it otherwise.
+    pub fn rows(&self, metadata: &Metadata) -> usize {
+        let lines = self.range.1 - self.range.0;
+        if self.content.1 == ContentType::InlineMath {
+            return lines;
+        }
+
+        let mut rows = if self.zoom == 1.0 { lines } else { ((lines as f32 * self.zoom).round() as usize).max(1) };
+        if let Some(max_rows) = self.config.max_rows {
+            rows = rows.min(max_rows.cells(metadata.viewport.0 as usize));
+        }
+
+        let aspect = match &*self.state.read().unwrap() {
+            ContentState::Ok(wand) => wand.aspect(),
+            _ => None,
+        };
+        if let (Some(max_cols), Some(aspect)) = (self.config.max_cols, aspect) {
+            let max_width = max_cols.cells(metadata.viewport.1 as usize) * utils::char_pixel_width();
+            let width = utils::rows_pixel_height(rows) as f32 * aspect;
+
+            if width > max_width as f32 {
+                rows = ((rows as f32 * max_width as f32 / width) as usize).max(1);
+            }
+        }
+
+        rows
+    }
+
+    /// Lines from the start of the node to the one an error points at
+    ///
+    /// latex reports lines of the whole document, the snippet is located in the generated source to
+    /// translate them.
+    fn error_offset(&self, err: &Error) -> usize {
+        let (content, kind) = self.content();
+        let start = if kind.is_fenced() { 1 } else { 0 };
+
+        match err {
+            Error::InvalidMath(_, _, line) if *line != usize::MAX => {
+                let tex = fs::read_to_string(utils::source_path(kind, &kind.source(content, &self.config), &self.config)).unwrap_or_default();
+
+                start + utils::snippet_line(&tex, &kind.source(content, &self.config), *line).unwrap_or(0)
+            },
+            _ => start,
+        }
+    }
+
+    /// Problem of the last render, if it failed
+    pub fn diagnostic(&self) -> Option<Diagnostic> {
+        let error = match &*self.state.read().unwrap() {
+            ContentState::Err(err) => Some((self.error_offset(err), err.to_string())),
+            _ => self.error.clone(),
+        };
+
+        error.map(|(offset, message)| Diagnostic {
+            id: self.id.clone(),
+            line: self.range.0 + offset,
+            column: self.column,
+            message,
+        })
+    }
+
+    /// Placard showing why the snippet failed, unless disabled for it
+    pub fn error_badge(&self, err: &Error, dim: &NodeDim) -> Option<MagickWand> {
+        // nodes without a limit may span the whole window
+        let max_width = if dim.max_width > 0 { dim.max_width } else { utils::window_pixel_width() };
+
+        self.config.error_badge.then(|| render::error_badge(&err.to_string(), max_width))
+    }
+
+    /// Stand-in while the snippet renders, of the visible height of the node and at most square
+    pub fn placeholder(&self, dim: &NodeDim) -> Option<RgbaImage> {
+        // the previous frame of an animation stays until the next one is encoded
+        if dim.frame.is_some_and(|x| x > 0) {
+            return None;
+        }
+
+        let height = dim.crop.map_or(dim.height, |x| x.0);
+        let width = if dim.max_width > 0 { height.min(dim.max_width) } else { height };
+
+        match self.config.placeholder {
+            Placeholder::None => None,
+            Placeholder::Box => Some(render::placeholder(width, height, None)),
+            Placeholder::Spinner => {
+                let elapsed = jobs::queue().elapsed(&self.id).unwrap_or_default();
+
+                Some(render::placeholder(width, height, Some(elapsed)))
+            },
+        }
+    }
+
+    /// Frames of the image if it is an animation, the state is empty while it is encoded
+    fn animation(&mut self) -> Option<&Arc<Animation>> {
+        if let ContentState::Ok(WrappedWand(_, Some(Pixels::Animated(animation)))) = &*self.state.read().unwrap() {
+            self.animation = Some(animation.clone());
+        }
+
+        self.animation.as_ref()
+    }
+
+    pub fn is_animated(&mut self) -> bool {
+        self.animation().is_some()
+    }
+
+    /// Frame of an animation drawn on its own, `None` for still images and animations which the
+    /// terminal plays
+    ///
+    /// The frame follows the clock in steps of `animation_fps`, frames in between are skipped.
+    pub fn frame(&mut self, playback: Playback) -> Option<usize> {
+        let (running, elapsed) = (self.clock.is_running(), self.clock.elapsed());
+        let step = 1000 / self.config.animation_fps.clamp(1, 1000) as u128;
+        let animation = self.animation()?;
+
+        match playback {
+            Playback::Terminal if running => None,
+            Playback::Still => Some(0),
+            _ => Some(animation.frame_at(Duration::from_millis((elapsed.as_millis() / step * step) as u64))),
+        }
+    }
+
+    /// Source and type of the snippet
+    pub fn content(&self) -> (&str, &ContentType) {
+        (&self.content.0, &self.content.1)
+    }
+
+    /// Cache key of the snippet
+    pub fn key(&self) -> String {
+        self.content.1.key(&self.content.0, &self.config)
+    }
+
+    /// Keys of all cache entries of the node, the SVG and the latex source it was converted from
+    pub fn cache_keys(&self) -> Vec<String> {
+        let source = self.content.1.source_key(&self.content.0, &self.config);
+
+        std::iter::once(self.key()).chain(source).collect()
+    }
+
+    /// Whether the image is ready, so that its size is known
+    pub fn is_rendered(&self) -> bool {
+        matches!(*self.state.read().unwrap(), ContentState::Ok(_))
+    }
+
+    /// Whether the node is rendered into the cache directory, linked files are not
+    pub fn is_cached(&self) -> bool {
+        self.content.1 != ContentType::File
+    }
+
+    /// Whether the artifact of the node is in the cache directory already
+    pub fn is_stored(&self) -> bool {
+        self.is_cached() && self.content.1.path(&self.content.0, &self.config).exists()
+    }
+
+    /// Start generating the image in the background, unless already done
+    pub fn prewarm(&mut self) {
+        let mut state = self.state.write().unwrap();
+
+        if let ContentState::Empty = *state {
+            *state = ContentState::Running;
 
+            let state_cloned = self.state.clone();
+            let content = self.content.clone();
+            let config = self.config.clone();
+            let memory = self.memory.clone();
+            jobs::queue().submit(&self.id, move || {
+                let res = jobs::catch(|| content.1.generate(content.0, &config, memory.as_ref()));
+
+                *state_cloned.write().unwrap() = match res {
+                    Ok(res) => ContentState::Ok(res),
+                    Err(err) => ContentState::Err(err),
+                };
+            });
+        }
+    }
+
+    pub fn get_sixel(&mut self, dim: NodeDim) -> Option<Result<Sixel>> {
         // first check the SIXEL blob cache
-        if let Some(data) = (*sixel_cache.read().unwrap()).get(&dim) {
+        if let Some(data) = (*self.sixel_cache.read().unwrap()).get(&dim) {
+            self.error = None;
             return Some(Ok(data.clone()));
         }
 
+        let Node { id, sixel_cache, state, content, config, memory, .. } = self;
+
         let state_cont = std::mem::replace(&mut *state.write().unwrap(), ContentState::Empty);
 
         let (res, state_cont) = match state_cont {
             ContentState::Empty => {
                 let state_cloned = state.clone();
                 let content = content.clone();
-                thread::spawn(move || {
-                    let res = content.1.generate(content.0);
+                let config = config.clone();
+                let memory = memory.clone();
+                jobs::queue().submit(id, move || {
+                    let res = jobs::catch(|| content.1.generate(content.0, &config, memory.as_ref()));
 
                     *state_cloned.write().unwrap() = match res {
                         Ok(res) => ContentState::Ok(res),
@@ -173,14 +882,21 @@ impl Node {
             ContentState::Err(error) => 
                 (Some(Err(error)), ContentState::Empty),
             ContentState::Ok(content) => {
-                // start thread to calculate SIXEL blob
+                // queue encoding the image
                 let sixel_cache = sixel_cache.clone();
                 let state = state.clone();
+                let config = config.clone();
+
+                jobs::queue().submit(id, move || {
+                    let res = jobs::catch(|| content.clone().wand_to_image(dim.clone(), &config));
 
-                thread::spawn(move || {
-                    let res = content.clone().wand_to_sixel(dim.clone());
-                    sixel_cache.write().unwrap().insert(dim, res);
-                    *state.write().unwrap() = ContentState::Ok(content);
+                    *state.write().unwrap() = match res {
+                        Ok(res) => {
+                            sixel_cache.write().unwrap().insert(dim, res);
+                            ContentState::Ok(content)
+                        },
+                        Err(err) => ContentState::Err(err),
+                    };
                 });
 
                 (None, ContentState::Running)
@@ -190,109 +906,183 @@ impl Node {
 
         let _ = std::mem::replace(&mut *state.write().unwrap(), state_cont);
 
+        if let Some(Err(err)) = &res {
+            self.error = Some((self.error_offset(err), err.to_string()));
+        }
+
         res
     }
 }
 
+/// Nodes of a parsed buffer, the nodes and folds by position, the lines of the folds and whether
+/// some node changed
+pub type Parsed = (BTreeMap<String, Node>, BTreeMap<(usize, usize), FoldInner>, Vec<usize>, bool);
+
 pub struct Content {
-    fences_regex: Regex,
-    file_regex: Regex,
+    extractors: Vec<Box<dyn Extractor + Send + Sync>>,
     header_regex: Regex,
-    newlines: Regex,
 }
 
 impl Content {
-    pub fn new() -> Content {
+    /// Parser of buffers of a Vim filetype, see `extract::for_filetype`
+    pub fn new(filetype: &str) -> Content {
         Content {
-            fences_regex: Regex::new(r"```(?P<name>([a-z]{3,}))(,height=(?P<height>([\d]+)))?[\w]*\n(?P<inner>[\s\S]+?)?```").unwrap(),
-            file_regex: Regex::new(r#"\n(?P<alt>!\[[^\]]*\])\((?P<file_name>.*?)\)(?P<new_lines>\n*)"#).unwrap(),
-            header_regex: Regex::new(r"\n(#{1,6}.*)").unwrap(),
-            newlines: Regex::new(r"\n").unwrap(),
+            extractors: extract::for_filetype(filetype),
+            // org keywords like `#+TITLE` are no headers
+            header_regex: Regex::new(r"(?m)\n(#{1,6}([^#+\n].*)?)$").unwrap(),
         }
     }
 
-    pub fn process(&self, content: &str, mut old_nodes: BTreeMap<String, Node>) -> Result<(BTreeMap<String, Node>, BTreeMap<usize, FoldInner>, Vec<usize>, bool)> {
-        // put new lines into a btree map for later
-        let (_, mut new_lines) = self.newlines.find_iter(content)
-            .map(|x| x.start())
-            .fold((1, BTreeMap::new()), |(mut nr, mut map): (usize, BTreeMap<usize, usize>), idx| {
-                nr += 1;
-                map.insert(idx, nr);
-
-                (nr, map)
-            });
-        new_lines.insert(1, 1);
+    pub fn process(&self, content: &str, mut old_nodes: BTreeMap<String, Node>, config: &Config) -> Result<Parsed> {
+        let buffer = Buffer::new(content);
 
         let folds = self.header_regex.find_iter(content)
-            .filter_map(|x| new_lines.get(&x.start()))
-            .copied()
+            .map(|x| buffer.line(x.start() + 1))
             .collect::<Vec<_>>();
 
         let mut nodes = BTreeMap::new();
         let mut any_changed = false;
 
-        let maths = self.fences_regex.captures_iter(content)
-            .map(|x| {
-                let kind = x.name("name").unwrap().as_str();
-                let content = x.name("inner").map_or("", |x| x.as_str()).to_string();
-                let height = x.name("height")
-                    .and_then(|x| x.as_str().parse::<usize>().ok())
-                    .unwrap_or_else(|| content.matches('\n').count() + 1);
-                let line = new_lines.get(&(x.get(0).unwrap().start() - 1)).unwrap();
-                let id = utils::hash(&content);
-
-                ContentType::from_fence(kind).map(|c|
-                    (height, *line, content, id, c)
-                )
-            });
-
-        let files = self.file_regex.captures_iter(content)
-            .map(|x| {
-                let file_name = x.name("file_name").unwrap().as_str().to_string();
-                let height = x.name("new_lines").unwrap().as_str().len() - 1;
-                let line = new_lines.get(&x.get(0).unwrap().start()).unwrap() + 1;
-                let id = utils::hash(&file_name);
-
-                Ok((height, line, file_name, id, ContentType::File))
-            });
+        let snippets = self.extractors.iter()
+            .map(|x| x.extract(&buffer, config))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
 
+        // snippets within others, like an environment in a latex fence, are part of the outer one
+        let nested = |x: &Snippet| snippets.iter()
+            .any(|outer| outer.span != x.span && outer.span.start <= x.span.start && x.span.end <= outer.span.end);
 
-        let strcts_gen = maths.chain(files)
-            .map(|x| x.map(|(height, line, content, id, kind)| {
+        let strcts_gen = snippets.iter()
+            .filter(|x| !nested(x))
+            .map(|Snippet { line, column, width, height, content, id, kind, .. }| {
+                let (line, column) = (*line, *column);
                 let new_range = (line, line + height);
 
                 // try to load from existing structures
-                if let Some(mut node) = old_nodes.remove(&id) {
+                if let Some(mut node) = old_nodes.remove(id) {
                     if new_range != node.range {
                         any_changed = true;
                     }
                     node.range = new_range;
+                    node.column = column;
+                    node.width = *width;
+                    // magic comments may have changed options outside of the key
+                    node.set_config(config);
 
                     nodes.insert(id.clone(), node);
                 } else {
                     any_changed = true;
 
-                    nodes.insert(id.clone(), Node::new(id.clone(), new_range, &content, kind));
+                    let mut node = Node::new(id.clone(), new_range, content, kind.clone(), config);
+                    node.column = column;
+                    node.width = *width;
+                    nodes.insert(id.clone(), node);
                 }
 
-                (line, FoldInner::Node((id, NodeView::Hidden)))
-            }));
+                Ok(((line, column), FoldInner::Node(id.clone())))
+            });
 
         let strcts = folds.iter()
             .map(|line| {
                 let new_fold = Fold {
                     state: FoldState::Open,
-                    line: *line,
                 };
-                Ok((*line, FoldInner::Fold(new_fold)))
+                Ok(((*line, 0), FoldInner::Fold(new_fold)))
             })
             .chain(strcts_gen)
             .collect::<Result<BTreeMap<_, _>>>()?;
 
-        //dbg!(&strcts);
-
         Ok((nodes, strcts, folds, any_changed))
     }
 
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_follow_the_type() {
+        render::test_art_path();
+        let config = Config::new();
+
+        assert_ne!(ContentType::Math.key("x", &config), ContentType::Typst.key("x", &config));
+        assert_ne!(ContentType::Math.source_key("x", &config), ContentType::Environment.source_key("x", &config));
+
+        // the parsers write to the path the render reads, inline math keeps its delimiters
+        assert_eq!(ContentType::Math.path("x", &config), utils::svg_path(&ContentType::Math, "x", 1.0, &config));
+        assert_eq!(ContentType::InlineMath.path("x", &config), utils::svg_path(&ContentType::InlineMath, "\\(x\\)", 1.0, &config));
+    }
+
+    #[test]
+    fn images_follow_the_backend() {
+        let mut config = Config::new();
+        let mut node = Node::new("id".to_string(), (1, 3), "x", ContentType::Math, &config);
+        let dim = NodeDim { height: 10, crop: None, max_width: 100, frame: None };
+        node.sixel_cache.write().unwrap().insert(dim.clone(), b"sixel".to_vec());
+
+        node.set_config(&config);
+        assert!(node.sixel_cache.read().unwrap().contains_key(&dim));
+
+        config.backend = render::BackendKind::Kitty;
+        node.set_config(&config);
+        assert!(node.sixel_cache.read().unwrap().is_empty());
+        assert_eq!(node.config.backend, render::BackendKind::Kitty);
+    }
+
+    #[test]
+    fn reused_nodes_follow_magic_comments() {
+        let content = Content::new("markdown");
+        let text = "<!-- preview: error_badge=false -->\n```math\nx\n```\n";
+        let (nodes, ..) = content.process(text, BTreeMap::new(), &Config::new()).unwrap();
+        assert!(nodes.values().all(|x| x.config.error_badge));
+
+        let config = Config::new().with_overrides(&crate::config::magic_comments(text));
+        let (nodes, ..) = content.process(text, nodes, &config).unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert!(nodes.values().all(|x| !x.config.error_badge));
+    }
+
+    #[test]
+    fn results_in_memory_skip_the_disk() {
+        let dir = render::test_art_path().join("memory");
+        fs::create_dir_all(&dir).unwrap();
+        let svg = dir.join("remembered.svg");
+        fs::write(&svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="20"/>"#).unwrap();
+
+        let config = Config::new();
+        let info = RenderInfo {
+            key: ContentType::Math.key("x^3", &config), path: svg.to_string_lossy().to_string(), width: 10.0, height: 20.0,
+            depth: None, raster: None, status: CacheStatus::Miss, warnings: Vec::new(),
+        };
+        let memory: Memory = Arc::new(Mutex::new(LruCache::new(1)));
+        memory.lock().unwrap().insert(info.key.clone(), info);
+
+        // nothing was rendered to the cache directory, latex would run and fail without the memory
+        assert!(!ContentType::Math.path("x^3", &config).exists());
+        assert!(ContentType::Math.generate("x^3".to_string(), &config, Some(&memory)).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paths_must_be_unicode() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"/tmp/\xff.svg"));
+        let res = RenderInfo::read(String::new(), path, path, CacheStatus::Hit);
+
+        assert!(matches!(res, Err(Error::NonUtf8Path(_))));
+    }
+
+    #[test]
+    fn names_are_parsed() {
+        let config = Config::new();
+
+        for kind in [ContentType::Math, ContentType::InlineMath, ContentType::Tex, ContentType::Environment, ContentType::Dot] {
+            assert_eq!(ContentType::from_name(kind.name(), &config).unwrap(), kind);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::{Config, Converter};
+use crate::error::{Error, Result};
+use crate::render::art_path;
+use crate::{gc, runner, script, utils};
+
+/// Converter configured for a fence name or the extension of a linked file
+pub fn find<'a>(name: &str, config: &'a Config) -> Option<&'a Converter> {
+    config.converters.iter().find(|x| x.name == name)
+}
+
+/// Converter of a linked file, by its lowercase extension
+pub fn for_file<'a>(path: &Path, config: &'a Config) -> Option<&'a Converter> {
+    let extension = path.extension()?.to_string_lossy().to_lowercase();
+
+    find(&extension, config)
+}
+
+/// Cache key of a snippet, editing the command renders it again
+///
+/// The content is hashed as bytes, linked files like MIDI are not text.
+pub fn key(name: &str, content: &[u8], config: &Config) -> String {
+    let command = find(name, config).map_or("", |x| x.command.as_str());
+
+    config.key(&format!("converter:{}:{}\n{}", name, command, utils::hash_bytes(content)))
+}
+
+/// Shell running the command template, so that it may use pipes and redirections
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+
+    cmd
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+
+    cmd
+}
+
+/// Convert a snippet with the command configured for its name and return the image it wrote
+///
+/// The content is written to `<key>.input` in the cache, the command runs there with `{input}`
+/// replaced by that file name and `{output}` by the key, so that it writes `{output}.svg` or
+/// `{output}.png`. SVGs are colored like all others. The input is an intermediate file, removed
+/// unless `keep_intermediates` is set.
+pub fn run(name: &str, content: &[u8], config: &Config) -> Result<PathBuf> {
+    let converter = find(name, config).ok_or_else(|| Error::UnknownFence(name.to_string()))?;
+    let key = key(name, content, config);
+    let input = format!("{}.input", key);
+
+    std::fs::write(art_path().join(&input), content).map_err(Error::Io)?;
+
+    let command = converter.command.replace("{input}", &input).replace("{output}", &key);
+    let cmd = runner::runner(config).output(shell(&command)
+        .current_dir(art_path())
+        .env("PREVIEW_INPUT", &input)
+        .env("PREVIEW_OUTPUT", &key), config.timeout_plot)?;
+
+    let output = script::output_path(&key);
+    if !cmd.status.success() || !output.exists() {
+        // the command may have written either image before failing
+        for ext in ["svg", "png"] {
+            let _ = std::fs::remove_file(art_path().join(&key).with_extension(ext));
+        }
+        let log = format!("{}{}", String::from_utf8_lossy(&cmd.stdout), String::from_utf8_lossy(&cmd.stderr));
+        return Err(Error::ConverterFailed(name.to_string(), log));
+    }
+
+    if output.extension().is_some_and(|x| x == "svg") {
+        utils::finish_svg(&output, config)?;
+    }
+
+    Ok(output)
+}
+
+/// Convert a linked file like a fence of its extension, the cache follows its content
+pub fn run_file(path: &Path, config: &Config) -> Result<PathBuf> {
+    let name = path.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+    let content = std::fs::read(path).map_err(Error::Io)?;
+
+    let output = script::output_path(&key(&name, &content, config));
+    if output.exists() {
+        return Ok(output);
+    }
+
+    let output = run(&name, &content, config)?;
+    if !config.keep_intermediates {
+        gc::remove_intermediates(&output)?;
+    }
+
+    Ok(output)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::content::{CacheStatus, ContentType};
+    use crate::render;
+
+    const SVG: &str = r#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="20" viewBox="0 0 10 20"><rect width="10" height="20"/></svg>"#;
+
+    fn config(name: &str, command: &str) -> Config {
+        let mut config = Config::new();
+        config.converters = vec![Converter { name: name.to_string(), command: command.to_string() }];
+
+        config
+    }
+
+    #[test]
+    fn fences_are_cached() {
+        render::test_art_path();
+        let config = config("box", &format!("test -s {{input}} && printf '%s' '{}' > {{output}}.svg", SVG));
+        let kind = ContentType::from_fence("box", &config).unwrap();
+
+        let info = kind.render("first", &config).unwrap();
+        assert_eq!((info.width, info.height, info.status), (10.0, 20.0, CacheStatus::Miss));
+        assert_eq!(kind.render("first", &config).unwrap().status, CacheStatus::Hit);
+
+        // other commands produce other images
+        let other = self::config("box", &format!("printf '%s' '{}' > {{output}}.svg", SVG));
+        assert_ne!(kind.key("first", &other), kind.key("first", &config));
+    }
+
+    #[test]
+    fn failures_leave_no_image() {
+        render::test_art_path();
+        let config = config("fail", "echo partial > {output}.png; echo partial > {output}.svg; echo broken >&2; exit 3");
+        let key = key("fail", b"content", &config);
+
+        let err = run("fail", b"content", &config).unwrap_err();
+        assert_eq!(err.to_string(), "converter fail failed: broken");
+        assert!(!art_path().join(&key).with_extension("svg").exists());
+        assert!(!art_path().join(&key).with_extension("png").exists());
+    }
+
+    #[test]
+    fn binary_files() {
+        let dir = render::test_art_path().join("binary");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("song.mid");
+        std::fs::write(&path, [0x4d, 0x54, 0xff, 0x00, 0x80]).unwrap();
+
+        let config = config("mid", &format!("cmp -s {{input}} '{}' && printf '%s' '{}' > {{output}}.svg", path.display(), SVG));
+        let output = run_file(&path, &config).unwrap();
+
+        assert_eq!(output, script::output_path(&key("mid", &std::fs::read(&path).unwrap(), &config)));
+        assert!(!output.with_extension("input").exists());
+    }
+}
@@ -0,0 +1,155 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use regex::Regex;
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::render::art_path;
+use crate::{runner, svg, utils};
+
+/// Prefix of the labels recording the buffer line of a heading
+const MARKER: &str = "preview-line-";
+
+/// Pages of a markdown buffer compiled as one latex document
+#[derive(Debug, Clone)]
+pub struct Pages {
+    pub key: String,
+    /// SVG of every page, in order
+    pub pages: Vec<PathBuf>,
+    /// buffer line of every heading and the page it landed on, counted from one
+    anchors: Vec<(usize, usize)>,
+}
+
+impl Pages {
+    /// Index of the page showing a buffer line, the one of the heading above it
+    pub fn page_of(&self, line: usize) -> usize {
+        let page = self.anchors.iter()
+            .take_while(|(anchor, _)| *anchor <= line)
+            .last()
+            .map_or(1, |(_, page)| *page);
+
+        page.clamp(1, self.pages.len().max(1)) - 1
+    }
+}
+
+/// Put a label in front of every heading outside of code fences
+///
+/// The labels end up in the aux file with the page they were typeset on, which relates the lines
+/// of the buffer to the pages.
+fn annotate(markdown: &str) -> String {
+    let mut out = String::new();
+    let mut fenced = false;
+
+    for (i, line) in markdown.lines().enumerate() {
+        if line.trim_start().starts_with("```") {
+            fenced = !fenced;
+        }
+        if !fenced && line.starts_with('#') {
+            out.push_str(&format!("`\\label{{{}{}}}`{{=latex}}\n\n", MARKER, i + 1));
+        }
+
+        out.push_str(line);
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Lines and pages of the heading labels in an aux file
+fn read_anchors(aux: &str) -> Vec<(usize, usize)> {
+    let label = Regex::new(&format!(r"\\newlabel\{{{}(\d+)\}}\{{\{{[^}}]*\}}\{{(\d+)\}}", MARKER)).unwrap();
+
+    let mut anchors = label.captures_iter(aux)
+        .filter_map(|x| Some((x[1].parse().ok()?, x[2].parse().ok()?)))
+        .collect::<Vec<_>>();
+    anchors.sort();
+
+    anchors
+}
+
+/// Convert a markdown buffer with pandoc to a standalone latex document and compile its pages
+///
+/// The pages are converted by dvisvgm like snippets, but at the paper size and with glyphs as
+/// paths. They are cached by the content, so that jumping around in a document is free. Relative
+/// paths of images are resolved against `dir`, the directory of the buffer.
+pub fn render(markdown: &str, dir: &Path, config: &Config) -> Result<Pages> {
+    let key = config.key(&format!("document:{}:{}", dir.display(), markdown));
+    let path = art_path().join(&key).with_extension("tex");
+
+    if !path.exists() {
+        fs::write(path.with_extension("md"), annotate(markdown)).map_err(Error::Io)?;
+
+        let pandoc = which::which("pandoc").map_err(Error::BinaryNotFound)?;
+        let cmd = runner::runner(config).output(Command::new(pandoc)
+            .current_dir(art_path())
+            .arg("--standalone")
+            .arg("--from=markdown")
+            .arg("--to=latex")
+            .arg(format!("--resource-path={}", dir.display()))
+            .arg("-o").arg(path.file_name().unwrap())
+            .arg(path.with_extension("md").file_name().unwrap()), config.timeout_plot)?;
+
+        if !cmd.status.success() {
+            let _ = fs::remove_file(&path);
+            return Err(Error::ToolFailed("pandoc".to_string(), String::from_utf8_lossy(&cmd.stderr).to_string()));
+        }
+    }
+
+    let pages = convert_pages(&path, dir, config)?;
+    let anchors = read_anchors(&fs::read_to_string(path.with_extension("aux")).unwrap_or_default());
+
+    Ok(Pages { key, pages, anchors })
+}
+
+/// SVGs of all pages of a compiled document, named `<key>-<page>.svg`
+fn convert_pages(path: &Path, dir: &Path, config: &Config) -> Result<Vec<PathBuf>> {
+    let existing = || -> Vec<PathBuf> {
+        let stem = path.file_stem().unwrap().to_string_lossy().to_string();
+        let mut pages = fs::read_dir(art_path()).into_iter().flatten()
+            .filter_map(|x| x.ok())
+            .filter_map(|x| {
+                let name = x.file_name().to_string_lossy().to_string();
+                let page = name.strip_prefix(&format!("{}-", stem))?.strip_suffix(".svg")?.parse::<usize>().ok()?;
+
+                Some((page, x.path()))
+            })
+            .collect::<Vec<_>>();
+        pages.sort();
+
+        pages.into_iter().map(|x| x.1).collect()
+    };
+
+    let pages = existing();
+    if !pages.is_empty() {
+        return Ok(pages);
+    }
+
+    let out_path = utils::compile_latex(path, Some(dir), config.timeout_plot, config)?;
+
+    let dvisvgm = which::which("dvisvgm").map_err(Error::BinaryNotFound)?;
+    let mut cmd = Command::new(dvisvgm);
+    if config.engine.output() == "pdf" {
+        cmd.arg("--pdf");
+    }
+
+    let cmd = runner::runner(config).output(cmd
+        .current_dir(art_path())
+        .arg("--page=1-")
+        .arg("-b").arg("papersize")
+        .arg("--no-fonts")
+        .arg("-o").arg("%f-%p.svg")
+        .arg(out_path.file_name().unwrap()), config.timeout_plot)?;
+
+    if !cmd.status.success() {
+        return Err(Error::InvalidDvisvgm(String::from_utf8_lossy(&cmd.stderr).to_string()));
+    }
+
+    let pages = existing();
+    for page in &pages {
+        svg::postprocess(page, config)?;
+    }
+
+    Ok(pages)
+}
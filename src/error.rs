@@ -4,14 +4,37 @@ use std::fmt;
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Errors of rendering a snippet
 #[derive(Debug)]
 pub enum Error {
     InvalidMath(String, String, usize), // reason, element, line
+    LatexCrashed(String), // error output
+    SpawnFailed(String, io::Error), // binary, cause
     InvalidDvisvgm(String),
+    ToolFailed(String, String), // binary, error output
+    InvalidGnuplot(String), // error output
+    ScriptFailed(String, String), // interpreter, error output
+    ScriptsDisabled(String), // fence
+    ConverterFailed(String, String), // converter, error output
+    #[cfg_attr(not(feature = "plotters"), allow(dead_code))]
+    InvalidPlot(String),
+    CropFailed(String),
+    GhostscriptMissing,
+    NothingTypeset,
     FileNotFound(PathBuf),
+    NonUtf8Path(PathBuf),
     BinaryNotFound(which::Error),
+    MissingPackage(String),
+    ShellEscapeRequired(String), // reason
     UnknownFence(String),
+    Timeout(String, String), // binary, partial output
+    Cancelled(String),
+    Panicked(String), // message
     InvalidImage(String),
+    InvalidSvg(String),
+    Magick(String), // failed operation
+    UnknownOption(String),
+    InvalidOption(String, String), // option, value
     Io(io::Error),
 }
  
@@ -20,16 +43,58 @@ impl fmt::Display for Error {
         let res = match self {
             Error::InvalidMath(reason, element, line) =>
                 format!("could not parse math {} at {} bc. {}", element, line, reason),
+            Error::LatexCrashed(err) =>
+                format!("latex crashed without a log: {}", err.lines().next().unwrap_or("")),
+            Error::SpawnFailed(binary, err) =>
+                format!("could not start {}: {}", binary, err),
             Error::InvalidDvisvgm(err) => 
                 err.to_string(),
+            Error::ToolFailed(binary, err) =>
+                format!("{} failed: {}", binary, err.lines().next().unwrap_or("")),
+            Error::InvalidGnuplot(err) =>
+                format!("could not plot with gnuplot: {}", err.lines().rev().find(|x| !x.trim().is_empty()).unwrap_or("").trim()),
+            Error::InvalidPlot(reason) =>
+                format!("could not draw plot: {}", reason),
+            Error::ScriptFailed(interpreter, err) =>
+                format!("{} failed: {}", interpreter, err.lines().rev().find(|x| !x.trim().is_empty()).unwrap_or("").trim()),
+            Error::ScriptsDisabled(fence) =>
+                format!("{} fences run code, enable the `run_scripts` option if you trust the document", fence),
+            Error::ConverterFailed(name, err) =>
+                format!("converter {} failed: {}", name, err.lines().rev().find(|x| !x.trim().is_empty()).unwrap_or("").trim()),
+            Error::CropFailed(err) =>
+                format!("could not crop PDF: {}", err),
+            Error::GhostscriptMissing =>
+                "PostScript specials need Ghostscript, install it or set the option libgs".to_string(),
+            Error::NothingTypeset =>
+                "latex produced no visible output, is the content blank or commented out?".to_string(),
             Error::FileNotFound(path) =>
-                format!("could not find file {}", path.to_str().unwrap()),
+                format!("could not find file {}", path.display()),
+            Error::NonUtf8Path(path) =>
+                format!("path {} is not valid UTF-8", path.display()),
             Error::BinaryNotFound(binary) => 
                 format!("binary not found: {}", binary),
+            Error::MissingPackage(package) =>
+                format!("latex package {} is not installed", package),
+            Error::ShellEscapeRequired(reason) =>
+                format!("{}, enable the `shell_escape` option if you trust the document", reason),
             Error::UnknownFence(kind) =>
                 format!("unknown fence with name {}", kind),
+            Error::Timeout(binary, log) =>
+                format!("{} timed out, last output: {}", binary, log.lines().last().unwrap_or("")),
+            Error::Cancelled(binary) =>
+                format!("{} was cancelled", binary),
+            Error::Panicked(message) =>
+                format!("renderer panicked: {}", message),
             Error::InvalidImage(path) =>
                 format!("could not read in {} as image", path),
+            Error::InvalidSvg(reason) =>
+                format!("could not process SVG: {}", reason),
+            Error::Magick(operation) =>
+                format!("imagemagick could not {}", operation),
+            Error::UnknownOption(key) =>
+                format!("unknown option {}", key),
+            Error::InvalidOption(key, value) =>
+                format!("invalid value {} for option {}", value, key),
             Error::Io(io_err) => format!("IO error: {}", io_err)
         };
 
@@ -0,0 +1,37 @@
+/// Functions exported to the editors with the kind of their reply, `String` or nothing
+///
+/// Expands to `$callback!` with the whole list, the C exports for Vim and the msgpack-rpc
+/// dispatch for Neovim are generated from it.
+macro_rules! for_each_export {
+    ($callback:ident) => {
+        $callback! {
+            update_content: String,
+            update_metadata: (),
+            set_filetype: (),
+            update_config: String,
+            clear_all: (),
+            draw: String,
+            set_folds: String,
+            display_id: String,
+            verify_entry: String,
+            tex_distribution: String,
+            sync_document: String,
+            write_manifest: String,
+            error_report: String,
+            finished_jobs: String,
+            cancel_jobs: (),
+            anchors: String,
+            update_anchors: String,
+            preview_at: String,
+            update_region: String,
+            diagnostics: String,
+            preview_document: String,
+            zoom_in: String,
+            zoom_out: String,
+            zoom_reset: String,
+            virtual_lines: String,
+            play: String,
+            pause: String
+        }
+    };
+}
@@ -0,0 +1,475 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use regex::Regex;
+
+use crate::config::Config;
+use crate::content::ContentType;
+use crate::error::Result;
+use crate::{markdown, utils};
+
+/// Text of a buffer with the offsets of its line breaks
+pub struct Buffer<'a> {
+    pub text: &'a str,
+    newlines: Vec<usize>,
+}
+
+impl<'a> Buffer<'a> {
+    pub fn new(text: &'a str) -> Buffer<'a> {
+        let newlines = text.match_indices('\n').map(|x| x.0).collect();
+
+        Buffer { text, newlines }
+    }
+
+    /// Line of a byte offset, starting at one
+    pub fn line(&self, offset: usize) -> usize {
+        self.newlines.partition_point(|x| *x < offset) + 1
+    }
+
+    /// Byte offset of the first character of a line, starting at one
+    fn line_start(&self, line: usize) -> usize {
+        if line <= 1 { 0 } else { self.newlines[line - 2] + 1 }
+    }
+}
+
+/// Snippet found in a buffer, rendered by a node
+pub struct Snippet {
+    /// bytes of the buffer it was found in, snippets within another one are dropped
+    pub span: Range<usize>,
+    pub line: usize,
+    pub column: usize,
+    /// characters of inline math in the line with its delimiters, zero for blocks
+    pub width: usize,
+    /// lines covered below `line`
+    pub height: usize,
+    pub content: String,
+    pub id: String,
+    pub kind: ContentType,
+}
+
+impl Snippet {
+    /// Snippet of a block whose first line holds its opening delimiter, like a fence
+    fn block(span: Range<usize>, line: usize, content: String, kind: ContentType, config: &Config) -> Snippet {
+        let id = kind.key(&content, config);
+
+        Snippet { span, line, column: 0, width: 0, height: content.matches('\n').count() + 1, content, id, kind }
+    }
+}
+
+/// Finds the snippets of one markup language in a buffer
+pub trait Extractor {
+    fn extract(&self, buffer: &Buffer, config: &Config) -> Result<Vec<Snippet>>;
+}
+
+/// Extractors of a Vim filetype like `org.graphics`, others than org, asciidoc and rst are markdown
+///
+/// Quoting the markup of another language, like `.. math::` in a markdown note, stays text.
+pub fn for_filetype(filetype: &str) -> Vec<Box<dyn Extractor + Send + Sync>> {
+    let is = |name: &str| filetype.split('.').any(|x| x == name);
+
+    if is("org") {
+        vec![Box::new(Org::new()), Box::new(Environments::new()), Box::new(InlineMath)]
+    } else if is("asciidoc") || is("asciidoctor") {
+        vec![Box::new(AsciiDoc::new())]
+    } else if is("rst") {
+        vec![Box::new(Rest::new())]
+    } else {
+        vec![Box::new(Markdown::new()), Box::new(Environments::new()), Box::new(InlineMath)]
+    }
+}
+
+/// Fences, image links and vimwiki transclusions of markdown
+pub struct Markdown {
+    fences: Regex,
+    file: Regex,
+    wiki: Regex,
+}
+
+impl Markdown {
+    pub fn new() -> Markdown {
+        Markdown {
+            fences: Regex::new(r"```(?P<name>([a-z]{3,}|r\b))(,height=(?P<height>([\d]+)))?[\w]*\n(?P<inner>[\s\S]+?)?```").unwrap(),
+            file: Regex::new(r#"\n(?P<alt>!\[[^\]]*\])\((?P<file_name>.*?)\)(?P<new_lines>\n*)"#).unwrap(),
+            wiki: Regex::new(r#"\n\{\{(?P<file_name>[^}|]*?)(\|[^}]*)?\}\}(?P<new_lines>\n*)"#).unwrap(),
+        }
+    }
+}
+
+impl Extractor for Markdown {
+    fn extract(&self, buffer: &Buffer, config: &Config) -> Result<Vec<Snippet>> {
+        let mut snippets = self.fences.captures_iter(buffer.text)
+            .map(|x| {
+                let kind = ContentType::from_fence(x.name("name").unwrap().as_str(), config)?;
+                let content = x.name("inner").map_or("", |x| x.as_str()).to_string();
+                let whole = x.get(0).unwrap();
+
+                let mut snippet = Snippet::block(whole.range(), buffer.line(whole.start()), content, kind, config);
+                if let Some(height) = x.name("height").and_then(|x| x.as_str().parse::<usize>().ok()) {
+                    snippet.height = height;
+                }
+
+                Ok(snippet)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // vimwiki transclusions `{{file|description}}` behave like markdown image links
+        let files = self.file.captures_iter(buffer.text)
+            .chain(self.wiki.captures_iter(buffer.text))
+            .map(|x| {
+                let file_name = x.name("file_name").unwrap().as_str().to_string();
+                let whole = x.get(0).unwrap();
+
+                Snippet {
+                    span: whole.range(),
+                    line: buffer.line(whole.start() + 1) + 1,
+                    column: 0,
+                    width: 0,
+                    height: x.name("new_lines").unwrap().as_str().len().saturating_sub(1),
+                    id: utils::hash(&file_name),
+                    content: file_name,
+                    kind: ContentType::File,
+                }
+            });
+        snippets.extend(files);
+
+        Ok(snippets)
+    }
+}
+
+/// Source blocks of org-mode, like `#+BEGIN_SRC gnuplot`
+pub struct Org {
+    src: Regex,
+}
+
+impl Org {
+    pub fn new() -> Org {
+        Org {
+            src: Regex::new(r"(?i)\n#\+begin_src[ \t]+(?P<name>[\w+-]+)[^\n]*\n(?P<inner>[\s\S]*?)#\+end_src").unwrap(),
+        }
+    }
+}
+
+impl Extractor for Org {
+    fn extract(&self, buffer: &Buffer, config: &Config) -> Result<Vec<Snippet>> {
+        // blocks take the names of fences, others like `emacs-lisp` are plain code there
+        let snippets = self.src.captures_iter(buffer.text)
+            .filter_map(|x| {
+                let kind = ContentType::from_fence(&x.name("name").unwrap().as_str().to_lowercase(), config).ok()?;
+                let content = x.name("inner").unwrap().as_str().to_string();
+                let whole = x.get(0).unwrap();
+
+                Some(Snippet::block(whole.start() + 1..whole.end(), buffer.line(whole.start() + 1), content, kind, config))
+            })
+            .collect();
+
+        Ok(snippets)
+    }
+}
+
+/// Delimited blocks of asciidoc with a diagram or math style, like `[latexmath]` over `++++`
+pub struct AsciiDoc {
+    block: Regex,
+}
+
+impl AsciiDoc {
+    pub fn new() -> AsciiDoc {
+        AsciiDoc {
+            block: Regex::new(r"(?m)^\[(?P<name>latexmath|plantuml|graphviz)(,[^\]\n]*)?\]\n(?P<open>\+{4}|-{4}|\.{4})\n(?P<inner>[\s\S]*?)^(?P<close>\+{4}|-{4}|\.{4})$").unwrap(),
+        }
+    }
+}
+
+impl Extractor for AsciiDoc {
+    fn extract(&self, buffer: &Buffer, config: &Config) -> Result<Vec<Snippet>> {
+        let snippets = self.block.captures_iter(buffer.text)
+            // without backreferences the closing delimiter is compared here
+            .filter(|x| x["open"] == x["close"])
+            .map(|x| {
+                let kind = match &x["name"] {
+                    "latexmath" => ContentType::Math,
+                    "plantuml" => ContentType::PlantUml,
+                    _ => ContentType::Dot,
+                };
+                let open = x.name("open").unwrap();
+
+                // the image covers the delimited lines, the style line above stays visible
+                Snippet::block(open.start()..x.get(0).unwrap().end(), buffer.line(open.start()), x["inner"].to_string(), kind, config)
+            })
+            .collect();
+
+        Ok(snippets)
+    }
+}
+
+/// Directives of reStructuredText, `.. math::` and `.. graphviz::` with an indented body
+pub struct Rest {
+    directive: Regex,
+}
+
+impl Rest {
+    pub fn new() -> Rest {
+        Rest {
+            directive: Regex::new(r"(?m)^\.\. (?P<name>math|graphviz)::[ \t]*\n(?P<body>([ \t]*\n|[ \t]+\S[^\n]*\n?)*)").unwrap(),
+        }
+    }
+}
+
+/// Content of an indented directive body, without its options and indentation
+fn rest_body(body: &str) -> String {
+    let lines = body.lines()
+        .skip_while(|x| x.trim_start().starts_with(':'))
+        .skip_while(|x| x.trim().is_empty())
+        .collect::<Vec<_>>();
+    let indent = lines.iter()
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| x.len() - x.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    lines.iter()
+        .map(|x| x.get(indent..).unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim_end()
+        .to_string()
+}
+
+impl Extractor for Rest {
+    fn extract(&self, buffer: &Buffer, config: &Config) -> Result<Vec<Snippet>> {
+        let snippets = self.directive.captures_iter(buffer.text)
+            .filter_map(|x| {
+                let content = rest_body(&x["body"]);
+                if content.is_empty() {
+                    return None;
+                }
+
+                let kind = if &x["name"] == "math" { ContentType::Math } else { ContentType::Dot };
+                let start = x.get(0).unwrap().start();
+                // blank lines after the body separate it from the next paragraph
+                let body = x["body"].trim_end();
+                let end = x.name("body").unwrap().start() + body.len();
+
+                let mut snippet = Snippet::block(start..end, buffer.line(start), content, kind, config);
+                snippet.height = body.matches('\n').count() + 1;
+
+                Some(snippet)
+            })
+            .collect();
+
+        Ok(snippets)
+    }
+}
+
+/// Latex environments written directly in the document, up to the matching `\end`
+pub struct Environments {
+    begin: Regex,
+}
+
+impl Environments {
+    pub fn new() -> Environments {
+        Environments {
+            begin: Regex::new(r"\n\\begin\{(?P<name>align\*?|equation\*?|tikzpicture|tabular)\}").unwrap(),
+        }
+    }
+}
+
+/// End of the environment `name` opened right before `start`, after its `\end`
+///
+/// Environments of the same name nested inside of it are skipped, `\end` has to balance every
+/// `\begin`.
+fn environment_end(text: &str, name: &str, start: usize) -> Option<usize> {
+    let (begin, end) = (format!("\\begin{{{}}}", name), format!("\\end{{{}}}", name));
+    let mut depth = 1;
+    let mut idx = start;
+
+    while depth > 0 {
+        let next_end = text[idx..].find(&end)? + idx;
+        match text[idx..next_end].find(&begin) {
+            Some(nested) => {
+                depth += 1;
+                idx += nested + begin.len();
+            },
+            None => {
+                depth -= 1;
+                idx = next_end + end.len();
+            },
+        }
+    }
+
+    Some(idx)
+}
+
+impl Extractor for Environments {
+    fn extract(&self, buffer: &Buffer, config: &Config) -> Result<Vec<Snippet>> {
+        let mut snippets = Vec::new();
+        let mut idx = 0;
+
+        // environments nested in a previous one are part of its snippet
+        while let Some(x) = self.begin.captures_at(buffer.text, idx) {
+            let start = x.get(0).unwrap().start() + 1;
+            let Some(end) = environment_end(buffer.text, &x["name"], x.get(0).unwrap().end()) else {
+                idx = x.get(0).unwrap().end();
+                continue;
+            };
+
+            let content = buffer.text[start..end].to_string();
+            let id = ContentType::Environment.key(&content, config);
+
+            snippets.push(Snippet {
+                span: start..end,
+                line: buffer.line(start),
+                column: 0,
+                width: 0,
+                height: content.matches('\n').count(),
+                content,
+                id,
+                kind: ContentType::Environment,
+            });
+            idx = end;
+        }
+
+        Ok(snippets)
+    }
+}
+
+/// Inline math of the prose, `$...$` and `\(...\)`, with the option `inline_math`
+pub struct InlineMath;
+
+impl Extractor for InlineMath {
+    fn extract(&self, buffer: &Buffer, config: &Config) -> Result<Vec<Snippet>> {
+        if !config.inline_math {
+            return Ok(Vec::new());
+        }
+
+        // short expressions like `$n$` recur in prose, number them so that every one gets a node
+        let mut occurrences = HashMap::new();
+        let snippets = markdown::inline_math(buffer.text).into_iter()
+            .map(|x| {
+                let key = ContentType::InlineMath.key(&x.content, config);
+                let nr = occurrences.entry(key.clone()).or_insert(0);
+                *nr += 1;
+
+                let start = buffer.line_start(x.line);
+                let span = start + x.start..start + x.end;
+                Snippet {
+                    width: buffer.text[span.clone()].chars().count(),
+                    span,
+                    line: x.line,
+                    column: x.column,
+                    height: 1,
+                    content: x.content,
+                    id: format!("{}-{}", key, nr),
+                    kind: ContentType::InlineMath,
+                }
+            })
+            .collect();
+
+        Ok(snippets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extract(extractor: &dyn Extractor, text: &str) -> Vec<(usize, String)> {
+        extractor.extract(&Buffer::new(text), &Config::new()).unwrap().into_iter()
+            .map(|x| (x.line, x.content))
+            .collect()
+    }
+
+    /// Lines, types and contents of the snippets found by the extractors of a filetype
+    fn extract_filetype(filetype: &str, text: &str) -> Vec<(usize, ContentType, String)> {
+        let mut config = Config::new();
+        config.inline_math = true;
+        let buffer = Buffer::new(text);
+
+        for_filetype(filetype).iter()
+            .flat_map(|x| x.extract(&buffer, &config).unwrap())
+            .map(|x| (x.line, x.kind, x.content))
+            .collect()
+    }
+
+    #[test]
+    fn markdown_fences_and_links() {
+        let text = "text\n```math\nx^2\n```\n![alt](image.png)\n\n{{diagram.svg|a diagram}}\n";
+
+        assert_eq!(extract(&Markdown::new(), text), vec![
+            (2, "x^2\n".to_string()),
+            // images are shown below their link
+            (6, "image.png".to_string()),
+            (8, "diagram.svg".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn links_at_the_end() {
+        // no newline follows the link, the image takes no lines below it
+        for text in ["text\n![](a.png)", "text\n{{a.png}}"] {
+            let snippets = Markdown::new().extract(&Buffer::new(text), &Config::new()).unwrap();
+            assert_eq!(snippets.iter().map(|x| (x.line, x.height, x.content.as_str())).collect::<Vec<_>>(), [(3, 0, "a.png")]);
+        }
+    }
+
+    #[test]
+    fn org_source_blocks() {
+        let text = "* notes\n#+BEGIN_SRC Gnuplot :results file\nplot x\n#+END_SRC\n#+begin_src emacs-lisp\n(x)\n#+end_src\n";
+
+        assert_eq!(extract(&Org::new(), text), vec![(2, "plot x\n".to_string())]);
+    }
+
+    #[test]
+    fn asciidoc_blocks() {
+        let text = "[latexmath]\n++++\nx^2\n++++\n\n[graphviz,format=svg]\n----\na -> b\n....\n";
+
+        // the graphviz block is never closed by its own delimiter
+        assert_eq!(extract(&AsciiDoc::new(), text), vec![(2, "x^2\n".to_string())]);
+    }
+
+    #[test]
+    fn rest_directives() {
+        let text = ".. math::\n   :label: square\n\n   x^2\n\nText\n\n.. graphviz::\n\n.. note::\n   a -> b\n";
+
+        assert_eq!(extract(&Rest::new(), text), vec![(1, "x^2".to_string())]);
+    }
+
+    #[test]
+    fn inline_math() {
+        assert!(extract(&InlineMath, "costs $5 or $6\n").is_empty());
+
+        let mut config = Config::new();
+        config.inline_math = true;
+        let snippets = InlineMath.extract(&Buffer::new("a $x$ b $x$\n"), &config).unwrap();
+
+        assert_eq!(snippets.iter().map(|x| (x.column, x.content.as_str())).collect::<Vec<_>>(), [(2, "x"), (8, "x")]);
+        assert_ne!(snippets[0].id, snippets[1].id);
+    }
+
+    #[test]
+    fn extractors_follow_the_filetype() {
+        let text = "\n```dot\na -> b\n```\n.. math::\n\n   x^2\n\n#+begin_src dot\nc -> d\n#+end_src\n";
+
+        assert_eq!(extract_filetype("markdown.graphics", text), vec![(2, ContentType::Dot, "a -> b\n".to_string())]);
+        assert_eq!(extract_filetype("graphical-preview", text), extract_filetype("markdown.graphics", text));
+        assert_eq!(extract_filetype("rst.graphics", text), vec![(5, ContentType::Math, "x^2".to_string())]);
+        assert_eq!(extract_filetype("org.graphics", text), vec![(9, ContentType::Dot, "c -> d\n".to_string())]);
+        assert!(extract_filetype("asciidoc.graphics", text).is_empty());
+    }
+
+    #[test]
+    fn nested_environments() {
+        let text = "text\n\\begin{tikzpicture}\n\\begin{tikzpicture}\n\\end{tikzpicture}\n\\end{tikzpicture}\n\\begin{align}\nx\n\\end{align}\n";
+
+        assert_eq!(extract(&Environments::new(), text), vec![
+            (2, "\\begin{tikzpicture}\n\\begin{tikzpicture}\n\\end{tikzpicture}\n\\end{tikzpicture}".to_string()),
+            (6, "\\begin{align}\nx\n\\end{align}".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn unclosed_environments() {
+        let text = "\n\\begin{align}\nx\n\\begin{tabular}\n\\end{tabular}\n";
+
+        assert_eq!(extract(&Environments::new(), text), vec![(4, "\\begin{tabular}\n\\end{tabular}".to_string())]);
+    }
+}
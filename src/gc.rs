@@ -0,0 +1,75 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use crate::cache::{self, CacheEntry};
+use crate::error::{Error, Result};
+
+/// Files of a render which are not needed anymore once the SVG exists
+///
+/// The latex source is kept for `verify_entry`, the DVI or PDF and the log with the baseline are
+/// kept for converting the snippet again with other options or zoom.
+const INTERMEDIATE_EXTENSIONS: [&str; 5] = ["aux", "crop.pdf", "gp", "script", "input"];
+
+/// Key an entry belongs to, SVGs converted at another zoom belong to the one at the original size
+fn owner(key: &str) -> &str {
+    match key.rsplit_once("-zoom") {
+        Some((owner, zoom)) if !zoom.is_empty() && zoom.bytes().all(|x| x.is_ascii_digit()) => owner,
+        _ => key,
+    }
+}
+
+/// Evict the least recently used entries until the cache is at most `limit` bytes large
+///
+/// Entries in `keep`, for example the blocks of the open document and their latex sources, are
+/// never evicted, neither are their zoomed SVGs. `on_evict` is invoked for every evicted entry.
+pub fn collect(limit: u64, keep: &BTreeSet<String>, mut on_evict: impl FnMut(&CacheEntry)) -> Result<Vec<CacheEntry>> {
+    let mut entries = cache::entries()?;
+    let mut total = entries.iter().map(|x| x.size).sum::<u64>();
+
+    entries.sort_by_key(|x| x.accessed);
+
+    let mut evicted = Vec::new();
+    for entry in entries {
+        if total <= limit {
+            break;
+        }
+
+        if keep.contains(owner(&entry.key)) {
+            continue;
+        }
+
+        if let Some(entry) = cache::evict(&entry.key)? {
+            total = total.saturating_sub(entry.size);
+            on_evict(&entry);
+            evicted.push(entry);
+        }
+    }
+
+    Ok(evicted)
+}
+
+/// Remove the intermediate artifacts of a successful render
+pub fn remove_intermediates(svg: &Path) -> Result<()> {
+    for ext in INTERMEDIATE_EXTENSIONS {
+        match fs::remove_file(svg.with_extension(ext)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(Error::Io(err)),
+            _ => {},
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zoomed_svgs_belong_to_their_key() {
+        assert_eq!(owner("abc-zoom150"), "abc");
+        assert_eq!(owner("abc-zoom"), "abc-zoom");
+        assert_eq!(owner("abc-zoomx"), "abc-zoomx");
+        assert_eq!(owner("abc"), "abc");
+    }
+}
@@ -0,0 +1,183 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+
+/// Unit of work run by the pool, named for the notification when done
+struct Job {
+    id: String,
+    task: Box<dyn FnOnce() + Send>,
+}
+
+/// Function receiving the id of every finished job
+type Callback = Box<dyn Fn(&str) + Send>;
+
+/// State of the pool shared with the workers
+struct Shared {
+    receiver: Mutex<Receiver<Job>>,
+    finished: Mutex<Vec<String>>,
+    /// time the oldest queued job of an id was submitted
+    submitted: Mutex<HashMap<String, Instant>>,
+    /// receives finished ids instead of `finished` once set
+    callback: Mutex<Option<Callback>>,
+}
+
+/// Fixed number of worker threads, running render jobs off the editor thread
+///
+/// Compiling with latex and converting with dvisvgm blocks for a long time. Jobs are queued here
+/// and the ids of finished ones collected, so that the editor can poll them and redraw, or passed
+/// to a callback right away.
+pub struct JobQueue {
+    sender: Mutex<Sender<Job>>,
+    shared: Arc<Shared>,
+}
+
+impl JobQueue {
+    pub fn new(workers: usize) -> JobQueue {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let shared = Arc::new(Shared {
+            receiver: Mutex::new(receiver),
+            finished: Mutex::new(Vec::new()),
+            submitted: Mutex::new(HashMap::new()),
+            callback: Mutex::new(None),
+        });
+
+        for _ in 0..workers.max(1) {
+            let shared = shared.clone();
+            thread::spawn(move || worker(&shared));
+        }
+
+        JobQueue { sender: Mutex::new(sender), shared }
+    }
+
+    /// Queue a job, `id` is reported by `take_finished` or the callback once it ran
+    pub fn submit<F: FnOnce() + Send + 'static>(&self, id: &str, task: F) {
+        let job = Job { id: id.to_string(), task: Box::new(task) };
+        self.shared.submitted.lock().unwrap().entry(id.to_string()).or_insert_with(Instant::now);
+
+        // the workers live as long as the process, sending never fails
+        let _ = self.sender.lock().unwrap().send(job);
+    }
+
+    /// Whether no job is queued or running
+    pub fn is_idle(&self) -> bool {
+        self.shared.submitted.lock().unwrap().is_empty()
+    }
+
+    /// Ids of jobs finished since the last call
+    pub fn take_finished(&self) -> Vec<String> {
+        std::mem::take(&mut *self.shared.finished.lock().unwrap())
+    }
+
+    /// Pass the id of every job to `callback` once it ran, from the worker thread
+    ///
+    /// The ids are no longer collected for `take_finished` then, ids collected so far are passed
+    /// right away.
+    pub fn on_finished<F: Fn(&str) + Send + 'static>(&self, callback: F) {
+        let mut slot = self.shared.callback.lock().unwrap();
+        for id in self.take_finished() {
+            callback(&id);
+        }
+
+        *slot = Some(Box::new(callback));
+    }
+
+    /// Time since a job of this id was queued, `None` if none is waiting or running
+    pub fn elapsed(&self, id: &str) -> Option<Duration> {
+        self.shared.submitted.lock().unwrap().get(id).map(|x| x.elapsed())
+    }
+}
+
+fn worker(shared: &Shared) {
+    loop {
+        // release the lock before running, so that other workers can pick up jobs
+        let job = match shared.receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        // tasks report their own panics with `catch`, this only keeps the worker alive
+        let _ = panic::catch_unwind(AssertUnwindSafe(job.task));
+
+        shared.submitted.lock().unwrap().remove(&job.id);
+        match &*shared.callback.lock().unwrap() {
+            Some(callback) => callback(&job.id),
+            None => shared.finished.lock().unwrap().push(job.id),
+        }
+    }
+}
+
+/// Run a task, turning a panic into an error for whoever waits for its result
+///
+/// This relies on the unwinding release profile of Cargo.toml, with `panic = "abort"` a panic
+/// ends the process anyway.
+pub fn catch<T, F: FnOnce() -> Result<T>>(task: F) -> Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(task)).unwrap_or_else(|err| Err(Error::Panicked(panic_message(&*err))))
+}
+
+fn panic_message(err: &(dyn Any + Send)) -> String {
+    err.downcast_ref::<&str>().map(|x| x.to_string())
+        .or_else(|| err.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown cause".to_string())
+}
+
+/// Pool shared by all renders, sized to the available cores
+pub fn queue() -> &'static JobQueue {
+    static QUEUE: OnceLock<JobQueue> = OnceLock::new();
+
+    QUEUE.get_or_init(|| {
+        let workers = thread::available_parallelism().map(|x| x.get()).unwrap_or(4);
+
+        JobQueue::new(workers)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn panics_are_reported() {
+        let res = catch::<(), _>(|| panic!("broken {}", 1));
+
+        assert_eq!(res.unwrap_err().to_string(), "renderer panicked: broken 1");
+    }
+
+    #[test]
+    fn panicking_jobs_finish() {
+        let queue = JobQueue::new(1);
+        let (sender, receiver) = mpsc::channel();
+        queue.on_finished(move |id| sender.send(id.to_string()).unwrap());
+
+        queue.submit("a", || panic!("broken"));
+        queue.submit("b", || {});
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), "a");
+        assert_eq!(receiver.recv_timeout(timeout).unwrap(), "b");
+        assert_eq!(queue.elapsed("a"), None);
+    }
+
+    #[test]
+    fn idle_once_jobs_finished() {
+        let queue = JobQueue::new(1);
+        let (sender, receiver) = mpsc::channel::<()>();
+        assert!(queue.is_idle());
+
+        queue.submit("a", move || receiver.recv().unwrap());
+        assert!(!queue.is_idle());
+
+        sender.send(()).unwrap();
+        let start = Instant::now();
+        while queue.take_finished().is_empty() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(queue.is_idle());
+    }
+}
@@ -4,12 +4,39 @@ use std::os::raw::c_char;
 use std::sync::Once;
 use std::cell::RefCell;
 use std::mem::MaybeUninit;
+use std::ptr::{addr_of, addr_of_mut};
 
+use miniserde::json;
+
+#[macro_use]
+mod exports;
 mod error;
+mod animation;
+mod ansi;
+mod batch;
+mod cache;
+mod config;
+mod svg;
+mod gc;
 mod utils;
+mod jobs;
+mod markdown;
+mod msgpack;
 mod render;
+mod protocol;
+mod rpc;
+mod runner;
+mod script;
+mod stats;
+mod termcap;
+mod tmux;
 mod content;
+mod converter;
+mod document;
+mod extract;
 mod node_view;
+#[cfg(feature = "plotters")]
+mod plot;
 
 use error::Result;
 
@@ -29,26 +56,41 @@ fn singleton() -> &'static SingletonReader {
                 inner: RefCell::new(render::Render::new()),
             };
             // Store it to the static var, i.e. initialize it
-            SINGLETON.write(singleton);
+            (*addr_of_mut!(SINGLETON)).write(singleton);
         });
 
         // Now we give out a shared reference to the data, which is safe to use
         // concurrently.
-        SINGLETON.assume_init_ref()
+        (*addr_of!(SINGLETON)).assume_init_ref()
     }
 }
 
+/// Drive the renderer over msgpack-rpc on the standard input and output, for Neovim
+pub fn serve_nvim() -> std::io::Result<()> {
+    rpc::serve()
+}
+
+/// Serve the versioned JSON line protocol on the standard input and output, for other editors
+pub fn serve_json() -> std::io::Result<()> {
+    protocol::serve()
+}
+
 pub fn result_to_cstring<T: ToString>(res: Result<T>) -> CString {
     let inner = match res {
         Ok(inn) => format!("{{ \"ok\": {} }}", inn.to_string()),
-        Err(err) => format!("{{ \"err\": \"{}\" }}", err.to_string()),
+        Err(err) => format!("{{ \"err\": {} }}", json::to_string(&err.to_string())),
     };
 
-    CString::new(inner).unwrap()
+    // a NUL would cut the reply short, in JSON strings it can be written as escape instead
+    CString::new(inner.replace('\0', "\\u0000")).unwrap_or_default()
 }
 
 macro_rules! export_fn {
     ($fn_name:ident,String)=> {
+        /// # Safety
+        ///
+        /// `input` has to point to a NUL terminated UTF-8 string, as passed by libcallex, and calls
+        /// must not overlap, the renderer is shared by all of them.
         #[no_mangle]
         pub unsafe extern "C" fn $fn_name(input: *const c_char) -> *const c_char {
             let input = CStr::from_ptr(input);
@@ -61,6 +103,10 @@ macro_rules! export_fn {
         }
     };
     ($fn_name:ident,usize) => {
+        /// # Safety
+        ///
+        /// `input` has to point to a NUL terminated UTF-8 string, as passed by libcallex, and calls
+        /// must not overlap, the renderer is shared by all of them.
         #[no_mangle]
         pub unsafe extern "C" fn $fn_name(input: *const c_char) -> usize {
             let input = CStr::from_ptr(input);
@@ -70,18 +116,42 @@ macro_rules! export_fn {
         }
     };
     ($fn_name:ident,()) => {
+        /// # Safety
+        ///
+        /// `input` has to point to a NUL terminated UTF-8 string, as passed by libcallex, and calls
+        /// must not overlap, the renderer is shared by all of them.
         #[no_mangle]
-        pub unsafe extern "C" fn $fn_name(input: *const c_char) {
+        pub unsafe extern "C" fn $fn_name(input: *const c_char) -> *const c_char {
             let input = CStr::from_ptr(input);
             let in_str = input.to_str().unwrap();
         
-            singleton().inner.borrow_mut().$fn_name(in_str).unwrap();
+            // these return nothing, reply with a JSON null
+            let res = singleton().inner.borrow_mut().$fn_name(in_str).map(|_| "null");
+            let res_str = result_to_cstring(res);
+
+            res_str.into_raw()
         }
     }
 }
 
-export_fn!(update_content, String);
-export_fn!(update_metadata, ());
-export_fn!(clear_all, ());
-export_fn!(draw, String);
-export_fn!(set_folds, ());
+macro_rules! export_all {
+    ($($fn_name:ident: $ret:tt),*) => {
+        $(export_fn!($fn_name, $ret);)*
+    };
+}
+
+for_each_export!(export_all);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn errors_are_escaped() {
+        let res = result_to_cstring::<usize>(Err(Error::UnknownFence("a\"b\\c\nd\0".to_string())));
+        let res = res.into_string().unwrap();
+
+        assert_eq!(res, r#"{ "err": "unknown fence with name a\"b\\c\nd\u0000" }"#);
+    }
+}
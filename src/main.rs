@@ -0,0 +1,20 @@
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let res = match std::env::args().nth(1).as_deref() {
+        Some("--nvim") => vim_graphical_preview::serve_nvim(),
+        Some("--json") => vim_graphical_preview::serve_json(),
+        _ => {
+            eprintln!("usage: vim-graphical-preview --nvim | --json");
+            return ExitCode::FAILURE;
+        },
+    };
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        },
+    }
+}
@@ -0,0 +1,178 @@
+/// Inline math `$...$` or `\(...\)` found in markdown or org prose
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InlineMath {
+    /// line of the math, starting at one
+    pub line: usize,
+    /// column of the opening delimiter in characters, starting at zero
+    pub column: usize,
+    /// byte offsets of the delimiters in the line, the closing one exclusive
+    pub start: usize,
+    pub end: usize,
+    pub content: String,
+}
+
+/// Find inline math delimited by single dollars or `\(` and `\)`
+///
+/// Dollars in prose, like currency amounts, must not swallow the rest of the document. The scanner
+/// therefore follows the rules of pandoc: the opening `$` has to be followed by a non-space, the
+/// closing `$` has to be on the same line, preceded by a non-space and not followed by a digit.
+/// Only the first unescaped dollar after an opening one is a candidate for closing it, so that
+/// "from $5 to $x$" yields `x` only. Escaped `\$` is a literal dollar everywhere, it neither opens
+/// nor closes math, while `\\$` is an escaped backslash followed by a delimiter. Inline code and
+/// fenced blocks are skipped, as are org blocks like `#+BEGIN_SRC` ... `#+END_SRC`. `\(...\)`, the
+/// latex fragment of org, has no such ambiguity, it only has to close on the same line.
+pub fn inline_math(text: &str) -> Vec<InlineMath> {
+    let mut res = Vec::new();
+    let mut in_fence = false;
+
+    for (nr, line) in text.lines().enumerate() {
+        let start = line.trim_start().to_lowercase();
+        if start.starts_with("```") || start.starts_with("#+begin_") || start.starts_with("#+end_") {
+            in_fence = !in_fence;
+            continue;
+        }
+        if in_fence {
+            continue;
+        }
+
+        let chars = line.char_indices().collect::<Vec<_>>();
+        let at = |idx: usize| chars.get(idx).map(|x| x.1);
+
+        let mut idx = 0;
+        while idx < chars.len() {
+            match chars[idx].1 {
+                // latex style delimiters, the content may contain dollars
+                '\\' if at(idx + 1) == Some('(') => {
+                    match closing_paren(&chars, idx + 2) {
+                        Some(end) => {
+                            res.push(InlineMath {
+                                line: nr + 1,
+                                column: idx,
+                                start: chars[idx].0,
+                                end: chars[end + 1].0 + 1,
+                                content: line[chars[idx + 1].0 + 1..chars[end].0].to_string(),
+                            });
+                            idx = end + 2;
+                        },
+                        None => idx += 2,
+                    }
+                },
+                // escaped character, for example a literal dollar
+                '\\' => idx += 2,
+                // inline code ends at the next backtick
+                '`' => {
+                    idx = (idx + 1..chars.len())
+                        .find(|x| chars[*x].1 == '`')
+                        .map_or(chars.len(), |x| x + 1);
+                },
+                // display math is handled by the block parser
+                '$' if at(idx + 1) == Some('$') => idx += 2,
+                '$' if at(idx + 1).is_none_or(|x| x.is_whitespace()) => idx += 1,
+                '$' => {
+                    match closing_dollar(&chars, idx + 1) {
+                        Some(end) => {
+                            res.push(InlineMath {
+                                line: nr + 1,
+                                column: idx,
+                                start: chars[idx].0,
+                                end: chars[end].0 + 1,
+                                content: line[chars[idx].0 + 1..chars[end].0].to_string(),
+                            });
+                            idx = end + 1;
+                        },
+                        None => idx += 1,
+                    }
+                },
+                _ => idx += 1,
+            }
+        }
+    }
+
+    res
+}
+
+/// Index of the dollar closing math which starts at `start`, if valid
+fn closing_dollar(chars: &[(usize, char)], start: usize) -> Option<usize> {
+    let mut idx = start;
+
+    while idx < chars.len() {
+        match chars[idx].1 {
+            '\\' => idx += 2,
+            '$' => {
+                let after_space = chars[idx - 1].1.is_whitespace();
+                let before_digit = chars.get(idx + 1).is_some_and(|x| x.1.is_ascii_digit());
+
+                return (!after_space && !before_digit).then_some(idx);
+            },
+            _ => idx += 1,
+        }
+    }
+
+    None
+}
+
+/// Index of the backslash of `\)` closing math which starts at `start`, if any
+fn closing_paren(chars: &[(usize, char)], start: usize) -> Option<usize> {
+    let mut idx = start;
+
+    while idx + 1 < chars.len() {
+        match (chars[idx].1, chars[idx + 1].1) {
+            ('\\', ')') => return Some(idx),
+            ('\\', _) => idx += 2,
+            _ => idx += 1,
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contents(text: &str) -> Vec<String> {
+        inline_math(text).into_iter().map(|x| x.content).collect()
+    }
+
+    #[test]
+    fn math_in_prose() {
+        let math = inline_math("where $x^2$ and \\(y\\) hold");
+
+        assert_eq!(math, vec![
+            InlineMath { line: 1, column: 6, start: 6, end: 11, content: "x^2".to_string() },
+            InlineMath { line: 1, column: 16, start: 16, end: 21, content: "y".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn currency_is_no_math() {
+        assert!(contents("it costs $5 and $10").is_empty());
+        assert!(contents("between $5 and $ 10").is_empty());
+        assert!(contents("$5 to $10, or $20").is_empty());
+        assert_eq!(contents("from $5 to $x$"), vec!["x"]);
+        assert_eq!(contents("pay $5, then $y$ more"), vec!["y"]);
+    }
+
+    #[test]
+    fn math_closes_on_its_line() {
+        assert!(contents("some $x\nand y$ later").is_empty());
+        assert!(contents("some \\(x\nand y\\) later").is_empty());
+        assert!(contents("$x $ and $x$5").is_empty());
+    }
+
+    #[test]
+    fn escaped_dollars() {
+        assert!(contents("costs \\$5 and \\$x\\$").is_empty());
+        assert!(contents("\\$x$ is no math").is_empty());
+        assert_eq!(contents("$a \\$ b$"), vec!["a \\$ b"]);
+        assert_eq!(contents("\\\\$x$"), vec!["x"]);
+        assert_eq!(contents("\\(a \\$ b\\)"), vec!["a \\$ b"]);
+    }
+
+    #[test]
+    fn code_is_skipped() {
+        assert!(contents("`$x$` and\n```\n$y$\n```").is_empty());
+        assert!(contents("#+BEGIN_SRC sh\necho $x$\n#+END_SRC").is_empty());
+        assert_eq!(contents("$$x$$ and $y$"), vec!["y"]);
+    }
+}
@@ -0,0 +1,295 @@
+use std::io::{self, Read, Write};
+
+/// Subset of MessagePack values exchanged with Neovim
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bin(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    /// extension types, Neovim uses them for buffer and window handles
+    Ext(i8, Vec<u8>),
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(x) => Some(x),
+            // some clients send strings as binary
+            Value::Bin(x) => std::str::from_utf8(x).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(x) => Some(*x),
+            _ => None,
+        }
+    }
+}
+
+/// Append the encoding of a value
+pub fn encode(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Nil => out.push(0xc0),
+        Value::Bool(x) => out.push(if *x { 0xc3 } else { 0xc2 }),
+        Value::Int(x) => encode_int(*x, out),
+        Value::Float(x) => {
+            out.push(0xcb);
+            out.extend_from_slice(&x.to_be_bytes());
+        },
+        Value::Str(x) => {
+            match x.len() {
+                len @ 0..=31 => out.push(0xa0 | len as u8),
+                len @ 32..=0xff => out.extend_from_slice(&[0xd9, len as u8]),
+                len @ 0x100..=0xffff => {
+                    out.push(0xda);
+                    out.extend_from_slice(&(len as u16).to_be_bytes());
+                },
+                len => {
+                    out.push(0xdb);
+                    out.extend_from_slice(&(len as u32).to_be_bytes());
+                },
+            }
+            out.extend_from_slice(x.as_bytes());
+        },
+        Value::Bin(x) => {
+            match x.len() {
+                len @ 0..=0xff => out.extend_from_slice(&[0xc4, len as u8]),
+                len @ 0x100..=0xffff => {
+                    out.push(0xc5);
+                    out.extend_from_slice(&(len as u16).to_be_bytes());
+                },
+                len => {
+                    out.push(0xc6);
+                    out.extend_from_slice(&(len as u32).to_be_bytes());
+                },
+            }
+            out.extend_from_slice(x);
+        },
+        Value::Array(items) => {
+            encode_len(items.len(), 0x90, 0xdc, out);
+            for item in items {
+                encode(item, out);
+            }
+        },
+        Value::Map(items) => {
+            encode_len(items.len(), 0x80, 0xde, out);
+            for (key, value) in items {
+                encode(key, out);
+                encode(value, out);
+            }
+        },
+        Value::Ext(kind, data) => {
+            match data.len() {
+                1 => out.push(0xd4),
+                2 => out.push(0xd5),
+                4 => out.push(0xd6),
+                8 => out.push(0xd7),
+                16 => out.push(0xd8),
+                len @ 0..=0xff => out.extend_from_slice(&[0xc7, len as u8]),
+                len @ 0x100..=0xffff => {
+                    out.push(0xc8);
+                    out.extend_from_slice(&(len as u16).to_be_bytes());
+                },
+                len => {
+                    out.push(0xc9);
+                    out.extend_from_slice(&(len as u32).to_be_bytes());
+                },
+            }
+            out.push(*kind as u8);
+            out.extend_from_slice(data);
+        },
+    }
+}
+
+fn encode_int(x: i64, out: &mut Vec<u8>) {
+    match x {
+        0..=0x7f => out.push(x as u8),
+        -32..=-1 => out.push(x as i8 as u8),
+        0x80..=0xffff_ffff => {
+            out.push(0xce);
+            out.extend_from_slice(&(x as u32).to_be_bytes());
+        },
+        _ => {
+            out.push(0xd3);
+            out.extend_from_slice(&x.to_be_bytes());
+        },
+    }
+}
+
+/// Length prefix of arrays and maps, `fix` for up to 15 elements, otherwise the 16 bit marker
+/// followed by the 32 bit one
+fn encode_len(len: usize, fix: u8, marker: u8, out: &mut Vec<u8>) {
+    if len < 16 {
+        out.push(fix | len as u8);
+    } else if len <= 0xffff {
+        out.push(marker);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(marker + 1);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+/// Encode a value and write it in one piece
+pub fn write<W: Write>(writer: &mut W, value: &Value) -> io::Result<()> {
+    let mut buf = Vec::new();
+    encode(value, &mut buf);
+
+    writer.write_all(&buf)?;
+    writer.flush()
+}
+
+/// Read the next value, blocking until it is complete
+pub fn read<R: Read>(reader: &mut R) -> io::Result<Value> {
+    let marker = read_bytes::<1, _>(reader)?[0];
+
+    Ok(match marker {
+        0x00..=0x7f => Value::Int(marker as i64),
+        0x80..=0x8f => read_map(reader, (marker & 0x0f) as usize)?,
+        0x90..=0x9f => read_array(reader, (marker & 0x0f) as usize)?,
+        0xa0..=0xbf => read_str(reader, (marker & 0x1f) as usize)?,
+        0xc0 => Value::Nil,
+        0xc2 => Value::Bool(false),
+        0xc3 => Value::Bool(true),
+        0xc4 => { let len = read_uint(reader, 1)?; Value::Bin(read_vec(reader, len)?) },
+        0xc5 => { let len = read_uint(reader, 2)?; Value::Bin(read_vec(reader, len)?) },
+        0xc6 => { let len = read_uint(reader, 4)?; Value::Bin(read_vec(reader, len)?) },
+        0xc7 => { let len = read_uint(reader, 1)?; read_ext(reader, len)? },
+        0xc8 => { let len = read_uint(reader, 2)?; read_ext(reader, len)? },
+        0xc9 => { let len = read_uint(reader, 4)?; read_ext(reader, len)? },
+        0xca => Value::Float(f32::from_be_bytes(read_bytes(reader)?) as f64),
+        0xcb => Value::Float(f64::from_be_bytes(read_bytes(reader)?)),
+        0xcc => Value::Int(read_uint(reader, 1)? as i64),
+        0xcd => Value::Int(read_uint(reader, 2)? as i64),
+        0xce => Value::Int(read_uint(reader, 4)? as i64),
+        0xcf => Value::Int(u64::from_be_bytes(read_bytes(reader)?) as i64),
+        0xd0 => Value::Int(i8::from_be_bytes(read_bytes(reader)?) as i64),
+        0xd1 => Value::Int(i16::from_be_bytes(read_bytes(reader)?) as i64),
+        0xd2 => Value::Int(i32::from_be_bytes(read_bytes(reader)?) as i64),
+        0xd3 => Value::Int(i64::from_be_bytes(read_bytes(reader)?)),
+        0xd4 => read_ext(reader, 1)?,
+        0xd5 => read_ext(reader, 2)?,
+        0xd6 => read_ext(reader, 4)?,
+        0xd7 => read_ext(reader, 8)?,
+        0xd8 => read_ext(reader, 16)?,
+        0xd9 => { let len = read_uint(reader, 1)?; read_str(reader, len)? },
+        0xda => { let len = read_uint(reader, 2)?; read_str(reader, len)? },
+        0xdb => { let len = read_uint(reader, 4)?; read_str(reader, len)? },
+        0xdc => { let len = read_uint(reader, 2)?; read_array(reader, len)? },
+        0xdd => { let len = read_uint(reader, 4)?; read_array(reader, len)? },
+        0xde => { let len = read_uint(reader, 2)?; read_map(reader, len)? },
+        0xdf => { let len = read_uint(reader, 4)?; read_map(reader, len)? },
+        0xe0..=0xff => Value::Int(marker as i8 as i64),
+        0xc1 => return Err(io::Error::new(io::ErrorKind::InvalidData, "reserved MessagePack marker 0xc1")),
+    })
+}
+
+fn read_bytes<const N: usize, R: Read>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
+
+/// Big endian unsigned integer of `width` bytes
+fn read_uint<R: Read>(reader: &mut R, width: usize) -> io::Result<usize> {
+    Ok(read_vec(reader, width)?.iter().fold(0, |acc, x| acc << 8 | *x as usize))
+}
+
+fn read_vec<R: Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
+
+fn read_str<R: Read>(reader: &mut R, len: usize) -> io::Result<Value> {
+    let buf = read_vec(reader, len)?;
+
+    Ok(Value::Str(String::from_utf8_lossy(&buf).to_string()))
+}
+
+fn read_ext<R: Read>(reader: &mut R, len: usize) -> io::Result<Value> {
+    let kind = read_bytes::<1, _>(reader)?[0] as i8;
+
+    Ok(Value::Ext(kind, read_vec(reader, len)?))
+}
+
+fn read_array<R: Read>(reader: &mut R, len: usize) -> io::Result<Value> {
+    (0..len).map(|_| read(reader)).collect::<io::Result<_>>().map(Value::Array)
+}
+
+fn read_map<R: Read>(reader: &mut R, len: usize) -> io::Result<Value> {
+    (0..len).map(|_| Ok((read(reader)?, read(reader)?)))
+        .collect::<io::Result<_>>()
+        .map(Value::Map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: Value) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode(&value, &mut buf);
+        assert_eq!(read(&mut buf.as_slice()).unwrap(), value, "encoded as {:x?}", buf);
+
+        buf
+    }
+
+    fn bytes(len: usize) -> Vec<u8> {
+        (0..len).map(|x| x as u8).collect()
+    }
+
+    #[test]
+    fn scalars() {
+        assert_eq!(round_trip(Value::Nil), [0xc0]);
+        assert_eq!(round_trip(Value::Bool(true)), [0xc3]);
+        assert_eq!(round_trip(Value::Bool(false)), [0xc2]);
+        assert_eq!(round_trip(Value::Float(-1.5))[0], 0xcb);
+
+        for x in [0, 1, 0x7f, 0x80, 0xffff, 0xffff_ffff, 0x1_0000_0000, i64::MAX, -1, -32, -33, i64::MIN] {
+            round_trip(Value::Int(x));
+        }
+        assert_eq!(round_trip(Value::Int(5)), [0x05]);
+        assert_eq!(round_trip(Value::Int(-1)), [0xff]);
+    }
+
+    #[test]
+    fn strings_and_binaries() {
+        for (len, marker) in [(0, 0xa0), (31, 0xbf), (32, 0xd9), (0xff, 0xd9), (0x100, 0xda), (0x10000, 0xdb)] {
+            assert_eq!(round_trip(Value::Str("x".repeat(len)))[0], marker);
+        }
+        for (len, marker) in [(0, 0xc4), (0xff, 0xc4), (0x100, 0xc5), (0x10000, 0xc6)] {
+            assert_eq!(round_trip(Value::Bin(bytes(len)))[0], marker);
+        }
+    }
+
+    #[test]
+    fn containers() {
+        for (len, marker) in [(0, 0x90), (15, 0x9f), (16, 0xdc), (0x10000, 0xdd)] {
+            assert_eq!(round_trip(Value::Array(vec![Value::Nil; len]))[0], marker);
+        }
+        for (len, marker) in [(0, 0x80), (15, 0x8f), (16, 0xde), (0x10000, 0xdf)] {
+            let map = (0..len as i64).map(|x| (Value::Int(x), Value::Str(x.to_string()))).collect();
+            assert_eq!(round_trip(Value::Map(map))[0], marker);
+        }
+
+        round_trip(Value::Array(vec![Value::Map(vec![(Value::Str("a".to_string()), Value::Ext(1, bytes(3)))])]));
+    }
+
+    #[test]
+    fn extensions() {
+        let markers = [(1, 0xd4), (2, 0xd5), (4, 0xd6), (8, 0xd7), (16, 0xd8), (0, 0xc7), (3, 0xc7), (0xff, 0xc7), (0x100, 0xc8), (0x10000, 0xc9)];
+        for (len, marker) in markers {
+            assert_eq!(round_trip(Value::Ext(-2, bytes(len)))[0], marker);
+        }
+    }
+}
@@ -12,7 +12,7 @@ pub enum NodeView {
 impl NodeView {
     pub fn new(node: &Node, metadata: &Metadata, offset: isize) -> NodeView {
         let start;
-        let mut height = node.range.1 - node.range.0 + 1;
+        let mut height = node.rows(metadata) + 1;
 
         if offset <= -(height as isize) {
             // if we are above the upper line, just skip
@@ -26,8 +26,6 @@ impl NodeView {
 
         let distance_lower = metadata.viewport.0 as isize - offset;
 
-        //dbg!(&offset, &height, &distance_lower);
-
         if distance_lower <= 0 {
             return NodeView::Hidden;
         } else if (distance_lower as usize) < height {
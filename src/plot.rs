@@ -0,0 +1,197 @@
+use std::path::{Path, PathBuf};
+
+use miniserde::{json, Deserialize};
+use plotters::prelude::*;
+
+use crate::config::Config;
+use crate::content::ContentType;
+use crate::error::{Error, Result};
+use crate::render::art_path;
+use crate::utils;
+
+/// Size of the plot in pixels if the spec doesn't give one
+const DEFAULT_SIZE: (u32, u32) = (640, 400);
+
+/// Plot of a `plot` fence, like `{"x_label": "t", "csv": "t,a\n0,1\n1,2"}`
+#[derive(Deserialize, Default)]
+struct Spec {
+    title: Option<String>,
+    x_label: Option<String>,
+    y_label: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    /// `[min, max]` of the axes, fitted to the data if left out
+    x_range: Option<Vec<f64>>,
+    y_range: Option<Vec<f64>>,
+    /// columns with a header line, the first one is x and every other one a series
+    csv: Option<String>,
+    series: Option<Vec<Series>>,
+}
+
+#[derive(Deserialize)]
+struct Series {
+    label: Option<String>,
+    /// `line` or `points`
+    style: Option<String>,
+    /// pairs of x and y
+    points: Vec<Vec<f64>>,
+}
+
+/// Series ready for drawing
+struct Curve {
+    label: Option<String>,
+    /// drawn as dots instead of a line
+    points: bool,
+    data: Vec<(f64, f64)>,
+}
+
+impl Spec {
+    /// Parse a JSON spec, any other content is taken as CSV
+    fn parse(content: &str) -> Result<Spec> {
+        if !content.trim_start().starts_with('{') {
+            return Ok(Spec { csv: Some(content.to_string()), ..Default::default() });
+        }
+
+        json::from_str(content).map_err(|_| Error::InvalidPlot("malformed JSON spec".to_string()))
+    }
+
+    /// Series of the spec followed by the columns of the CSV data
+    fn curves(&self) -> Result<Vec<Curve>> {
+        let mut all = Vec::new();
+
+        for series in self.series.iter().flatten() {
+            let data = series.points.iter()
+                .map(|x| match x[..] {
+                    [x, y] => Ok((x, y)),
+                    _ => Err(Error::InvalidPlot("points have to be pairs of x and y".to_string())),
+                })
+                .collect::<Result<_>>()?;
+
+            all.push(Curve { label: series.label.clone(), points: series.style.as_deref() == Some("points"), data });
+        }
+
+        if let Some(csv) = &self.csv {
+            all.extend(parse_csv(csv)?);
+        }
+
+        Ok(all)
+    }
+}
+
+/// Columns of CSV data as series over the first one, named by the header if there is one
+fn parse_csv(csv: &str) -> Result<Vec<Curve>> {
+    let mut lines = csv.lines().filter(|x| !x.trim().is_empty()).peekable();
+    let split = |line: &str| line.split(',').map(|x| x.trim().to_string()).collect::<Vec<_>>();
+
+    // a header is a first line which isn't numeric
+    let header = match lines.peek() {
+        Some(line) if split(line).iter().any(|x| x.parse::<f64>().is_err()) => lines.next().map(split),
+        _ => None,
+    };
+
+    let mut columns = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let values = split(line).iter()
+            .map(|x| x.parse::<f64>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| Error::InvalidPlot(format!("row {} is not numeric", i + 1)))?;
+
+        for (j, y) in values.iter().enumerate().skip(1) {
+            if columns.len() < j {
+                let label = header.as_ref().and_then(|x| x.get(j).cloned());
+                columns.push(Curve { label, points: false, data: Vec::new() });
+            }
+            columns[j - 1].data.push((values[0], *y));
+        }
+    }
+
+    Ok(columns)
+}
+
+/// Smallest and largest value, widened by a twentieth so that points don't sit on the border
+fn bounds(values: impl Iterator<Item = f64>, given: &Option<Vec<f64>>) -> Result<(f64, f64)> {
+    if let Some(range) = given {
+        return match range[..] {
+            [min, max] if min < max => Ok((min, max)),
+            _ => Err(Error::InvalidPlot("ranges have to be [min, max]".to_string())),
+        };
+    }
+
+    let (min, max) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), x| (min.min(x), max.max(x)));
+    if min > max {
+        return Err(Error::InvalidPlot("no data to plot".to_string()));
+    }
+
+    let pad = if max > min { (max - min) / 20.0 } else { 1.0 };
+    Ok((min - pad, max + pad))
+}
+
+fn draw(spec: &Spec, path: &Path) -> Result<()> {
+    let curves = spec.curves()?;
+    let x = bounds(curves.iter().flat_map(|x| x.data.iter().map(|p| p.0)), &spec.x_range)?;
+    let y = bounds(curves.iter().flat_map(|x| x.data.iter().map(|p| p.1)), &spec.y_range)?;
+
+    let size = (spec.width.unwrap_or(DEFAULT_SIZE.0), spec.height.unwrap_or(DEFAULT_SIZE.1));
+    let root = SVGBackend::new(path, size).into_drawing_area();
+    let failed = |err: DrawingAreaErrorKind<_>| Error::InvalidPlot(err.to_string());
+
+    let mut builder = ChartBuilder::on(&root);
+    builder.margin(10).x_label_area_size(40).y_label_area_size(50);
+    if let Some(title) = &spec.title {
+        builder.caption(title, ("sans-serif", 20));
+    }
+
+    let mut chart = builder.build_cartesian_2d(x.0..x.1, y.0..y.1).map_err(failed)?;
+
+    let mut mesh = chart.configure_mesh();
+    mesh.disable_mesh();
+    if let Some(label) = &spec.x_label {
+        mesh.x_desc(label);
+    }
+    if let Some(label) = &spec.y_label {
+        mesh.y_desc(label);
+    }
+    mesh.draw().map_err(failed)?;
+
+    for (i, curve) in curves.iter().enumerate() {
+        let color = Palette99::pick(i).to_rgba();
+
+        let drawn = if curve.points {
+            chart.draw_series(curve.data.iter().map(|x| Circle::new(*x, 3, color.filled())))
+        } else {
+            chart.draw_series(LineSeries::new(curve.data.iter().copied(), color.stroke_width(2)))
+        }.map_err(failed)?;
+
+        if let Some(label) = &curve.label {
+            drawn.label(label)
+                .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color.stroke_width(2)));
+        }
+    }
+
+    if curves.iter().any(|x| x.label.is_some()) {
+        chart.configure_series_labels()
+            .border_style(BLACK)
+            .draw()
+            .map_err(failed)?;
+    }
+
+    root.present().map_err(failed)
+}
+
+/// Draw a plot spec with plotters to a SVG file
+pub fn parse_plot(content: &str, config: &Config) -> Result<PathBuf> {
+    let path = art_path()
+        .join(config.key(&ContentType::Plot.tagged(content)))
+        .with_extension("svg");
+
+    if !path.exists() {
+        if let Err(err) = draw(&Spec::parse(content)?, &path) {
+            let _ = std::fs::remove_file(&path);
+            return Err(err);
+        }
+
+        utils::finish_svg(&path, config)?;
+    }
+
+    Ok(path)
+}
@@ -0,0 +1,210 @@
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use miniserde::{json, Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::content::{ContentType, RenderInfo};
+use crate::{jobs, runner};
+
+/// Version of the protocol, requests carrying a different one are rejected
+pub const VERSION: u32 = 1;
+
+/// One line of input, for example `{"cmd":"render","id":3,"kind":"latex","content":"..."}`
+#[derive(Deserialize)]
+struct Request {
+    /// version the frontend was written against, assumed current if left out
+    version: Option<u32>,
+    cmd: String,
+    /// chosen by the frontend and repeated in the response
+    id: u64,
+    /// fence name like `math` or `gnuplot`, or `file` for a path
+    kind: Option<String>,
+    content: Option<String>,
+    /// options of the `config` command, as in `g:graphical_preview_config`
+    options: Option<json::Value>,
+}
+
+/// One line of output, `type` is `version`, `ok`, `rendered` or `error`
+#[derive(Serialize)]
+struct Response {
+    version: u32,
+    id: u64,
+    #[serde(rename = "type")]
+    kind: String,
+    /// location and metrics of the image, for `rendered`
+    info: Option<RenderInfo>,
+    /// description of the problem, for `error`
+    message: Option<String>,
+}
+
+impl Response {
+    fn new(id: u64, kind: &str) -> Response {
+        Response { version: VERSION, id, kind: kind.to_string(), info: None, message: None }
+    }
+
+    fn rendered(id: u64, info: RenderInfo) -> Response {
+        Response { info: Some(info), ..Response::new(id, "rendered") }
+    }
+
+    fn error(id: u64, message: String) -> Response {
+        Response { message: Some(message), ..Response::new(id, "error") }
+    }
+}
+
+type Output = Arc<Mutex<dyn Write + Send>>;
+
+/// Number of renders not answered yet
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+fn respond(out: &Output, response: &Response) {
+    let mut out = out.lock().unwrap();
+
+    // the frontend is gone if this fails, the input closes soon after
+    let _ = writeln!(out, "{}", json::to_string(response));
+    let _ = out.flush();
+}
+
+/// Serve the JSON line protocol on the standard input and output until the input closes
+///
+/// This is independent of Vim, so that other editors can use the renderer. Renders run in the
+/// background and respond once done, possibly out of order; the `id` relates them to requests.
+pub fn serve() -> io::Result<()> {
+    let out: Output = Arc::new(Mutex::new(io::stdout()));
+    let mut config = Config::new();
+
+    // nobody polls for finished jobs, the responses are the notification
+    jobs::queue().on_finished(|_| {});
+
+    for line in io::stdin().lock().lines() {
+        answer(&line?, &mut config, &out);
+    }
+
+    // answer all renders before exiting
+    while PENDING.load(Ordering::SeqCst) > 0 {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Ok(())
+}
+
+/// Answer one line of input, renders respond once they are done
+fn answer(line: &str, config: &mut Config, out: &Output) {
+    if line.trim().is_empty() {
+        return;
+    }
+
+    match json::from_str::<Request>(line) {
+        Ok(request) => handle(request, config, out),
+        Err(_) => respond(out, &Response::error(0, format!("malformed request {}", line))),
+    }
+}
+
+fn handle(request: Request, config: &mut Config, out: &Output) {
+    let id = request.id;
+
+    if let Some(version) = request.version.filter(|x| *x != VERSION) {
+        return respond(out, &Response::error(id, format!("unsupported protocol version {}, expected {}", version, VERSION)));
+    }
+
+    match request.cmd.as_str() {
+        "version" => respond(out, &Response::new(id, "version")),
+        "config" => {
+            let options = request.options.map_or("{}".to_string(), |x| json::to_string(&x));
+
+            match config.update(&options) {
+                Ok(()) => respond(out, &Response::new(id, "ok")),
+                Err(err) => respond(out, &Response::error(id, err.to_string())),
+            }
+        },
+        "render" => {
+            let kind = match request.kind.as_deref().unwrap_or("math") {
+                "file" => Ok(ContentType::File),
+                kind => ContentType::from_fence(kind, config),
+            };
+            let kind = match kind {
+                Ok(kind) => kind,
+                Err(err) => return respond(out, &Response::error(id, err.to_string())),
+            };
+
+            let content = request.content.unwrap_or_default();
+            let (config, out) = (config.clone(), out.clone());
+            PENDING.fetch_add(1, Ordering::SeqCst);
+            jobs::queue().submit(&id.to_string(), move || {
+                // a panic is answered like any error, so that `PENDING` drops to zero in the end
+                match jobs::catch(|| kind.render(&content, &config)) {
+                    Ok(info) => respond(&out, &Response::rendered(id, info)),
+                    Err(err) => respond(&out, &Response::error(id, err.to_string())),
+                }
+                PENDING.fetch_sub(1, Ordering::SeqCst);
+            });
+        },
+        "cancel" => {
+            runner::cancel_running();
+            respond(out, &Response::new(id, "ok"));
+        },
+        cmd => respond(out, &Response::error(id, format!("unknown command {}", cmd))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// Lines written in answer to some input, renders are waited for
+    fn responses(input: &[&str]) -> Vec<String> {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let out: Output = buf.clone();
+        let mut config = Config::new();
+
+        for line in input {
+            answer(line, &mut config, &out);
+        }
+
+        let start = Instant::now();
+        while PENDING.load(Ordering::SeqCst) > 0 && start.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let buf = buf.lock().unwrap();
+        String::from_utf8_lossy(&buf).lines().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn requests_are_answered() {
+        assert_eq!(responses(&[
+            r#"{"cmd":"version","id":1}"#,
+            "",
+            r#"{"cmd":"config","id":2,"options":{"pdf_dpi":150}}"#,
+            r#"{"cmd":"cancel","id":3,"version":1}"#,
+        ]), [
+            r#"{"version":1,"id":1,"type":"version","info":null,"message":null}"#,
+            r#"{"version":1,"id":2,"type":"ok","info":null,"message":null}"#,
+            r#"{"version":1,"id":3,"type":"ok","info":null,"message":null}"#,
+        ]);
+    }
+
+    #[test]
+    fn errors_are_answered() {
+        let res = responses(&[
+            "{",
+            r#"{"cmd":"version","id":1,"version":2}"#,
+            r#"{"cmd":"draw","id":2}"#,
+            r#"{"cmd":"render","id":3,"kind":"nonexistent"}"#,
+            r#"{"cmd":"render","id":4,"kind":"file","content":"/nonexistent.png"}"#,
+        ]);
+
+        assert_eq!(res[..3], [
+            r#"{"version":1,"id":0,"type":"error","info":null,"message":"malformed request {"}"#,
+            r#"{"version":1,"id":1,"type":"error","info":null,"message":"unsupported protocol version 2, expected 1"}"#,
+            r#"{"version":1,"id":2,"type":"error","info":null,"message":"unknown command draw"}"#,
+        ]);
+        assert!(res[3].starts_with(r#"{"version":1,"id":3,"type":"error""#));
+        // renders answer in the background
+        assert!(res[4].starts_with(r#"{"version":1,"id":4,"type":"error""#), "{}", res[4]);
+    }
+}
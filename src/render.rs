@@ -1,40 +1,821 @@
 use std::io::{Write, Stdout};
-use std::collections::BTreeMap;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use std::fs::File;
-use std::os::unix::io::FromRawFd;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::env;
 use std::mem;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::result;
+use std::str::FromStr;
+#[cfg(unix)]
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Duration;
 
+use image::{imageops, Pixel, Rgba, RgbaImage};
+use magick_rust::{DrawingWand, MagickWand, PixelWand};
 use miniserde::{json, Serialize, Deserialize};
 
-use crate::error::Result;
+use crate::{ansi, batch, cache, document, jobs, runner, stats, svg, termcap, tmux};
+use crate::animation::Playback;
+use crate::config::{self, Config, PreviewMode};
+use crate::error::{Error, Result};
 use crate::utils;
 use crate::node_view::NodeView;
-use crate::content::{Content, Node, NodeDim};
+use crate::content::{Content, ContentType, Diagnostic, Memory, Node, NodeDim};
 
-pub const ART_PATH: &str = "/tmp/nvim_arts/";
+/// Cache directory used if the platform has no cache location
+const FALLBACK_ART_PATH: &str = "/tmp/nvim_arts/";
+
+/// Cache directory set by the configuration, the platform default if none
+static ART_PATH: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// Directory holding all rendered artifacts
+pub fn art_path() -> PathBuf {
+    ART_PATH.read().unwrap().clone().unwrap_or_else(default_art_path)
+}
+
+/// Switch to a cache directory of the test process, shared by all tests
+#[cfg(test)]
+pub fn test_art_path() -> PathBuf {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+    DIR.get_or_init(|| {
+        let dir = env::temp_dir().join(format!("vim-graphical-preview-test-{}", std::process::id()));
+        set_art_path(&dir.to_string_lossy()).unwrap();
+
+        dir
+    }).clone()
+}
+
+/// Platform cache location, honoring `XDG_CACHE_HOME`
+pub fn default_art_path() -> PathBuf {
+    let home = env::var_os("HOME").map(PathBuf::from);
+
+    let base = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ if cfg!(target_os = "macos") => home.map(|x| x.join("Library").join("Caches")),
+        _ if cfg!(windows) => env::var_os("LOCALAPPDATA").map(PathBuf::from),
+        _ => home.map(|x| x.join(".cache")),
+    };
+
+    base.map(|x| x.join("vim-graphical-preview"))
+        .unwrap_or_else(|| PathBuf::from(FALLBACK_ART_PATH))
+}
+
+/// Switch the cache directory, creating it if missing, an empty path selects the default
+pub fn set_art_path(path: &str) -> Result<()> {
+    let path = if path.is_empty() { default_art_path() } else { PathBuf::from(path) };
+    std::fs::create_dir_all(&path).map_err(Error::Io)?;
+
+    *ART_PATH.write().unwrap() = Some(path);
+
+    Ok(())
+}
 
 pub type CodeId = String;
+
+/// Terminal graphics protocol used to display images
+pub trait Backend: Send + Sync {
+    /// Escape sequences drawing the image at the cursor
+    fn encode(&self, image: &MagickWand) -> Result<Vec<u8>>;
+
+    /// Escape sequences showing an encoded image at a 1-based terminal position
+    fn place(&self, image: &[u8], row: usize, col: usize) -> Vec<u8> {
+        let mut buf = format!("\x1b[s\x1b[{};{}H", row, col).into_bytes();
+        buf.extend_from_slice(image);
+        buf.extend_from_slice(b"\x1b[u");
+
+        buf
+    }
+
+    /// Escape sequences removing all drawn images, empty if the terminal overwrites them anyway
+    fn clear(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// How animations are shown, see `encode_animation`
+    fn playback(&self) -> Playback {
+        Playback::Frames
+    }
+
+    /// Escape sequences playing an animation at the cursor, for backends with `Playback::Terminal`
+    ///
+    /// `id` is the same for every size of an animation, so that its previous upload is replaced.
+    fn encode_animation(&self, _id: u32, frames: &[(MagickWand, Duration)]) -> Result<Vec<u8>> {
+        self.encode(&frames[0].0)
+    }
+}
+
+/// Image as PNG, the format of most protocols
+fn png(image: &MagickWand) -> Result<Vec<u8>> {
+    image.write_image_blob("png").map_err(|_| Error::Magick("encode the image as PNG".to_string()))
+}
+
+/// SIXEL graphics, images are part of the text and erased by redraws
+pub struct Sixel;
+
+impl Backend for Sixel {
+    fn encode(&self, image: &MagickWand) -> Result<Vec<u8>> {
+        image.write_image_blob("sixel").map_err(|_| Error::Magick("encode the image as SIXEL".to_string()))
+    }
+}
+
+/// Kitty graphics protocol, images are kept on a separate layer until deleted
+pub struct Kitty;
+
+/// Maximal size of the base64 payload in a single escape code
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+/// PNG of an image split into escape codes, the keys of `header` go into the first one
+fn kitty_chunks(header: &str, image: &MagickWand) -> Result<Vec<u8>> {
+    let payload = utils::base64(&png(image)?);
+    let chunks = payload.as_bytes().chunks(KITTY_CHUNK_SIZE).collect::<Vec<_>>();
+
+    let mut buf = Vec::new();
+    for (idx, chunk) in chunks.iter().enumerate() {
+        let more = if idx + 1 < chunks.len() { 1 } else { 0 };
+        let header = if idx == 0 { header } else { "" };
+
+        buf.extend_from_slice(format!("\x1b_G{}m={};", header, more).as_bytes());
+        buf.extend_from_slice(chunk);
+        buf.extend_from_slice(b"\x1b\\");
+    }
+
+    Ok(buf)
+}
+
+impl Backend for Kitty {
+    fn encode(&self, image: &MagickWand) -> Result<Vec<u8>> {
+        // `q=2` suppresses responses, which would end up as input in the editor, and `C=1` keeps
+        // the cursor in place
+        kitty_chunks("a=T,f=100,q=2,C=1,", image)
+    }
+
+    fn clear(&self) -> Vec<u8> {
+        b"\x1b_Ga=d,d=A,q=2\x1b\\".to_vec()
+    }
+
+    fn playback(&self) -> Playback {
+        Playback::Terminal
+    }
+
+    /// The first frame is placed like an image, the others are added to it with the time until
+    /// the next one in `z`; `s=3` starts the animation and `v=1` loops it forever. An upload of the
+    /// animation at another size is deleted with its frames first.
+    fn encode_animation(&self, id: u32, frames: &[(MagickWand, Duration)]) -> Result<Vec<u8>> {
+        let mut buf = format!("\x1b_Ga=d,d=I,i={},q=2\x1b\\", id).into_bytes();
+        for (idx, (frame, delay)) in frames.iter().enumerate() {
+            let header = if idx == 0 {
+                format!("a=T,f=100,i={},q=2,C=1,", id)
+            } else {
+                format!("a=f,f=100,i={},z={},q=2,", id, delay.as_millis())
+            };
+            buf.extend(kitty_chunks(&header, frame)?);
+        }
+
+        buf.extend_from_slice(format!("\x1b_Ga=a,i={},r=1,z={},q=2\x1b\\", id, frames[0].1.as_millis()).as_bytes());
+        buf.extend_from_slice(format!("\x1b_Ga=a,i={},s=3,v=1,q=2\x1b\\", id).as_bytes());
+
+        Ok(buf)
+    }
+}
+
+/// Inline images of iTerm2, placed in the cells like text
+pub struct Iterm;
+
+impl Backend for Iterm {
+    fn encode(&self, image: &MagickWand) -> Result<Vec<u8>> {
+        let png = png(image)?;
+        let (width, height) = (image.get_image_width(), image.get_image_height());
+
+        // give the size in pixels, otherwise the image is scaled to cells
+        Ok(format!(
+            "\x1b]1337;File=inline=1;size={};width={}px;height={}px;preserveAspectRatio=0;doNotMoveCursor=1:{}\x07",
+            png.len(), width, height, utils::base64(&png)
+        ).into_bytes())
+    }
+}
+
+/// Colored half blocks, for terminals without any graphics protocol
+pub struct Ansi;
+
+impl Backend for Ansi {
+    fn encode(&self, image: &MagickWand) -> Result<Vec<u8>> {
+        let (width, height) = (image.get_image_width(), image.get_image_height());
+        let pixels = image.export_image_pixels(0, 0, width, height, "RGBA").unwrap_or_default();
+        let Some(image) = RgbaImage::from_raw(width as u32, height as u32, pixels) else {
+            return Err(Error::Magick("export the pixels of the image".to_string()));
+        };
+
+        // the image covers as many cells as it would with a graphics protocol
+        let cols = width.div_ceil(utils::char_pixel_width().max(1));
+        let rows = height.div_ceil(utils::char_pixel_height().max(1));
+
+        Ok(ansi::to_cells(&image, cols, rows).into_bytes())
+    }
+
+    /// Every line of blocks is moved to the column of the image, a newline would return to the
+    /// left edge of the terminal
+    fn place(&self, image: &[u8], row: usize, col: usize) -> Vec<u8> {
+        let mut buf = b"\x1b[s".to_vec();
+        for (idx, line) in image.split(|x| *x == b'\n').filter(|x| !x.is_empty()).enumerate() {
+            buf.extend_from_slice(format!("\x1b[{};{}H", row + idx, col).as_bytes());
+            buf.extend_from_slice(line);
+        }
+        buf.extend_from_slice(b"\x1b[u");
+
+        buf
+    }
+}
+
+/// Image stored for ueberzugpp, in terminal cells
+#[derive(Serialize, Deserialize)]
+struct UeberzugImage {
+    path: String,
+    max_width: usize,
+    max_height: usize,
+}
+
+/// Command read by `ueberzugpp layer`, positions are 0-based cells
+#[derive(Serialize)]
+struct UeberzugCommand<'a> {
+    action: &'static str,
+    identifier: String,
+    x: Option<usize>,
+    y: Option<usize>,
+    path: Option<&'a str>,
+    max_width: Option<usize>,
+    max_height: Option<usize>,
+}
+
+/// Images drawn by ueberzugpp in a child window, for terminals without graphics protocol
+pub struct Ueberzug {
+    child: Mutex<Option<(Child, ChildStdin)>>,
+    shown: Mutex<Vec<String>>,
+}
+
+impl Ueberzug {
+    pub fn new() -> Ueberzug {
+        let child = Command::new("ueberzugpp")
+            .args(["layer", "--silent"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()
+            .and_then(|mut child| child.stdin.take().map(|stdin| (child, stdin)));
+
+        Ueberzug { child: Mutex::new(child), shown: Mutex::new(Vec::new()) }
+    }
+
+    /// Send a single command, images are silently dropped if ueberzugpp is not running
+    fn send(&self, command: &UeberzugCommand) {
+        if let Some((_, stdin)) = &mut *self.child.lock().unwrap() {
+            let _ = writeln!(stdin, "{}", json::to_string(command));
+        }
+    }
+}
+
+impl Backend for Ueberzug {
+    fn encode(&self, image: &MagickWand) -> Result<Vec<u8>> {
+        let png = png(image)?;
+        let path = art_path().join(utils::hash_bytes(&png)).with_extension("ueberzug.png");
+        if !path.exists() {
+            std::fs::write(&path, &png).map_err(Error::Io)?;
+        }
+
+        let image = UeberzugImage {
+            path: path.to_string_lossy().to_string(),
+            max_width: image.get_image_width().div_ceil(utils::char_pixel_width().max(1)),
+            max_height: image.get_image_height().div_ceil(utils::char_pixel_height().max(1)),
+        };
+
+        Ok(json::to_string(&image).into_bytes())
+    }
+
+    fn place(&self, image: &[u8], row: usize, col: usize) -> Vec<u8> {
+        let image: UeberzugImage = match json::from_str(&String::from_utf8_lossy(image)) {
+            Ok(image) => image,
+            Err(_) => return Vec::new(),
+        };
+
+        let identifier = format!("{}-{}-{}", image.path, row, col);
+        self.send(&UeberzugCommand {
+            action: "add",
+            identifier: identifier.clone(),
+            x: Some(col.saturating_sub(1)),
+            y: Some(row.saturating_sub(1)),
+            path: Some(&image.path),
+            max_width: Some(image.max_width),
+            max_height: Some(image.max_height),
+        });
+        self.shown.lock().unwrap().push(identifier);
+
+        Vec::new()
+    }
+
+    /// Frames would pile up as separate images, the first one is shown
+    fn playback(&self) -> Playback {
+        Playback::Still
+    }
+
+    fn clear(&self) -> Vec<u8> {
+        for identifier in self.shown.lock().unwrap().drain(..) {
+            self.send(&UeberzugCommand {
+                action: "remove", identifier, x: None, y: None, path: None, max_width: None, max_height: None,
+            });
+        }
+
+        Vec::new()
+    }
+}
+
+/// Backend inside tmux, forwarding the escape sequences of another one to the outer terminal
+pub struct Tmux {
+    inner: &'static dyn Backend,
+    /// position of the pane in the terminal, queried again after clearing
+    offset: Mutex<Option<(usize, usize)>>,
+}
+
+impl Tmux {
+    const fn new(inner: &'static dyn Backend) -> Tmux {
+        Tmux { inner, offset: Mutex::new(None) }
+    }
+}
+
+impl Backend for Tmux {
+    fn encode(&self, image: &MagickWand) -> Result<Vec<u8>> {
+        self.inner.encode(image)
+    }
+
+    /// The cursor is moved in the outer terminal, positions are shifted by the offset of the pane
+    fn place(&self, image: &[u8], row: usize, col: usize) -> Vec<u8> {
+        let (top, left) = *self.offset.lock().unwrap()
+            .get_or_insert_with(|| tmux::pane_offset().unwrap_or((0, 0)));
+
+        tmux::passthrough(&self.inner.place(image, row + top, col + left))
+    }
+
+    fn clear(&self) -> Vec<u8> {
+        // panes move when splitting or resizing, which clears the images
+        *self.offset.lock().unwrap() = None;
+
+        let clear = self.inner.clear();
+        if clear.is_empty() { clear } else { tmux::passthrough(&clear) }
+    }
+
+    fn playback(&self) -> Playback {
+        self.inner.playback()
+    }
+
+    fn encode_animation(&self, id: u32, frames: &[(MagickWand, Duration)]) -> Result<Vec<u8>> {
+        self.inner.encode_animation(id, frames)
+    }
+}
+
+/// How images are shown in the terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// iTerm2, kitty or SIXEL as supported by the terminal, guessed from the environment if it
+    /// doesn't tell
+    Auto,
+    Sixel,
+    Kitty,
+    Iterm,
+    Ueberzug,
+    Ansi,
+}
+
+impl FromStr for BackendKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> result::Result<Self, ()> {
+        match s {
+            "auto" => Ok(BackendKind::Auto),
+            "sixel" => Ok(BackendKind::Sixel),
+            "kitty" => Ok(BackendKind::Kitty),
+            "iterm" => Ok(BackendKind::Iterm),
+            "ueberzug" => Ok(BackendKind::Ueberzug),
+            "ansi" => Ok(BackendKind::Ansi),
+            _ => Err(()),
+        }
+    }
+}
+
+impl BackendKind {
+    /// Resolve `auto` by asking the terminal, see `termcap`
+    fn resolve(self) -> BackendKind {
+        match self {
+            BackendKind::Auto => termcap::detect_backend(),
+            kind => kind,
+        }
+    }
+}
+
+/// Backend for the configured kind, resolving `auto` from the terminal
+///
+/// Inside tmux the escape sequences are wrapped for passthrough. ueberzugpp finds the pane by
+/// itself.
+pub fn backend(kind: BackendKind) -> &'static dyn Backend {
+    static UEBERZUG: OnceLock<Ueberzug> = OnceLock::new();
+    static TMUX_SIXEL: Tmux = Tmux::new(&Sixel);
+    static TMUX_KITTY: Tmux = Tmux::new(&Kitty);
+    static TMUX_ITERM: Tmux = Tmux::new(&Iterm);
+
+    match (kind.resolve(), tmux::active()) {
+        (BackendKind::Sixel, true) => &TMUX_SIXEL,
+        (BackendKind::Kitty, true) => &TMUX_KITTY,
+        (BackendKind::Iterm, true) => &TMUX_ITERM,
+        (BackendKind::Sixel, false) => &Sixel,
+        (BackendKind::Kitty, false) => &Kitty,
+        (BackendKind::Iterm, false) => &Iterm,
+        (BackendKind::Ueberzug, _) => UEBERZUG.get_or_init(Ueberzug::new),
+        (BackendKind::Ansi, _) => &Ansi,
+        (BackendKind::Auto, _) => unreachable!(),
+    }
+}
+
+/// Extensions of image files decoded directly instead of through imagemagick
+pub const RASTER_EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "webp", "gif"];
+
+/// Whether a linked file is a plain raster image
+pub fn is_raster(path: &Path) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| RASTER_EXTENSIONS.contains(&x.to_lowercase().as_str()))
+}
+
+/// Decode a raster image at full resolution
+pub fn load_raster(path: &Path) -> Result<RgbaImage> {
+    let invalid = || Error::InvalidImage(path.to_string_lossy().to_string());
+
+    let image = image::ImageReader::open(path).map_err(Error::Io)?
+        .with_guessed_format().map_err(Error::Io)?
+        .decode().map_err(|_| invalid())?;
+
+    Ok(image.into_rgba8())
+}
+
+/// Scale the image into a rectangle of pixels, centering it with transparent bars
+///
+/// The aspect ratio is kept, so that photos are not distorted by the height of their node.
+pub fn letterbox(image: &RgbaImage, width: usize, height: usize) -> RgbaImage {
+    let (width, height) = (width.max(1) as u32, height.max(1) as u32);
+    let scale = f32::min(width as f32 / image.width() as f32, height as f32 / image.height() as f32);
+    let scaled = imageops::resize(
+        image,
+        ((image.width() as f32 * scale).round() as u32).clamp(1, width),
+        ((image.height() as f32 * scale).round() as u32).clamp(1, height),
+        imageops::FilterType::Triangle,
+    );
+
+    let mut canvas = RgbaImage::new(width, height);
+    let (x, y) = ((width - scaled.width()) / 2, (height - scaled.height()) / 2);
+    imageops::overlay(&mut canvas, &scaled, x as i64, y as i64);
+
+    canvas
+}
+
+/// Fill of the box drawn while a snippet renders
+const PLACEHOLDER_COLOR: [u8; 4] = [128, 128, 128, 255];
+
+/// Number of dots of the spinner and how long each is highlighted
+const SPINNER_DOTS: usize = 8;
+const SPINNER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Grey box for a node still rendering, with a spinner of dots if the time since queuing is known
+pub fn placeholder(width: usize, height: usize, elapsed: Option<Duration>) -> RgbaImage {
+    let (width, height) = (width.max(1) as u32, height.max(1) as u32);
+    let mut image = RgbaImage::from_pixel(width, height, Rgba(PLACEHOLDER_COLOR));
+
+    let elapsed = match elapsed {
+        Some(elapsed) => elapsed,
+        None => return image,
+    };
+    let frame = (elapsed.as_millis() / SPINNER_INTERVAL.as_millis()) as usize % SPINNER_DOTS;
+
+    let size = width.min(height) as f32;
+    let (cx, cy, radius) = (width as f32 / 2.0, height as f32 / 2.0, size * 0.07);
+    for dot in 0..SPINNER_DOTS {
+        let angle = dot as f32 / SPINNER_DOTS as f32 * std::f32::consts::TAU;
+        let (x, y) = (cx + angle.sin() * size * 0.3, cy - angle.cos() * size * 0.3);
+
+        // the dot of the current frame is white, the ones behind it fade into the box
+        let age = (frame + SPINNER_DOTS - dot) % SPINNER_DOTS;
+        let mix = |grey: u8| grey + ((255 - grey) as usize * (SPINNER_DOTS - age) / SPINNER_DOTS) as u8;
+        let color = Rgba([mix(PLACEHOLDER_COLOR[0]), mix(PLACEHOLDER_COLOR[1]), mix(PLACEHOLDER_COLOR[2]), 255]);
+
+        let (x0, x1) = ((x - radius).floor().max(0.0) as u32, ((x + radius).ceil() as u32).min(width));
+        let (y0, y1) = ((y - radius).floor().max(0.0) as u32, ((y + radius).ceil() as u32).min(height));
+        for py in y0..y1 {
+            for px in x0..x1 {
+                if (px as f32 + 0.5 - x).powi(2) + (py as f32 + 0.5 - y).powi(2) <= radius * radius {
+                    image.put_pixel(px, py, color);
+                }
+            }
+        }
+    }
+
+    image
+}
+
+/// Disc under the play button of video thumbnails, translucent
+const PLAY_BUTTON_COLOR: [u8; 4] = [0, 0, 0, 160];
+
+/// Draw a play button in the middle of a video thumbnail, a white triangle on a dark disc
+pub fn play_button(image: &mut RgbaImage) {
+    let (width, height) = (image.width(), image.height());
+    let (cx, cy, radius) = (width as f32 / 2.0, height as f32 / 2.0, width.min(height) as f32 * 0.15);
+
+    let (x0, x1) = ((cx - radius).floor().max(0.0) as u32, ((cx + radius).ceil() as u32).min(width));
+    let (y0, y1) = ((cy - radius).floor().max(0.0) as u32, ((cy + radius).ceil() as u32).min(height));
+    for py in y0..y1 {
+        for px in x0..x1 {
+            let (x, y) = (px as f32 + 0.5 - cx, py as f32 + 0.5 - cy);
+            if x * x + y * y > radius * radius {
+                continue;
+            }
+
+            // the triangle points right, from its left edge at -0.4 to its tip at 0.6 radii
+            if x >= -0.4 * radius && y.abs() <= 0.5 * (0.6 * radius - x) {
+                image.put_pixel(px, py, Rgba([255, 255, 255, 255]));
+            } else {
+                image.get_pixel_mut(px, py).blend(&Rgba(PLAY_BUTTON_COLOR));
+            }
+        }
+    }
+}
+
+/// Wand holding the pixels of an image, for encoding them with a backend
+pub fn image_wand(image: &RgbaImage) -> MagickWand {
+    let mut transparent = PixelWand::new();
+    transparent.set_color("none").unwrap();
+
+    let mut wand = MagickWand::new();
+    wand.new_image(image.width() as usize, image.height() as usize, &transparent).unwrap();
+    wand.import_image_pixels(0, 0, image.width() as usize, image.height() as usize, image.as_raw(), "RGBA").unwrap();
+
+    wand
+}
+
+/// Fill of the placard shown for failed snippets
+const BADGE_COLOR: &str = "#c62828";
+
+/// Red placard of one row with the first line of an error, at most `max_width` pixels wide
+pub fn error_badge(message: &str, max_width: usize) -> MagickWand {
+    let height = utils::rows_pixel_height(1).max(1);
+    let font_size = height as f64 * 0.6;
+
+    // glyphs of the default font are about 0.6 em wide, longer messages are cut off
+    let glyph = font_size * 0.6;
+    let max_chars = ((max_width as f64 - font_size) / glyph).max(1.0) as usize;
+    let line = message.lines().next().unwrap_or_default();
+    let text = if line.chars().count() > max_chars {
+        line.chars().take(max_chars - 1).chain(Some('…')).collect()
+    } else {
+        line.to_string()
+    };
+    let width = ((text.chars().count() as f64 * glyph + font_size) as usize).clamp(1, max_width.max(1));
+
+    let mut background = PixelWand::new();
+    background.set_color(BADGE_COLOR).unwrap();
+    let mut foreground = PixelWand::new();
+    foreground.set_color("white").unwrap();
+
+    let mut wand = MagickWand::new();
+    wand.new_image(width, height, &background).unwrap();
+
+    let mut draw = DrawingWand::new();
+    draw.set_font_size(font_size);
+    draw.set_fill_color(&foreground);
+
+    // text is positioned at its baseline, capitals are about 0.7 em high
+    let _ = wand.annotate_image(&draw, font_size / 2.0, (height as f64 + font_size * 0.7) / 2.0, 0.0, &text);
+
+    wand
+}
+
+/// Descriptor of the terminal receiving the images, the standard output unless replaced
+#[cfg(unix)]
+static OUTPUT_FD: AtomicI32 = AtomicI32::new(1);
+
+/// Console receiving the images, the standard output unless replaced
+#[cfg(windows)]
+static OUTPUT: Mutex<Option<File>> = Mutex::new(None);
+
+/// Descriptor of the terminal receiving the images
+#[cfg(unix)]
+pub fn output_fd() -> i32 {
+    OUTPUT_FD.load(Ordering::SeqCst)
+}
+
+/// Write images to `file` instead of the standard output, which may be used for messages
+#[cfg(unix)]
+pub fn set_output(file: File) {
+    OUTPUT_FD.store(file.into_raw_fd(), Ordering::SeqCst);
+}
+
+/// Write images to `file` instead of the standard output, which may be used for messages
+#[cfg(windows)]
+pub fn set_output(file: File) {
+    *OUTPUT.lock().unwrap() = Some(file);
+}
+
+/// Write the whole buffer to the console, escape sequences are interpreted by ConPTY
+#[cfg(windows)]
+fn write_raw(stdout: &Stdout, buf: &[u8]) {
+    let mut stdout = stdout.lock();
+    let _ = match OUTPUT.lock().unwrap().as_mut() {
+        Some(file) => file.write_all(buf),
+        None => stdout.write_all(buf).and_then(|_| stdout.flush()),
+    };
+}
+
+/// Write the whole buffer to the terminal, bypassing the buffering of `Stdout`
+#[cfg(unix)]
+fn write_raw(stdout: &Stdout, buf: &[u8]) {
+    let outer_lock = stdout.lock();
+    let mut stdout = unsafe { File::from_raw_fd(OUTPUT_FD.load(Ordering::SeqCst)) };
+    let mut idx = 0;
+    while idx < buf.len() {
+        match stdout.write(&buf[idx..]) {
+            Ok(n) => idx += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {},
+            // the terminal is gone, retrying would never finish
+            Err(_) => break,
+        }
+    }
+    std::mem::forget(stdout);
+    drop(outer_lock);
+}
 pub type Folds = Vec<(usize, isize)>;
 
 #[derive(Debug, Deserialize)]
 pub struct Metadata {
+    /// id of the window, several can show the same buffer
+    pub winid: u64,
     pub file_range: (u64, u64),
+    /// rows and text columns of the window, without statusline and line numbers
     pub viewport: (u64, u64),
     pub cursor: u64,
     pub winpos: (usize, usize),
     pub char_height: usize,
+    /// first and last line of the closed folds in the window
+    pub closed_folds: Vec<(usize, usize)>,
+    /// filler lines placed by the editor above a line, as `(line, count)`
+    pub virtual_lines: Option<Vec<(usize, usize)>>,
 }
 
 impl Metadata {
-    pub fn new() -> Metadata {
-        Metadata {
-            file_range: (1, 1),
-            viewport: (1, 1),
-            cursor: 1,
-            winpos: (1, 1),
-            char_height: 0,
+    /// Whether some lines of the range are in the window
+    fn shows(&self, range: (usize, usize)) -> bool {
+        range.1 as u64 >= self.file_range.0 && range.0 as u64 <= self.file_range.1
+    }
+
+    /// Whether a line is hidden in a closed fold, or is the summary line of one
+    fn is_folded(&self, line: usize) -> bool {
+        self.closed_folds.iter().any(|(start, end)| *start <= line && line <= *end)
+    }
+
+    /// Row of a line relative to the top of the window, closed folds take up a single row
+    fn row(&self, line: usize) -> isize {
+        let hidden = self.closed_folds.iter()
+            .filter(|(_, end)| *end < line)
+            .map(|(start, end)| end - start)
+            .sum::<usize>();
+
+        // filler lines push the lines below them down, those above the window are not shown
+        let filler = self.virtual_lines.iter().flatten()
+            .filter(|(above, _)| self.file_range.0 < *above as u64 && *above <= line)
+            .map(|(_, count)| count)
+            .sum::<usize>();
+
+        line as isize - self.file_range.0 as isize - hidden as isize + filler as isize
+    }
+}
+
+/// Tracks what is on screen, so that only nodes which moved or were overdrawn are drawn again
+///
+/// Vim scrolls, redraws the cursor line and the one it left, everything else stays untouched,
+/// including the images drawn over it.
+#[derive(Debug, Default)]
+pub struct Viewport {
+    /// cursor line of the previous draw
+    last_cursor: u64,
+}
+
+impl Viewport {
+    /// Whether the node has to be drawn for its new view
+    fn needs_draw(&self, metadata: &Metadata, node: &Node, old: &NodeView, new: &NodeView) -> bool {
+        if old != new {
+            return true;
         }
+
+        let touched = |line: u64| node.range.0 as u64 <= line && line <= (node.range.0 + node.rows(metadata)) as u64;
+        new.is_visible() && (touched(metadata.cursor) || touched(self.last_cursor))
+    }
+
+    /// Width in pixels left right of a column, images are cut off there instead of spilling into
+    /// the neighbouring window
+    fn pixel_width(metadata: &Metadata, column: usize) -> usize {
+        (metadata.viewport.1 as usize).saturating_sub(column) * utils::char_pixel_width()
+    }
+}
+
+/// Placement state of one window showing the buffer
+pub struct Window {
+    metadata: Metadata,
+    viewport: Viewport,
+    /// view of the nodes drawn in this window, missing ones are hidden
+    views: HashMap<CodeId, NodeView>,
+    /// frame of the animations on screen, they are drawn again once another one is due
+    frames: HashMap<CodeId, usize>,
+}
+
+impl Window {
+    pub fn new(metadata: Metadata) -> Window {
+        Window { metadata, viewport: Viewport::default(), views: HashMap::new(), frames: HashMap::new() }
+    }
+}
+
+/// Lines replacing the old lines `start..end` of the buffer, counted from zero
+#[derive(Debug, Deserialize)]
+pub struct Region {
+    start: usize,
+    end: usize,
+    lines: Vec<String>,
+}
+
+/// Snippet whose cached artifact is checked, `kind` is a fence or `inline_math` and `environment`
+#[derive(Debug, Deserialize)]
+pub struct Entry {
+    kind: String,
+    content: String,
+}
+
+/// Position of the cursor, sent when it moves in hover mode
+#[derive(Debug, Deserialize)]
+pub struct CursorPos {
+    winid: u64,
+    line: usize,
+    /// column in characters, starting at zero
+    col: usize,
+    /// terminal row and column of the cursor, starting at one
+    screen: (usize, usize),
+}
+
+/// Side window showing the compiled buffer and the cursor line of the buffer, sent by the editor
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct PageWindow {
+    winid: u64,
+    /// line of the cursor in the buffer, starting at one
+    line: usize,
+    /// terminal row and column of the top left corner, starting at one
+    winpos: (usize, usize),
+    /// rows and columns of the window
+    viewport: (u64, u64),
+    /// directory of the buffer, relative paths of images are resolved against it
+    dir: String,
+}
+
+/// Pages of the whole buffer, in document mode
+#[derive(Default)]
+struct PageView {
+    window: Option<PageWindow>,
+    /// key of the newest render, results of older ones are dropped
+    wanted: Arc<Mutex<String>>,
+    pages: Arc<RwLock<Option<Result<document::Pages>>>>,
+    /// key of the document and index of the page on screen
+    drawn: Option<(String, usize)>,
+}
+
+/// Id of the render jobs of documents
+const DOCUMENT_JOB: &str = "document";
+
+/// Node shown in an overlay next to the cursor, in hover mode
+#[derive(Debug)]
+struct Hover {
+    id: CodeId,
+    winid: u64,
+    screen: (usize, usize),
+    drawn: bool,
+}
+
+impl Hover {
+    /// Terminal position of an overlay, below the cursor or above it if the window ends first
+    fn position(&self, metadata: &Metadata, rows: usize) -> (usize, usize) {
+        let bottom = metadata.winpos.0 + metadata.viewport.0 as usize;
+        let row = if self.screen.0 + 1 + rows <= bottom {
+            self.screen.0 + 1
+        } else {
+            self.screen.0.saturating_sub(rows).max(metadata.winpos.0)
+        };
+
+        (row, self.screen.1)
     }
 }
 
@@ -46,30 +827,37 @@ pub enum FoldState {
 
 #[derive(Debug)]
 pub struct Fold {
-    pub line: usize,
     pub state: FoldState,
 }
 
 #[derive(Debug)]
 pub enum FoldInner {
     Fold(Fold),
-    Node((CodeId, NodeView)),
+    Node(CodeId),
 }
 
-impl FoldInner {
-    pub fn is_in_view(&self, metadata: &Metadata, blocks: &BTreeMap<CodeId, Node>) -> bool {
-        match self {
-            FoldInner::Node((id, _)) => {
-                let range = blocks.get(id).unwrap().range;
-        
-                range.1 as u64 >= metadata.file_range.0 &&
-                    range.0 as u64 <= metadata.file_range.1
-            },
-            FoldInner::Fold(ref fold) => 
-                fold.line as u64 >= metadata.file_range.0 &&
-                    fold.line as u64 <= metadata.file_range.1
-        }
-    }
+/// First line of a node in the buffer, tracked by the editor across edits
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Anchor {
+    id: CodeId,
+    line: usize,
+}
+
+/// Filler lines the editor inserts above a line, so that an image taller than its lines doesn't
+/// cover the text below it
+#[derive(Debug, Serialize)]
+pub struct VirtualLines {
+    id: CodeId,
+    /// first line below the lines of the node, starting at one
+    line: usize,
+    count: usize,
+}
+
+/// Blocks whose job finished, and whether others are still queued or running
+#[derive(Debug, Serialize)]
+pub struct FinishedJobs {
+    ids: Vec<String>,
+    pending: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -81,200 +869,547 @@ pub struct RedrawState {
 pub struct Render {
     stdout: Stdout,
     blocks: BTreeMap<CodeId, Node>,
-    strcts: BTreeMap<usize, FoldInner>,
-    metadata: Metadata,
+    strcts: BTreeMap<(usize, usize), FoldInner>,
+    /// windows showing the buffer, by id
+    windows: BTreeMap<u64, Window>,
     content: Content,
+    /// Vim filetype of the buffer, selecting the markup languages parsed
+    filetype: String,
+    config: Config,
+    /// Options set by magic comments of the current buffer
+    overrides: Vec<(String, String)>,
+    document: cache::DocumentSync,
+    memory: Memory,
+    /// node under the cursor, in hover mode
+    hover: Option<Hover>,
+    /// buffer of the last update, changed regions are applied to it
+    lines: Vec<String>,
+    pages: PageView,
+    /// zoom of all images, nodes with their own zoom are scaled along
+    zoom: f32,
 }
 
+/// Number of render results kept in memory
+const MEMORY_CAPACITY: usize = 512;
+
+/// Factor applied by one step of zooming in or out, and the limits of the zoom
+const ZOOM_STEP: f32 = 1.25;
+const ZOOM_RANGE: (f32, f32) = (0.25, 8.0);
+
 impl Render {
     pub fn new() -> Render {
-        if !Path::new(ART_PATH).exists() {
-            std::fs::create_dir(ART_PATH).unwrap();
+        if !art_path().exists() {
+            std::fs::create_dir_all(art_path()).unwrap();
         }
 
         Render {
             stdout: std::io::stdout(),
             blocks: BTreeMap::new(),
             strcts: BTreeMap::new(),
-            metadata: Metadata::new(),
-            content: Content::new(),
+            windows: BTreeMap::new(),
+            content: Content::new(""),
+            filetype: String::new(),
+            config: Config::new(),
+            overrides: Vec::new(),
+            document: cache::DocumentSync::default(),
+            memory: Arc::new(Mutex::new(cache::LruCache::new(MEMORY_CAPACITY))),
+            hover: None,
+            lines: Vec::new(),
+            pages: PageView::default(),
+            zoom: 1.0,
         }
     }
 
     pub fn draw(&mut self, _: &str) -> Result<usize> {
         let mut pending = false;
+        let backend = backend(self.config.backend);
 
-        // mutable iterator of items, skipping things outside the viewport
-        let mut items = self.strcts.iter_mut()
-            .map(|(a, item)| {
-                if !item.is_in_view(&self.metadata, &self.blocks) {
-                    if let FoldInner::Node((_, ref mut view)) = item {
-                        *view = NodeView::Hidden;
-                    }
-                }
+        if self.config.mode == PreviewMode::Hover {
+            return self.draw_hover(backend);
+        }
+        if self.config.mode == PreviewMode::Document {
+            return self.draw_page(backend);
+        }
 
-                (a, item)
-            })
-            .filter(|(_, item)| {
-                item.is_in_view(&self.metadata, &self.blocks)
-            })
-            .collect::<Vec<_>>();
+        for window in self.windows.values_mut() {
+            pending |= Render::draw_window(window, &self.strcts, &mut self.blocks, &self.stdout, backend)?;
+        }
 
-        // initialize current item
-        let mut iter = items.iter_mut();
-        let mut item = match iter.next() {
-            Some(x) => x,
-            None => return Ok(0)
-        };
+        Ok(if pending { 1 } else { 0 })
+    }
 
-        // initialize last line and top offset, so that first iteration gives offset to first item
-        let mut last_line = self.metadata.file_range.0 as usize;
-        let mut top_offset: isize = 0;
-    
-        // perform fold skipping if folded in
-        let mut skip_to = None;
-        'outer: loop {
-            match item.1 {
-                FoldInner::Node((id, ref mut node_view)) => {
-                    let node = self.blocks.get_mut(id).unwrap();
-
-                    // calculate new offset (this can be negative at the beginning)
-                    top_offset += node.range.0 as isize - last_line as isize;
-                    last_line = node.range.0;
-
-                    pending |= Render::draw_node(&self.metadata, &self.stdout, node, node_view, top_offset)?;
-                },
-                FoldInner::Fold(ref fold) => {
-                    // offset has a header of single line
-                    top_offset += fold.line as isize - last_line as isize;
-
-                    if let FoldState::Folded(end) =  fold.state {
-                        skip_to = Some(end);
-                        
-                        last_line = end;
-                    } else {
-                        last_line = fold.line;
-                    }
-                }
+    /// Draw the nodes visible in a window, returns whether some are still being rendered or play
+    /// frame by frame
+    fn draw_window(window: &mut Window, strcts: &BTreeMap<(usize, usize), FoldInner>, blocks: &mut BTreeMap<CodeId, Node>, stdout: &Stdout, backend: &dyn Backend) -> Result<bool> {
+        let Window { metadata, viewport, views, frames } = window;
+        let mut pending = false;
+
+        for item in strcts.values() {
+            let id = match item {
+                FoldInner::Node(id) => id,
+                // folds only matter for the placement of the nodes below, see `Metadata::row`
+                FoldInner::Fold(_) => continue,
+            };
+
+            // nodes outside of the viewport or in a closed fold are hidden
+            let node = blocks.get_mut(id).unwrap();
+            if !metadata.shows(node.range) || metadata.is_folded(node.range.0) {
+                views.remove(id);
+                continue;
             }
 
-            // get new items and skip until line is reached
-            loop {
-                item = match iter.next() {
-                    Some(x) => x,
-                    None => break 'outer
-                };
-
-                if let Some(skip_line) = skip_to.take() {
-                    if *item.0 <= skip_line {
-                        skip_to = Some(skip_line);
-                        continue;
-                    }
-                }
+            // this can be negative for nodes starting above the window
+            let top_offset = metadata.row(node.range.0);
+            let node_view = views.entry(id.clone()).or_insert(NodeView::Hidden);
 
-                break;
+            // the next frame is drawn over the old one
+            let frame = node.frame(backend.playback());
+            if frame.is_some() && frames.get(id) != frame.as_ref() {
+                *node_view = NodeView::Hidden;
             }
 
+            pending |= Render::draw_node(metadata, viewport, stdout, backend, node, node_view, top_offset)?;
+
+            if let (Some(frame), true) = (frame, node_view.is_visible()) {
+                frames.insert(id.clone(), frame);
+                pending |= node.clock.is_running() && backend.playback() == Playback::Frames;
+            }
         }
 
-        //dbg!(&pending);
+        viewport.last_cursor = metadata.cursor;
 
-        Ok(if pending { 1 } else { 0 })
+        Ok(pending)
     }
-    pub fn draw_node(metadata: &Metadata, stdout: &Stdout, node: &mut Node, view: &mut NodeView, top_offset: isize) -> Result<bool> {
+    pub fn draw_node(metadata: &Metadata, viewport: &Viewport, stdout: &Stdout, backend: &dyn Backend, node: &mut Node, view: &mut NodeView, top_offset: isize) -> Result<bool> {
         // calculate new view and height of node
         let new_view = NodeView::new(node,  metadata, top_offset);
-        let char_height = metadata.char_height;
-        let theight = node.range.1 - node.range.0;
-
-        let (pos, crop) = match (&view, &new_view) {
-            (NodeView::UpperBorder(_, _) | NodeView::LowerBorder(_, _) | NodeView::Hidden, NodeView::Visible(pos, _)) =>
-                (*pos, None),
-            (NodeView::Hidden, NodeView::LowerBorder(pos, height)) =>
-                (*pos, Some((height * char_height, 0))),
-            (NodeView::LowerBorder(_, height_old), NodeView::LowerBorder(pos, height)) if height_old < height =>
-                (*pos, Some((height * char_height, 0))),
-            (NodeView::Hidden, NodeView::UpperBorder(y, height)) => 
-                (0, Some((height * char_height, y * char_height))),
-            (NodeView::UpperBorder(y_old, _), NodeView::UpperBorder(y, height)) if y < y_old =>
-                (0, Some((height * char_height, y * char_height))),
-            _ => return Ok(false),
+        let rows = utils::rows_pixel_height;
+        let theight = node.rows(metadata);
+
+        if !viewport.needs_draw(metadata, node, view, &new_view) {
+            return Ok(false);
+        }
+
+        // partially visible nodes are clipped at the edge of the window
+        let (pos, crop) = match new_view {
+            NodeView::Visible(pos, _) => (pos, None),
+            NodeView::LowerBorder(pos, height) => (pos, Some((rows(height), 0))),
+            NodeView::UpperBorder(y, height) => (0, Some((rows(height), rows(y)))),
+            NodeView::Hidden => {
+                *view = NodeView::Hidden;
+                return Ok(false);
+            },
         };
 
         let dim = NodeDim {
-            height: theight * char_height,
-            crop
+            height: rows(theight),
+            crop,
+            max_width: Viewport::pixel_width(metadata, node.column),
+            frame: node.frame(backend.playback()),
         };
 
-        if let Some(buf) = node.get_sixel(dim) {
-            // bail out if an error happened during conversion
-            let mut buf = buf?;
-
-            //dbg!(&metadata.winpos.0, &metadata.winpos.1);
-            let mut wbuf = format!("\x1b[s\x1b[{};{}H", pos + metadata.winpos.0, metadata.winpos.1).into_bytes();
-            //for _ in 0..(node.range.1-node.range.0 - 1) {
-            //    wbuf.extend_from_slice(b"\x1b[B\x1b[K");
-            //}
-
-            //wbuf.append(&mut format!("\x1b[{};{}H", pos + metadata.winpos.0, metadata.winpos.1).into_bytes());
-            //dbg!(&buf.len());
-            wbuf.append(&mut buf);
-            //wbuf.append(&mut format!("\x1b[{};{}H", metadata.viewport.0, metadata.winpos.1).into_bytes());
-            //wbuf.append(&mut format!("\x1b[?80h\x1bP100;1q\"1;1;2000;50\"1;1;2000;50\x1b[u\x1b\\").into_bytes());
-            //wbuf.extend_from_slice(b"\x1b[u");
-            wbuf.extend_from_slice(b"\x1b[u");
-
-            {
-                let outer_lock = stdout.lock();
-                let mut stdout = unsafe { File::from_raw_fd(1) };
-                let mut idx = 0;
-                while idx < wbuf.len() {
-                    match stdout.write(&wbuf[idx..]) {
-                        Ok(n) => idx += n,
-                        Err(_) => {/*eprintln!("{}", err);*/},
+        if let Some(buf) = node.get_sixel(dim.clone()) {
+            // bail out if an error happened during conversion, after showing it in place
+            let buf = match buf {
+                Ok(buf) => buf,
+                Err(err) => {
+                    if let Some(Ok(badge)) = node.error_badge(&err, &dim).map(|x| backend.encode(&x)) {
+                        write_raw(stdout, &backend.place(&badge, pos + metadata.winpos.0, metadata.winpos.1 + node.column));
+                        *view = new_view;
                     }
-                }
-                std::mem::forget(stdout);
-                drop(outer_lock);
-            }
+
+                    return Err(err);
+                },
+            };
+            let wbuf = backend.place(&buf, pos + metadata.winpos.0, metadata.winpos.1 + node.column);
+
+            write_raw(stdout, &wbuf);
+            *view = new_view;
 
             Ok(false)
         } else {
+            // the view stays unchanged, so that the image replaces the placeholder once done
+            if let Some(placeholder) = node.placeholder(&dim) {
+                write_raw(stdout, &backend.place(&backend.encode(&image_wand(&placeholder))?, pos + metadata.winpos.0, metadata.winpos.1 + node.column));
+            }
+
             Ok(new_view.is_visible())
         }
     }
 
-    pub fn clear_all(&mut self, _: &str) -> Result<()> {
-        for fold in self.strcts.values_mut() {
-            if let FoldInner::Node(ref mut node) = fold {
-                node.1 = NodeView::Hidden;
+    /// Draw the overlay of the node under the cursor, unless already on screen
+    fn draw_hover(&mut self, backend: &dyn Backend) -> Result<usize> {
+        let hover = match &mut self.hover {
+            Some(hover) if !hover.drawn => hover,
+            _ => return Ok(0),
+        };
+        let (node, metadata) = match (self.blocks.get_mut(&hover.id), self.windows.get(&hover.winid)) {
+            (Some(node), Some(metadata)) => (node, &metadata.metadata),
+            _ => return Ok(0),
+        };
+
+        // inline math spans one row, blocks as many as the lines following them
+        let rows = usize::max(1, node.rows(metadata));
+        let (row, col) = hover.position(metadata, rows);
+        let dim = NodeDim {
+            height: utils::rows_pixel_height(rows),
+            crop: None,
+            max_width: (metadata.winpos.1 + metadata.viewport.1 as usize).saturating_sub(col) * utils::char_pixel_width(),
+            frame: node.frame(backend.playback()),
+        };
+
+        match node.get_sixel(dim.clone()) {
+            Some(Ok(buf)) => {
+                write_raw(&self.stdout, &backend.place(&buf, row, col));
+                hover.drawn = true;
+
+                Ok(0)
+            },
+            Some(Err(err)) => {
+                if let Some(Ok(badge)) = node.error_badge(&err, &dim).map(|x| backend.encode(&x)) {
+                    write_raw(&self.stdout, &backend.place(&badge, row, col));
+                    hover.drawn = true;
+                }
+
+                Err(err)
+            },
+            None => {
+                if let Some(placeholder) = node.placeholder(&dim) {
+                    write_raw(&self.stdout, &backend.place(&backend.encode(&image_wand(&placeholder))?, row, col));
+                }
+
+                Ok(1)
+            },
+        }
+    }
+
+    /// Draw the page at the cursor into the side window, returns 1 while the document compiles
+    fn draw_page(&mut self, backend: &dyn Backend) -> Result<usize> {
+        let PageView { window, pages, drawn, .. } = &mut self.pages;
+        let Some(window) = window else {
+            return Ok(0);
+        };
+
+        let mut state = pages.write().unwrap();
+        let res = match &*state {
+            None => return Ok(if jobs::queue().elapsed(DOCUMENT_JOB).is_some() { 1 } else { 0 }),
+            Some(Err(_)) => return Err(state.take().unwrap().unwrap_err()),
+            Some(Ok(res)) => res,
+        };
+
+        let page = res.page_of(window.line);
+        if res.pages.is_empty() || drawn.as_ref() == Some(&(res.key.clone(), page)) {
+            return Ok(0);
+        }
+
+        // fit the page into the window, keeping its aspect ratio
+        let tree = svg::load_tree(&res.pages[page])?;
+        let (width, height) = (window.viewport.1 as usize * utils::char_pixel_width(), utils::rows_pixel_height(window.viewport.0 as usize));
+        let height = height.min((width as f32 * tree.size().height() / tree.size().width()) as usize);
+
+        // pages are paper, always opaque
+        let image = svg::rasterize(&tree, height, false);
+        write_raw(&self.stdout, &backend.place(&backend.encode(&image_wand(&image))?, window.winpos.0, window.winpos.1));
+        *drawn = Some((res.key.clone(), page));
+
+        Ok(0)
+    }
+
+    /// Show the page at the cursor line in the side window of document mode
+    ///
+    /// Returns 1 if the window moved or was resized, the editor has to redraw the screen to erase
+    /// the old page.
+    pub fn preview_document(&mut self, window: &str) -> Result<usize> {
+        let window: PageWindow = json::from_str(window)
+            .map_err(|_| Error::InvalidOption("window".to_string(), window.to_string()))?;
+
+        let moved = self.pages.window.as_ref()
+            .is_some_and(|old| (old.winid, old.winpos, old.viewport) != (window.winid, window.winpos, window.viewport));
+        if moved {
+            self.pages.drawn = None;
+        }
+        self.pages.window = Some(window);
+
+        Ok(if moved { 1 } else { 0 })
+    }
+
+    /// Compile the whole buffer in the background, replacing the pages once done
+    fn render_pages(&mut self) {
+        let content = self.lines.join("\n");
+        let config = self.document_config();
+        let dir = self.pages.window.as_ref()
+            .map(|x| PathBuf::from(&x.dir))
+            .filter(|x| x.is_absolute())
+            .or_else(|| std::env::current_dir().ok())
+            .unwrap_or_default();
+        let key = config.key(&format!("{}:{}", dir.display(), content));
+
+        if *self.pages.wanted.lock().unwrap() == key {
+            return;
+        }
+        *self.pages.wanted.lock().unwrap() = key.clone();
+
+        let (wanted, pages) = (self.pages.wanted.clone(), self.pages.pages.clone());
+        jobs::queue().submit(DOCUMENT_JOB, move || {
+            let res = jobs::catch(|| document::render(&content, &dir, &config));
+
+            // a newer version of the buffer is being compiled already
+            if *wanted.lock().unwrap() == key {
+                *pages.write().unwrap() = Some(res);
+            }
+        });
+    }
+
+    /// Select the node under the cursor for the overlay of hover mode
+    ///
+    /// Returns 1 if a drawn overlay was dismissed, the editor has to redraw the screen to erase it.
+    pub fn preview_at(&mut self, cursor: &str) -> Result<usize> {
+        let cursor: CursorPos = json::from_str(cursor)
+            .map_err(|_| Error::InvalidOption("cursor".to_string(), cursor.to_string()))?;
+        let id = self.node_at(&cursor);
+
+        let unchanged = self.hover.as_ref()
+            .is_some_and(|x| Some(&x.id) == id.as_ref() && x.winid == cursor.winid && x.screen == cursor.screen);
+        if unchanged {
+            return Ok(0);
+        }
+
+        let dismissed = self.hover.take().is_some_and(|x| x.drawn);
+        if dismissed {
+            let clear = backend(self.config.backend).clear();
+            if !clear.is_empty() {
+                write_raw(&self.stdout, &clear);
+            }
+        }
+
+        self.hover = id.map(|id| Hover { id, winid: cursor.winid, screen: cursor.screen, drawn: false });
+
+        Ok(if dismissed { 1 } else { 0 })
+    }
+
+    /// Node under the cursor, inline math is hit between its delimiters, blocks on any of their lines
+    fn node_at(&self, cursor: &CursorPos) -> Option<CodeId> {
+        self.blocks.values()
+            .filter(|node| match node.content() {
+                (_, ContentType::InlineMath) => node.range.0 == cursor.line
+                    && node.column <= cursor.col && cursor.col < node.column + node.width,
+                _ => node.range.0 <= cursor.line && cursor.line <= node.range.1,
+            })
+            .max_by_key(|node| node.column)
+            .map(|node| node.id.clone())
+    }
+
+    /// Scale the image under the cursor, or all images without a cursor, by a factor
+    ///
+    /// `None` resets the zoom. The SVGs are rasterized again from the cache at the new number of
+    /// rows, latex doesn't run. Returns 1 if a zoom changed, the editor has to redraw the screen to
+    /// erase the images at their old size.
+    fn zoom(&mut self, cursor: &str, factor: Option<f32>) -> Result<usize> {
+        let scale = |zoom: f32| factor.map_or(1.0, |x| (zoom * x).clamp(ZOOM_RANGE.0, ZOOM_RANGE.1));
+
+        if cursor.is_empty() {
+            self.zoom = scale(self.zoom);
+            for node in self.blocks.values_mut() {
+                node.zoom = factor.map_or(1.0, |_| scale(node.zoom));
+            }
+        } else {
+            let cursor: CursorPos = json::from_str(cursor)
+                .map_err(|_| Error::InvalidOption("cursor".to_string(), cursor.to_string()))?;
+            let Some(node) = self.node_at(&cursor).and_then(|id| self.blocks.get_mut(&id)) else {
+                return Ok(0);
+            };
+
+            node.zoom = if factor.is_some() { scale(node.zoom) } else { self.zoom };
+        }
+
+        self.clear_all("")?;
+
+        Ok(1)
+    }
+
+    pub fn zoom_in(&mut self, cursor: &str) -> Result<usize> {
+        self.zoom(cursor, Some(ZOOM_STEP))
+    }
+
+    pub fn zoom_out(&mut self, cursor: &str) -> Result<usize> {
+        self.zoom(cursor, Some(1.0 / ZOOM_STEP))
+    }
+
+    pub fn zoom_reset(&mut self, cursor: &str) -> Result<usize> {
+        self.zoom(cursor, None)
+    }
+
+    /// Play or pause the animation under the cursor, or all animations without a cursor
+    ///
+    /// Paused animations show the frame they stopped at. Returns 1 if one changed, the editor has
+    /// to redraw the screen so that kitty drops the image it plays.
+    fn playback(&mut self, cursor: &str, play: bool) -> Result<usize> {
+        let ids = if cursor.is_empty() {
+            self.blocks.keys().cloned().collect()
+        } else {
+            let cursor: CursorPos = json::from_str(cursor)
+                .map_err(|_| Error::InvalidOption("cursor".to_string(), cursor.to_string()))?;
+
+            self.node_at(&cursor).into_iter().collect::<Vec<_>>()
+        };
+
+        let mut changed = false;
+        for node in self.blocks.values_mut().filter(|x| ids.contains(&x.id)) {
+            if !node.is_animated() || node.clock.is_running() == play {
+                continue;
             }
+
+            if play { node.clock.play() } else { node.clock.pause() }
+            changed = true;
+        }
+
+        if !changed {
+            return Ok(0);
+        }
+        self.clear_all("")?;
+
+        Ok(1)
+    }
+
+    pub fn play(&mut self, cursor: &str) -> Result<usize> {
+        self.playback(cursor, true)
+    }
+
+    pub fn pause(&mut self, cursor: &str) -> Result<usize> {
+        self.playback(cursor, false)
+    }
+
+    pub fn clear_all(&mut self, _: &str) -> Result<()> {
+        for window in self.windows.values_mut() {
+            window.views.clear();
+            window.frames.clear();
+        }
+
+        // the overlay is erased as well, draw it again
+        if let Some(hover) = &mut self.hover {
+            hover.drawn = false;
+        }
+
+        let clear = backend(self.config.backend).clear();
+        if !clear.is_empty() {
+            write_raw(&self.stdout, &clear);
+        }
+
+        Ok(())
+    }
+
+    /// Parse the following buffers as markup of a Vim filetype like `org.graphics`
+    pub fn set_filetype(&mut self, filetype: &str) -> Result<()> {
+        if filetype != self.filetype {
+            self.filetype = filetype.to_string();
+            self.content = Content::new(filetype);
         }
 
         Ok(())
     }
 
+    /// Update the windows showing the buffer, given as list of their metadata
     pub fn update_metadata(&mut self, metadata: &str) -> Result<()> {
-        let mut metadata: Metadata = json::from_str(metadata).unwrap();
-        metadata.char_height = utils::char_pixel_height();
+        let metadata: Vec<Metadata> = json::from_str(metadata)
+            .map_err(|_| Error::InvalidOption("metadata".to_string(), metadata.to_string()))?;
+        let char_height = utils::char_pixel_height();
 
-        let rerender = metadata.viewport != self.metadata.viewport;
+        // resized, moved and closed windows leave images behind at their old place, so do lines
+        // disappearing in a fold
+        let rerender = metadata.len() != self.windows.len() || metadata.iter().any(|new| {
+            self.windows.get(&new.winid)
+                .is_none_or(|old| old.metadata.viewport != new.viewport || old.metadata.winpos != new.winpos || old.metadata.closed_folds != new.closed_folds)
+        });
         if rerender {
             self.clear_all("")?;
         }
 
-        self.metadata = metadata;
+        let mut old = mem::take(&mut self.windows);
+        for mut metadata in metadata {
+            metadata.char_height = char_height;
+
+            let window = match old.remove(&metadata.winid) {
+                Some(window) => Window { metadata, ..window },
+                None => Window::new(metadata),
+            };
+            self.windows.insert(window.metadata.winid, window);
+        }
 
         Ok(())
     }
 
+    pub fn update_config(&mut self, options: &str) -> Result<usize> {
+        let old = self.config.clone();
+        self.config.update(options)?;
+
+        if old == self.config {
+            return Ok(0);
+        }
+
+        if self.config.cache_dir != old.cache_dir {
+            set_art_path(&self.config.cache_dir)?;
+        }
+
+        if self.config.cell_size != old.cell_size {
+            utils::set_cell_size(self.config.cell_size);
+        }
+
+        // images are encoded and placed for the backend, the old one removes what it drew
+        if self.config.backend != old.backend {
+            let clear = backend(old.backend).clear();
+            if !clear.is_empty() {
+                write_raw(&self.stdout, &clear);
+            }
+        }
+        self.clear_all("")?;
+
+        // nodes are reused by their key, options outside of it have to reach them as well
+        let config = self.document_config();
+        for node in self.blocks.values_mut() {
+            node.set_config(&config);
+        }
+
+        // options outside of the cache key, like `emit_png`, change the results as well
+        *self.memory.lock().unwrap() = cache::LruCache::new(MEMORY_CAPACITY);
+
+        if !self.config.prewarm.is_empty() {
+            let config = self.config.clone();
+            jobs::queue().submit("prewarm", move || {
+                for fragment in &config.prewarm {
+                    let _ = ContentType::Math.render(fragment, &config);
+                }
+            });
+        }
+
+        Ok(1)
+    }
+
+    /// Global configuration with the magic comments of the buffer applied
+    fn document_config(&self) -> Config {
+        self.config.with_overrides(&self.overrides)
+    }
+
     pub fn update_content(&mut self, content: &str) -> Result<String> {
+        self.lines = content.split('\n').map(|x| x.to_string()).collect();
+
         let old_blocks = mem::take(&mut self.blocks);
-        let (nodes, strcts, folds, any_changed) = self.content.process(content, old_blocks)?;
+        let known = old_blocks.keys().cloned().collect::<Vec<_>>();
+        self.overrides = config::magic_comments(content);
+        let (mut nodes, strcts, folds, any_changed) = self.content.process(content, old_blocks, &self.document_config())?;
+
+        // new snippets follow the zoom of all images and share the results of earlier renders
+        for node in nodes.values_mut().filter(|x| !known.contains(&x.id)) {
+            node.zoom = self.zoom;
+            node.memory = Some(self.memory.clone());
+        }
 
         self.strcts = strcts;
         self.blocks = nodes;
 
+        if self.config.mode == PreviewMode::Document {
+            self.render_pages();
+        }
+
         let ret = RedrawState {
             should_redraw: any_changed,
             update_folding: Some(folds),
@@ -283,15 +1418,254 @@ impl Render {
         Ok(json::to_string(&ret))
     }
 
+    /// Replace the changed lines of the buffer and update the nodes like `update_content`
+    ///
+    /// Sent while typing, so that the editor doesn't have to pass the whole buffer on every pause.
+    /// Nodes are identified by the hash of their content, only those whose content changed are
+    /// rendered again.
+    pub fn update_region(&mut self, region: &str) -> Result<String> {
+        let region: Region = json::from_str(region)
+            .ok()
+            .filter(|x: &Region| x.start <= x.end && x.end <= self.lines.len())
+            .ok_or_else(|| Error::InvalidOption("region".to_string(), region.to_string()))?;
+
+        let mut lines = mem::take(&mut self.lines);
+        lines.splice(region.start..region.end, region.lines);
+
+        self.update_content(&lines.join("\n"))
+    }
+
+    /// Render new blocks in the background and evict removed ones, called on save
+    pub fn sync_document(&mut self, _: &str) -> Result<String> {
+        let blocks = &mut self.blocks;
+        // ids of inline math carry the occurrence, several nodes can share a cache key
+        let mut keys = blocks.values()
+            .filter(|node| node.is_cached())
+            .map(|node| node.key())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+
+        // evictions are logged along with the renders
+        let log = self.config.stats_log.clone();
+        self.document.on_evict(Box::new(move |entry| {
+            if !log.is_empty() {
+                stats::record_eviction(&log, entry);
+            }
+        }));
+
+        let mut report = self.document.sync(keys.iter().map(|x| x.as_str()), |key| {
+            let mut rendered = false;
+            for node in blocks.values_mut().filter(|node| node.key() == key) {
+                // artifacts found in the cache are only loaded
+                rendered |= !node.is_stored();
+                node.prewarm();
+            }
+
+            rendered
+        })?;
+
+        if let Some(limit) = self.config.cache_size_limit {
+            // the latex sources are shared by the SVGs of all colors and zoom levels
+            let keep = blocks.values()
+                .filter(|node| node.is_cached())
+                .flat_map(|node| node.cache_keys())
+                .collect();
+            report.collected = self.document.collect(limit * 1024 * 1024, &keep)?.into_iter()
+                .map(|x| x.key)
+                .collect();
+        }
+
+        // results of evicted entries point to removed files now
+        let mut memory = self.memory.lock().unwrap();
+        for key in report.evicted.iter().chain(&report.collected) {
+            memory.remove(key);
+        }
+
+        Ok(json::to_string(&report))
+    }
+
+    /// Render all blocks of the document and write a manifest of the outputs to `dest`
+    pub fn write_manifest(&mut self, dest: &str) -> Result<String> {
+        let items = self.blocks.values()
+            .map(|node| {
+                let (content, kind) = node.content();
+                (content.to_string(), kind.clone())
+            })
+            .collect::<Vec<_>>();
+
+        batch::write_manifest(dest, &items, &self.document_config(), &self.memory)
+    }
+
+    /// Render all blocks of the document and stack the failed ones with their errors in one image
+    pub fn error_report(&mut self, _: &str) -> Result<String> {
+        let items = self.blocks.values()
+            .map(|node| {
+                let (content, kind) = node.content();
+                (content.to_string(), kind.clone())
+            })
+            .collect::<Vec<_>>();
+
+        let config = self.document_config();
+        let failures = batch::render_all(&items, &config, &self.memory).into_iter()
+            .zip(items)
+            .filter_map(|(res, (content, _))| res.err().map(|err| (content, err)))
+            .collect::<Vec<_>>();
+
+        let path = batch::render_error_report(&failures, &config)?;
+
+        Ok(json::to_string(&path.to_string_lossy().to_string()))
+    }
+
+    /// Kill the latex, dvisvgm and gnuplot processes of running jobs
+    pub fn cancel_jobs(&mut self, _: &str) -> Result<()> {
+        runner::cancel_running();
+
+        Ok(())
+    }
+
+    /// Ids of the blocks whose background job finished since the last call
+    ///
+    /// The editor polls this to redraw as soon as an image is ready, without blocking on renders,
+    /// and stops once no job is pending.
+    pub fn finished_jobs(&mut self, _: &str) -> Result<String> {
+        // checked first, jobs finishing in between are taken below
+        let pending = !jobs::queue().is_idle();
+
+        // report in document order, independent of which compilation happened to finish first
+        let mut finished = jobs::queue().take_finished().into_iter()
+            .filter_map(|id| self.blocks.get(&id).map(|node| ((node.range, node.column), id)))
+            .collect::<Vec<_>>();
+        finished.sort();
+        finished.dedup();
+
+        let ids = finished.into_iter().map(|(_, id)| id).collect::<Vec<_>>();
+
+        Ok(json::to_string(&FinishedJobs { ids, pending }))
+    }
+
+    /// Errors of the nodes which failed to render, at the lines of the buffer they point at
+    pub fn diagnostics(&mut self, _: &str) -> Result<String> {
+        let mut diagnostics = self.blocks.values()
+            .filter_map(|node| node.diagnostic())
+            .collect::<Vec<Diagnostic>>();
+        diagnostics.sort_by_key(|x| (x.line, x.column));
+
+        Ok(json::to_string(&diagnostics))
+    }
+
+    /// Filler lines needed below the rendered images of a window, if `virtual_lines` is enabled
+    ///
+    /// Images are as high as their rows scaled by the zoom, rounded to whole pixels and, with
+    /// `snap_to_cells`, padded to whole cells. The count is what they take more than their lines.
+    pub fn virtual_lines(&mut self, winid: &str) -> Result<String> {
+        let metadata = winid.trim().parse::<u64>().ok()
+            .and_then(|x| self.windows.get(&x))
+            .map(|x| &x.metadata)
+            .ok_or_else(|| Error::InvalidOption("winid".to_string(), winid.to_string()))?;
+
+        if !self.config.virtual_lines {
+            return Ok(json::to_string(&Vec::<VirtualLines>::new()));
+        }
+
+        let cell = utils::char_pixel_height();
+        let fillers = self.blocks.values()
+            .filter(|node| node.is_rendered())
+            .filter_map(|node| {
+                let (lines, rows) = (node.range.1 - node.range.0, node.rows(metadata));
+                let mut height = utils::rows_pixel_height(rows);
+                if self.config.snap_to_cells {
+                    height = height.div_ceil(cell) * cell;
+                }
+                let needed = (rows..).find(|x| utils::rows_pixel_height(*x) >= height).unwrap();
+
+                (needed > lines).then(|| VirtualLines { id: node.id.clone(), line: node.range.0 + lines, count: needed - lines })
+            })
+            .collect::<Vec<_>>();
+
+        Ok(json::to_string(&fillers))
+    }
+
+    /// Name and version of the TeX distribution, `null` if none was found
+    pub fn tex_distribution(&mut self, _: &str) -> Result<String> {
+        let distribution = utils::detect_tex_distribution(&self.document_config()).map(|x| x.to_string());
+
+        Ok(json::to_string(&distribution))
+    }
+
+    /// Lines of all nodes, for anchoring them in the buffer
+    pub fn anchors(&mut self, _: &str) -> Result<String> {
+        let anchors = self.blocks.values()
+            .map(|node| Anchor { id: node.id.clone(), line: node.range.0 })
+            .collect::<Vec<_>>();
+
+        Ok(json::to_string(&anchors))
+    }
+
+    /// Move nodes to the lines their anchors were moved to by edits
+    ///
+    /// The content isn't parsed again, nodes keep their height. This lets images follow text
+    /// inserted or deleted above them, until the next `update_content` catches up.
+    pub fn update_anchors(&mut self, anchors: &str) -> Result<usize> {
+        let anchors: Vec<Anchor> = json::from_str(anchors)
+            .map_err(|_| Error::InvalidOption("anchors".to_string(), anchors.to_string()))?;
+
+        let mut any_moved = false;
+        for anchor in anchors {
+            if let Some(node) = self.blocks.get_mut(&anchor.id) {
+                if node.range.0 != anchor.line {
+                    node.range = (anchor.line, anchor.line + node.range.1 - node.range.0);
+                    any_moved = true;
+                }
+            }
+        }
+
+        if any_moved {
+            let blocks = &self.blocks;
+            self.strcts = mem::take(&mut self.strcts).into_iter()
+                .map(|(key, item)| match item {
+                    FoldInner::Node(ref id) => ((blocks[id].range.0, blocks[id].column), item),
+                    FoldInner::Fold(_) => (key, item),
+                })
+                .collect();
+        }
+
+        Ok(if any_moved { 1 } else { 0 })
+    }
+
+    /// Check that the cached artifact of a snippet was rendered from it
+    pub fn verify_entry(&mut self, entry: &str) -> Result<String> {
+        let config = self.document_config();
+        let entry: Entry = json::from_str(entry)
+            .map_err(|_| Error::InvalidOption("entry".to_string(), entry.to_string()))?;
+        let kind = ContentType::from_name(&entry.kind, &config)?;
+
+        let valid = cache::verify_entry(&kind, &entry.content, &config)?;
+
+        Ok(json::to_string(&valid))
+    }
+
+    pub fn display_id(&mut self, content: &str) -> Result<String> {
+        let id = cache::display_id(&ContentType::Math.key(content, &self.document_config()))?;
+
+        Ok(json::to_string(&id))
+    }
+
     pub fn set_folds(&mut self, folds: &str) -> Result<usize> {
-        let folds: Folds = json::from_str(folds).unwrap();
+        let folds: Folds = json::from_str(folds)
+            .map_err(|_| Error::InvalidOption("folds".to_string(), folds.to_string()))?;
         let mut folds = folds.into_iter();
 
+        // the editor clears the screen before updating folds, everything has to be drawn again
+        for window in self.windows.values_mut() {
+            window.views.clear();
+        }
+
         let mut any_changed = false;
 
         // loop through structs and update fold information
         let mut end_fold: Option<usize> = None;
-        for (line, elm) in &mut self.strcts {
+        for ((line, _), elm) in &mut self.strcts {
             if let Some(tmp) = &end_fold {
                 if tmp < line {
                     end_fold = None;
@@ -319,10 +1693,12 @@ impl Render {
                         any_changed = true;
                     }
                 },
-                FoldInner::Node((_, ref mut view)) => {
+                FoldInner::Node(id) => {
                     if let Some(tmp) = &end_fold {
                         if line < tmp {
-                            *view = NodeView::Hidden;
+                            for window in self.windows.values_mut() {
+                                window.views.remove(id);
+                            }
                         }
                     }
                 }
@@ -332,3 +1708,56 @@ impl Render {
         Ok(if any_changed { 1 } else { 0 })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ansi_lines_are_placed() {
+        let placed = Ansi.place(b"\x1b[0m \x1b[0m\n\x1b[0m \x1b[0m\n", 3, 7);
+
+        assert_eq!(placed, b"\x1b[s\x1b[3;7H\x1b[0m \x1b[0m\x1b[4;7H\x1b[0m \x1b[0m\x1b[u");
+        assert_eq!(BackendKind::from_str("ansi"), Ok(BackendKind::Ansi));
+    }
+
+    #[test]
+    fn options_reach_rendered_blocks() {
+        test_art_path();
+        let mut render = Render::new();
+        render.update_content("text\n```math\nx\n\n\n\n```\n").unwrap();
+
+        let metadata = Metadata {
+            winid: 1, file_range: (1, 40), viewport: (40, 80), cursor: 1, winpos: (1, 1), char_height: 0,
+            closed_folds: Vec::new(), virtual_lines: None,
+        };
+        let rows = |render: &Render| render.blocks.values().map(|x| x.rows(&metadata)).collect::<Vec<_>>();
+        assert_eq!(rows(&render), [5]);
+
+        render.update_config(r#"{"max_rows": "2"}"#).unwrap();
+        assert_eq!(rows(&render), [2]);
+    }
+
+    #[test]
+    fn cursor_hits_inline_math_between_delimiters() {
+        test_art_path();
+        let mut render = Render::new();
+        render.update_config(r#"{"inline_math": "true"}"#).unwrap();
+        render.update_content("a $x$ b \\(y\\) c\n").unwrap();
+
+        let hit = |col| render.node_at(&CursorPos { winid: 1, line: 1, col, screen: (1, 1) })
+            .map(|id| render.blocks[&id].content().0.to_string());
+        assert_eq!(hit(4).as_deref(), Some("x"));
+        assert_eq!(hit(5), None);
+        assert_eq!(hit(12).as_deref(), Some("y"));
+        assert_eq!(hit(13), None);
+    }
+
+    #[test]
+    fn malformed_window_state_is_an_error() {
+        let mut render = Render::new();
+
+        assert!(matches!(render.update_metadata("[{"), Err(Error::InvalidOption(..))));
+        assert!(matches!(render.set_folds("folds"), Err(Error::InvalidOption(..))));
+    }
+}
@@ -0,0 +1,121 @@
+use std::io::{self, BufReader};
+use std::sync::{Arc, Mutex};
+
+use crate::error::Result;
+use crate::jobs;
+use crate::msgpack::{self, Value};
+use crate::render::{self, Render};
+
+/// Terminal the editor runs in, the images are written there
+#[cfg(unix)]
+const TERMINAL: &str = "/dev/tty";
+#[cfg(windows)]
+const TERMINAL: &str = "CONOUT$";
+
+/// Message types of msgpack-rpc
+const REQUEST: i64 = 0;
+const RESPONSE: i64 = 1;
+const NOTIFICATION: i64 = 2;
+
+/// Serve msgpack-rpc on the standard input and output until the input closes
+///
+/// Neovim starts the binary as job with `rpc = true`. Requests and notifications carry the names of
+/// the functions exported to Vim, with the same single string argument, and are answered with the
+/// same `{"ok": ...}` or `{"err": ...}` JSON. Images are written to the controlling terminal, the
+/// standard output belongs to the channel. Once jobs finish, the autocommand `User
+/// GraphicalPreviewReady` is triggered with their ids as `data`.
+pub fn serve() -> io::Result<()> {
+    let tty = std::fs::OpenOptions::new().write(true).open(TERMINAL)?;
+    render::set_output(tty);
+
+    let stdout = Arc::new(Mutex::new(io::stdout()));
+    let mut stdin = BufReader::new(io::stdin());
+    let mut render = Render::new();
+
+    let notifier = stdout.clone();
+    jobs::queue().on_finished(move |id| {
+        let data = Value::Map(vec![
+            (Value::Str("pattern".to_string()), Value::Str("GraphicalPreviewReady".to_string())),
+            (Value::Str("data".to_string()), Value::Array(vec![Value::Str(id.to_string())])),
+        ]);
+        let msg = Value::Array(vec![
+            Value::Int(NOTIFICATION),
+            Value::Str("nvim_exec_autocmds".to_string()),
+            Value::Array(vec![Value::Str("User".to_string()), data]),
+        ]);
+
+        // the editor is gone if this fails, the input closes soon after
+        let _ = msgpack::write(&mut *notifier.lock().unwrap(), &msg);
+    });
+
+    loop {
+        let msg = match msgpack::read(&mut stdin) {
+            Ok(Value::Array(msg)) => msg,
+            Ok(_) => continue,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        match msg.first().and_then(|x| x.as_int()) {
+            Some(REQUEST) if msg.len() == 4 => {
+                let reply = dispatch(&mut render, &msg[2], &msg[3]);
+                let (err, res) = match reply {
+                    Some(reply) => (Value::Nil, Value::Str(reply)),
+                    None => (Value::Str(format!("unknown method {}", msg[2].as_str().unwrap_or("?"))), Value::Nil),
+                };
+
+                let response = Value::Array(vec![Value::Int(RESPONSE), msg[1].clone(), err, res]);
+                msgpack::write(&mut *stdout.lock().unwrap(), &response)?;
+            },
+            Some(NOTIFICATION) if msg.len() == 3 => {
+                dispatch(&mut render, &msg[1], &msg[2]);
+            },
+            _ => {},
+        }
+    }
+}
+
+/// JSON reply in the format returned to Vim
+fn reply<T: ToString>(res: Result<T>) -> String {
+    crate::result_to_cstring(res).into_string().unwrap()
+}
+
+/// Reply of an export, those returning nothing reply with a JSON null
+macro_rules! reply_of {
+    (String, $res:expr) => { reply($res) };
+    ((), $res:expr) => { reply($res.map(|_| "null")) };
+}
+
+macro_rules! dispatch_exports {
+    ($($fn_name:ident: $ret:tt),*) => {
+        /// Call the method named like the exported function, `None` if there is none
+        fn dispatch(render: &mut Render, method: &Value, params: &Value) -> Option<String> {
+            let arg = match params {
+                Value::Array(params) => params.first().and_then(|x| x.as_str()).unwrap_or(""),
+                _ => "",
+            };
+
+            Some(match method.as_str()? {
+                $(stringify!($fn_name) => reply_of!($ret, render.$fn_name(arg)),)*
+                _ => return None,
+            })
+        }
+    };
+}
+
+for_each_export!(dispatch_exports);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn methods_are_the_exports() {
+        let mut render = Render::new();
+        let call = |render: &mut Render, method: &str| dispatch(render, &Value::Str(method.to_string()), &Value::Array(Vec::new()));
+
+        assert_eq!(call(&mut render, "clear_all").as_deref(), Some(r#"{ "ok": null }"#));
+        assert_eq!(call(&mut render, "set_folds").as_deref(), Some(r#"{ "err": "invalid value  for option folds" }"#));
+        assert_eq!(call(&mut render, "serve"), None);
+    }
+}
@@ -0,0 +1,287 @@
+use std::io::Read;
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+
+/// Executes the external binaries
+///
+/// All subprocesses are spawned through a runner, so that they can be wrapped in a sandbox or
+/// replaced by a mock.
+pub trait CommandRunner: Send + Sync {
+    /// Run to completion with closed standard input, killing the process after `timeout`
+    ///
+    /// Closing the input makes an interactive prompt fail instead of waiting forever.
+    fn output(&self, cmd: &mut Command, timeout: Duration) -> Result<Output>;
+}
+
+/// Bumped to cancel the processes running at that moment
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Kill all processes waited for by `output`, for example when the editor quits
+pub fn cancel_running() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Spawns the binaries directly
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn output(&self, cmd: &mut Command, timeout: Duration) -> Result<Output> {
+        let mut child = new_group(cmd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| spawn_failed(cmd, err))?;
+
+        // drain the pipes in the background, a full pipe would block the child
+        let stdout = drain(child.stdout.take());
+        let stderr = drain(child.stderr.take());
+
+        let start = Instant::now();
+        let generation = GENERATION.load(Ordering::SeqCst);
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(Error::Io)? {
+                break status;
+            }
+
+            if GENERATION.load(Ordering::SeqCst) != generation {
+                kill(&mut child);
+
+                return Err(Error::Cancelled(cmd.get_program().to_string_lossy().to_string()));
+            }
+
+            if start.elapsed() > timeout {
+                kill(&mut child);
+
+                // latex logs to the standard output, dvisvgm and gnuplot to the error output
+                let name = cmd.get_program().to_string_lossy().to_string();
+                let mut log = stdout.join().unwrap_or_default();
+                log.extend(stderr.join().unwrap_or_default());
+                let log = String::from_utf8_lossy(&log).to_string();
+
+                return Err(Error::Timeout(name, log));
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        Ok(Output {
+            status,
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        })
+    }
+}
+
+/// Start the process in a group of its own, which `kill` ends as a whole
+#[cfg(unix)]
+fn new_group(cmd: &mut Command) -> &mut Command {
+    use std::os::unix::process::CommandExt;
+
+    cmd.process_group(0)
+}
+
+#[cfg(windows)]
+fn new_group(cmd: &mut Command) -> &mut Command {
+    cmd
+}
+
+/// Kill a process and everything it started, like latex run by a wrapper script
+///
+/// Children left running would hold the output pipes open and block draining them.
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    use nix::sys::signal::{killpg, Signal};
+    use nix::unistd::Pid;
+
+    if killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL).is_err() {
+        let _ = child.kill();
+    }
+    let _ = child.wait();
+}
+
+#[cfg(windows)]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Error of a binary which couldn't be started, for example as it isn't executable
+fn spawn_failed(cmd: &Command, err: std::io::Error) -> Error {
+    Error::SpawnFailed(cmd.get_program().to_string_lossy().to_string(), err)
+}
+
+/// Prefixes every command with a wrapper, for example `bwrap` or `firejail`
+pub struct SandboxRunner {
+    prefix: Vec<String>,
+}
+
+impl SandboxRunner {
+    /// Parse the wrapper from a whitespace separated command line
+    pub fn new(prefix: &str) -> SandboxRunner {
+        SandboxRunner {
+            prefix: prefix.split_whitespace().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    fn wrap(&self, cmd: &Command) -> Command {
+        let mut wrapped = Command::new(&self.prefix[0]);
+        wrapped.args(&self.prefix[1..])
+            .arg(cmd.get_program())
+            .args(cmd.get_args());
+
+        if let Some(dir) = cmd.get_current_dir() {
+            wrapped.current_dir(dir);
+        }
+        for (key, value) in cmd.get_envs() {
+            match value {
+                Some(value) => wrapped.env(key, value),
+                None => wrapped.env_remove(key),
+            };
+        }
+
+        wrapped
+    }
+}
+
+impl CommandRunner for SandboxRunner {
+    fn output(&self, cmd: &mut Command, timeout: Duration) -> Result<Output> {
+        SystemRunner.output(&mut self.wrap(cmd), timeout)
+    }
+}
+
+/// Caps the number of processes run by all renders together
+///
+/// The limit is shared crate-wide, so that overlapping batches, prewarming and single renders
+/// cannot thrash the machine. Waiting for a slot does not count towards the timeout.
+pub struct LimitedRunner {
+    inner: Arc<dyn CommandRunner>,
+    limit: usize,
+}
+
+static RUNNING: Mutex<usize> = Mutex::new(0);
+static RELEASED: Condvar = Condvar::new();
+
+/// Slot of a running process, freed on drop
+struct Permit;
+
+impl Permit {
+    fn acquire(limit: usize) -> Permit {
+        let mut running = RUNNING.lock().unwrap();
+        while *running >= limit.max(1) {
+            running = RELEASED.wait(running).unwrap();
+        }
+        *running += 1;
+
+        Permit
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *RUNNING.lock().unwrap() -= 1;
+        RELEASED.notify_all();
+    }
+}
+
+impl CommandRunner for LimitedRunner {
+    fn output(&self, cmd: &mut Command, timeout: Duration) -> Result<Output> {
+        let _permit = Permit::acquire(self.limit);
+
+        self.inner.output(cmd, timeout)
+    }
+}
+
+static OVERRIDE: RwLock<Option<Arc<dyn CommandRunner>>> = RwLock::new(None);
+
+/// Replace the runner for all subsequent renders, `None` restores the configured one
+#[cfg(test)]
+pub fn set_runner(runner: Option<Arc<dyn CommandRunner>>) {
+    *OVERRIDE.write().unwrap() = runner;
+}
+
+/// Runner to use with the given configuration
+pub fn runner(config: &Config) -> Arc<dyn CommandRunner> {
+    let inner: Arc<dyn CommandRunner> = if let Some(runner) = &*OVERRIDE.read().unwrap() {
+        runner.clone()
+    } else if config.sandbox.trim().is_empty() {
+        Arc::new(SystemRunner)
+    } else {
+        Arc::new(SandboxRunner::new(&config.sandbox))
+    };
+
+    Arc::new(LimitedRunner { inner, limit: config.max_processes })
+}
+
+/// Standard output of `<binary> --version`, `None` if it can't be run
+pub fn version(runner: &dyn CommandRunner, binary: &str) -> Option<String> {
+    let out = runner.output(Command::new(binary).arg("--version"), Duration::from_secs(5)).ok()?;
+
+    Some(String::from_utf8_lossy(&out.stdout).to_string())
+}
+
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// Answers `--version` with a fixed output for some binaries, as if no others were installed
+///
+/// All other commands are run, so that renders of other tests work while it replaces the runner.
+#[cfg(all(test, unix))]
+pub struct VersionRunner(pub Vec<(&'static str, &'static str)>);
+
+#[cfg(all(test, unix))]
+impl CommandRunner for VersionRunner {
+    fn output(&self, cmd: &mut Command, timeout: Duration) -> Result<Output> {
+        use std::os::unix::process::ExitStatusExt;
+
+        if !cmd.get_args().eq(["--version"]) {
+            return SystemRunner.output(cmd, timeout);
+        }
+
+        let program = cmd.get_program().to_string_lossy().to_string();
+        match self.0.iter().find(|x| x.0 == program) {
+            Some((_, version)) => Ok(Output { status: std::process::ExitStatus::from_raw(0), stdout: version.as_bytes().to_vec(), stderr: Vec::new() }),
+            None => Err(spawn_failed(cmd, std::io::ErrorKind::NotFound.into())),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runners_are_replaced() {
+        set_runner(Some(Arc::new(VersionRunner(vec![("latex", "pdfTeX 3.141592653 (TeX Live 2023)\n")]))));
+        let version = version(&*runner(&Config::new()), "latex");
+        set_runner(None);
+
+        assert_eq!(version.as_deref(), Some("pdfTeX 3.141592653 (TeX Live 2023)\n"));
+    }
+
+    #[test]
+    fn timeout_kills_children() {
+        let mut cmd = Command::new("sh");
+        // the sleeping child inherits the output pipe, draining it waits for its end
+        cmd.arg("-c").arg("echo started; echo failing >&2; sleep 30 & sleep 30");
+
+        let start = Instant::now();
+        let res = SystemRunner.output(&mut cmd, Duration::from_millis(200));
+
+        assert!(matches!(res, Err(Error::Timeout(_, ref log)) if log == "started\nfailing\n"), "{:?}", res.err());
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,136 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::config::Config;
+use crate::content::ContentType;
+use crate::error::{Error, Result};
+use crate::render::art_path;
+use crate::{runner, utils};
+
+/// Languages whose figure is saved by a wrapper around the code of the fence
+const BUILTIN: [&str; 3] = ["python", "r", "julia"];
+
+/// Whether fences of this name are run as scripts, built-in or configured in `script_commands`
+pub fn is_script(fence: &str, config: &Config) -> bool {
+    BUILTIN.contains(&fence) || config.script_commands.iter().any(|x| x.fence == fence)
+}
+
+/// Image written by the script of a key, the PNG if the script produced one instead of a SVG
+pub fn output_path(key: &str) -> PathBuf {
+    let svg = art_path().join(key).with_extension("svg");
+    let png = svg.with_extension("png");
+
+    if !svg.exists() && png.exists() { png } else { svg }
+}
+
+/// Source saving the current figure of the built-in languages to `svg`, other code is kept as is
+fn wrap(fence: &str, content: &str, svg: &str) -> String {
+    let content = content.trim_end();
+
+    match fence {
+        // names of the epilogue are private, so that they don't clash with the script
+        "python" => format!(
+            "{}\n\nimport sys as _sys\nimport matplotlib.pyplot as _plt\nif not _plt.get_fignums():\n    _sys.exit('no figure was drawn')\n_plt.savefig('{}', bbox_inches='tight', transparent=True)\n",
+            content, svg
+        ),
+        "r" => format!("svg('{}', bg = 'transparent')\n{}\ninvisible(dev.off())\n", svg, content),
+        "julia" => format!("{}\n\nsavefig(\"{}\")\n", content, svg),
+        _ => format!("{}\n", content),
+    }
+}
+
+/// Command line running a script of the fence, before the placeholders are replaced
+fn command(fence: &str, config: &Config) -> Vec<String> {
+    if let Some(command) = config.script_commands.iter().find(|x| x.fence == fence) {
+        return command.args.clone();
+    }
+
+    let interpreter = match fence {
+        "python" => config.python.as_str(),
+        "r" => "Rscript",
+        _ => fence,
+    };
+
+    vec![interpreter.to_string(), "{script}".to_string()]
+}
+
+/// Run the code of a script fence and return the image it wrote
+///
+/// The command gets the placeholders `{script}`, `{svg}` and `{png}` replaced by the file names in
+/// the cache, the script sees the latter in `PREVIEW_SVG` and `PREVIEW_PNG`. Python runs with the
+/// non-interactive svg backend of matplotlib, so that `plt.show()` returns at once. Scripts execute
+/// arbitrary code and only run with the trusted option `run_scripts`.
+pub fn run(fence: &str, content: &str, config: &Config) -> Result<PathBuf> {
+    if !config.run_scripts {
+        return Err(Error::ScriptsDisabled(fence.to_string()));
+    }
+
+    let key = config.key(&ContentType::Script(fence.to_string()).tagged(content));
+    let path = art_path().join(&key).with_extension("svg");
+    let name = |ext: &str| format!("{}.{}", key, ext);
+
+    std::fs::write(path.with_extension("script"), wrap(fence, content, &name("svg"))).map_err(Error::Io)?;
+
+    let args = command(fence, config).into_iter()
+        .map(|x| x.replace("{script}", &name("script")).replace("{svg}", &name("svg")).replace("{png}", &name("png")))
+        .collect::<Vec<_>>();
+    let binary = which::which(&args[0]).map_err(Error::BinaryNotFound)?;
+
+    let cmd = runner::runner(config).output(Command::new(binary)
+        .current_dir(art_path())
+        .env("MPLBACKEND", "svg")
+        .env("PREVIEW_SVG", name("svg"))
+        .env("PREVIEW_PNG", name("png"))
+        .args(&args[1..]), config.timeout_plot)?;
+
+    let output = output_path(&key);
+    if !cmd.status.success() || !output.exists() {
+        // the script may have written either image before failing
+        for ext in ["svg", "png"] {
+            let _ = std::fs::remove_file(path.with_extension(ext));
+        }
+        let log = format!("{}{}", String::from_utf8_lossy(&cmd.stdout), String::from_utf8_lossy(&cmd.stderr));
+        return Err(Error::ScriptFailed(args[0].clone(), log));
+    }
+
+    if output == path {
+        utils::finish_svg(&path, config)?;
+    }
+
+    Ok(output)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::config::ScriptCommand;
+    use crate::render;
+
+    #[test]
+    fn python_saves_the_figure() {
+        let source = wrap("python", "plt.plot([1, 2])\n", "out.svg");
+
+        assert!(source.starts_with("plt.plot([1, 2])\n\nimport sys as _sys\n"));
+        assert!(source.ends_with("_plt.savefig('out.svg', bbox_inches='tight', transparent=True)\n"));
+        assert_eq!(wrap("ruby", "puts 1\n\n", "out.svg"), "puts 1\n");
+    }
+
+    #[test]
+    fn failures_leave_no_image() {
+        render::test_art_path();
+        let mut config = Config::new();
+        config.run_scripts = true;
+        config.script_commands = vec![ScriptCommand {
+            fence: "fail".to_string(),
+            args: ["sh", "-c", "echo partial > {svg}; echo partial > {png}; echo broken >&2; exit 3"]
+                .map(String::from).to_vec(),
+        }];
+
+        let err = run("fail", "content", &config).unwrap_err();
+        assert!(matches!(err, Error::ScriptFailed(ref binary, ref log) if binary == "sh" && log == "broken\n"));
+
+        let key = config.key(&ContentType::Script("fail".to_string()).tagged("content"));
+        assert!(!art_path().join(&key).with_extension("svg").exists());
+        assert!(!art_path().join(&key).with_extension("png").exists());
+    }
+}
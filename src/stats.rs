@@ -0,0 +1,105 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use miniserde::{json, Serialize};
+
+use crate::cache::CacheEntry;
+use crate::content::{CacheStatus, RenderInfo};
+use crate::error::Result;
+
+/// Line of the statistics log, describing a single render
+#[derive(Serialize)]
+struct Record<'a> {
+    /// seconds since the unix epoch
+    timestamp: f64,
+    key: &'a str,
+    duration_ms: f64,
+    /// `hit`, `miss`, `error` or `evicted`
+    status: &'static str,
+    error: Option<String>,
+}
+
+/// Serializes appends of concurrent renders, so that lines never interleave
+static LOG: Mutex<()> = Mutex::new(());
+
+/// Append a JSON line describing a render to the log at `path`
+///
+/// Failing to write the log never fails the render, the record is dropped instead.
+pub fn record(path: &str, key: &str, duration: Duration, res: &Result<RenderInfo>) {
+    let (status, error) = match res {
+        Ok(info) if info.status == CacheStatus::Hit => ("hit", None),
+        Ok(_) => ("miss", None),
+        Err(err) => ("error", Some(err.to_string())),
+    };
+
+    append(path, &Record {
+        timestamp: now(),
+        key,
+        duration_ms: duration.as_secs_f64() * 1000.0,
+        status,
+        error,
+    });
+}
+
+/// Append a JSON line for an entry evicted from the cache, with a duration of zero
+pub fn record_eviction(path: &str, entry: &CacheEntry) {
+    append(path, &Record {
+        timestamp: now(),
+        key: &entry.key,
+        duration_ms: 0.0,
+        status: "evicted",
+        error: None,
+    });
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0.0, |x| x.as_secs_f64())
+}
+
+fn append(path: &str, record: &Record) {
+    let mut line = json::to_string(record);
+    line.push('\n');
+
+    let _lock = LOG.lock().unwrap();
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+        let _ = file.write_all(line.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::error::Error;
+    use crate::render::{self, art_path};
+
+    #[test]
+    fn records_are_appended_as_lines() {
+        render::test_art_path();
+        let path = art_path().join("stats.jsonl");
+        let _ = fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+
+        record(path, "failed", Duration::from_millis(1500), &Err(Error::Cancelled("latex".to_string())));
+        record_eviction(path, &CacheEntry {
+            key: "evicted".to_string(),
+            files: Vec::new(),
+            size: 0,
+            modified: SystemTime::UNIX_EPOCH,
+            accessed: SystemTime::UNIX_EPOCH,
+        });
+
+        // the timestamp differs between runs
+        let log = fs::read_to_string(path).unwrap();
+        let lines = log.lines().map(|x| x.split_once(',').unwrap().1).collect::<Vec<_>>();
+        assert_eq!(lines, [
+            r#""key":"failed","duration_ms":1500.0,"status":"error","error":"latex was cancelled"}"#,
+            r#""key":"evicted","duration_ms":0.0,"status":"evicted","error":null}"#,
+        ]);
+        assert!(log.lines().all(|x| x.starts_with(r#"{"timestamp":"#)));
+    }
+}
@@ -0,0 +1,349 @@
+use std::fs;
+use std::path::Path;
+use std::result;
+use std::sync::{Arc, OnceLock};
+
+use image::RgbaImage;
+use regex::Regex;
+use resvg::{tiny_skia, usvg};
+
+use crate::config::{Backdrop, Config, FontMode, FrameStyle, Transparency};
+use crate::error::{Error, Result};
+
+/// Apply all SVG level options to a freshly generated file
+pub fn postprocess(path: &Path, config: &Config) -> Result<()> {
+    let original = fs::read_to_string(path).map_err(Error::Io)?;
+    let mut svg = original.clone();
+
+    if let Some(color) = config.foreground() {
+        svg = recolor(&svg, color)?;
+    }
+
+    if config.transparency == Transparency::Flatten {
+        svg = flatten_transparency(&svg);
+    }
+
+    if config.rotation_deg != 0.0 {
+        svg = rotate(&svg, config.rotation_deg)?;
+    }
+
+    if let Some(frame) = &config.frame {
+        svg = add_frame(&svg, frame)?;
+    }
+
+    if !config.background_image.is_empty() {
+        svg = add_background_image(&svg, Path::new(&config.background_image))?;
+    }
+
+    if let Some(backdrop) = &config.backdrop {
+        svg = add_backdrop(&svg, backdrop)?;
+    }
+
+    // a transparent image never gets a background
+    let background = if config.transparent { "" } else { config.background.as_str() };
+
+    if let Some((width, height)) = config.canvas {
+        svg = place_on_canvas(&svg, width, height, background)?;
+    } else if !background.is_empty() {
+        let (x, y, width, height) = view_box(&svg)?;
+        svg = insert_rect(&svg, (x, y, width, height), background)?;
+    }
+
+    // rendering hints are honoured by resvg, librsvg and the internal renderer of imagemagick
+    if !config.antialias {
+        svg = set_root_attr(&svg, "shape-rendering", "crispEdges")?;
+        svg = set_root_attr(&svg, "text-rendering", "optimizeSpeed")?;
+    }
+
+    // leave the file untouched with default options
+    if svg != original {
+        fs::write(path, svg).map_err(Error::Io)?;
+    }
+
+    Ok(())
+}
+
+/// Whether the document has no visible content
+///
+/// This is the case for a degenerated view box or if no graphic element is present at all.
+pub fn is_empty(svg: &str) -> bool {
+    let graphics = Regex::new(r"<(path|use|rect|circle|ellipse|line|polyline|polygon|text|image)\b").unwrap();
+
+    match view_box(svg) {
+        Ok((_, _, width, height)) if width > 0.0 && height > 0.0 => !graphics.is_match(svg),
+        _ => true,
+    }
+}
+
+/// Replace group opacity by per element fill and stroke opacity
+///
+/// Rasterizers have to composite a group with `opacity` offscreen, which many get wrong. The fill
+/// and stroke opacity properties are inherited by the children instead and applied one by one.
+pub fn flatten_transparency(svg: &str) -> String {
+    let group_opacity = Regex::new(r#"(<g\b[^>]*?)\sopacity=['"]([^'"]*)['"]"#).unwrap();
+
+    group_opacity
+        .replace_all(svg, "$1 fill-opacity='$2' stroke-opacity='$2'")
+        .into_owned()
+}
+
+/// Paint black content in another color, for dark color schemes
+///
+/// Elements without a color inherit the fill of the root, explicit black is replaced. Other
+/// colors, for example of `\color` or gnuplot lines, are kept.
+pub fn recolor(svg: &str, color: &str) -> Result<String> {
+    let black = Regex::new(r#"\b(fill|stroke)=['"](#000|#000000|black)['"]"#).unwrap();
+    let svg = black.replace_all(svg, |x: &regex::Captures| format!("{}='{}'", &x[1], escape(color)));
+
+    set_root_attr(&svg, "fill", color)
+}
+
+/// Escape text for use in element content or attribute values
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
+
+/// Opening `<svg ...>` tag of a document
+fn root_tag(svg: &str) -> Result<regex::Match<'_>> {
+    Regex::new(r"<svg\b[^>]*>").unwrap()
+        .find(svg)
+        .ok_or_else(|| Error::InvalidSvg("missing svg element".to_string()))
+}
+
+/// Value of an attribute of the root element
+pub fn root_attr(svg: &str, name: &str) -> Result<Option<String>> {
+    let tag = root_tag(svg)?.as_str();
+    let attr = Regex::new(&format!(r#"\s{}=['"]([^'"]*)['"]"#, regex::escape(name))).unwrap();
+
+    Ok(attr.captures(tag).map(|x| x[1].to_string()))
+}
+
+/// Set or add an attribute of the root element, the value is escaped
+pub fn set_root_attr(svg: &str, name: &str, value: &str) -> Result<String> {
+    let tag = root_tag(svg)?;
+    let attr = Regex::new(&format!(r#"\s{}=['"][^'"]*['"]"#, regex::escape(name))).unwrap();
+    let replacement = format!(" {}='{}'", name, escape(value));
+
+    let new_tag = if attr.is_match(tag.as_str()) {
+        attr.replace(tag.as_str(), regex::NoExpand(&replacement)).into_owned()
+    } else {
+        let end = if tag.as_str().ends_with("/>") { 2 } else { 1 };
+        let (start, close) = tag.as_str().split_at(tag.as_str().len() - end);
+        format!("{}{}{}", start, replacement, close)
+    };
+
+    Ok(format!("{}{}{}", &svg[..tag.start()], new_tag, &svg[tag.end()..]))
+}
+
+/// Parsed `viewBox` of the root element as (x, y, width, height)
+pub fn view_box(svg: &str) -> Result<(f32, f32, f32, f32)> {
+    let view_box = root_attr(svg, "viewBox")?
+        .ok_or_else(|| Error::InvalidSvg("missing viewBox".to_string()))?;
+
+    let vals = view_box.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|x| !x.is_empty())
+        .map(|x| x.parse::<f32>())
+        .collect::<result::Result<Vec<_>, _>>()
+        .map_err(|_| Error::InvalidSvg(format!("invalid viewBox {}", view_box)))?;
+
+    match vals[..] {
+        [x, y, width, height] => Ok((x, y, width, height)),
+        _ => Err(Error::InvalidSvg(format!("invalid viewBox {}", view_box))),
+    }
+}
+
+/// Replace the view box and update the size accordingly, one user unit is one pt
+pub fn set_view_box(svg: &str, (x, y, width, height): (f32, f32, f32, f32)) -> Result<String> {
+    let svg = set_root_attr(svg, "viewBox", &format!("{} {} {} {}", x, y, width, height))?;
+    let svg = set_root_attr(&svg, "width", &format!("{}pt", width))?;
+
+    set_root_attr(&svg, "height", &format!("{}pt", height))
+}
+
+/// Insert an element as last child of the root, so that it is painted above the content
+fn insert_last(svg: &str, element: &str) -> Result<String> {
+    let end = svg.rfind("</svg>")
+        .ok_or_else(|| Error::InvalidSvg("missing closing svg tag".to_string()))?;
+
+    Ok(format!("{}{}{}", &svg[..end], element, &svg[end..]))
+}
+
+/// Draw a rectangle around the content, growing the view box to fit it
+pub fn add_frame(svg: &str, frame: &FrameStyle) -> Result<String> {
+    let (x, y, width, height) = view_box(svg)?;
+    let pad = frame.padding;
+    let rect = (x - pad, y - pad, width + 2.0 * pad, height + 2.0 * pad);
+
+    // the stroke is centered on the outline of the rectangle
+    let half = frame.width / 2.0;
+    let svg = set_view_box(svg, (rect.0 - half, rect.1 - half, rect.2 + frame.width, rect.3 + frame.width))?;
+
+    let element = format!(
+        "<rect x='{}' y='{}' width='{}' height='{}' fill='none' stroke='{}' stroke-width='{}'/>",
+        rect.0, rect.1, rect.2, rect.3, escape(&frame.color), frame.width
+    );
+
+    insert_last(&svg, &element)
+}
+
+/// Paint a rounded rectangle, filled with a gradient, behind the content
+pub fn add_backdrop(svg: &str, backdrop: &Backdrop) -> Result<String> {
+    let (x, y, width, height) = view_box(svg)?;
+
+    let element = format!(
+        "<defs><linearGradient id='backdrop-gradient' x1='0' y1='0' x2='0' y2='1'><stop offset='0' stop-color='{}'/><stop offset='1' stop-color='{}'/></linearGradient></defs><rect x='{}' y='{}' width='{}' height='{}' rx='{}' ry='{}' fill='url(#backdrop-gradient)' fill-opacity='{}'/>",
+        escape(&backdrop.top), escape(&backdrop.bottom), x, y, width, height, backdrop.radius, backdrop.radius, backdrop.opacity
+    );
+
+    insert_first(svg, &element)
+}
+
+/// Stretch an image over the content bounds, painted below the content
+///
+/// The image is linked by its absolute path, so that large files are not copied into every SVG.
+pub fn add_background_image(svg: &str, image: &Path) -> Result<String> {
+    let (x, y, width, height) = view_box(svg)?;
+    let image = fs::canonicalize(image)
+        .map_err(|_| Error::FileNotFound(image.to_path_buf()))?;
+
+    let element = format!(
+        "<image x='{}' y='{}' width='{}' height='{}' preserveAspectRatio='none' xlink:href='{}'/>",
+        x, y, width, height, escape(&image.to_string_lossy())
+    );
+
+    let svg = set_root_attr(svg, "xmlns:xlink", "http://www.w3.org/1999/xlink")?;
+
+    insert_first(&svg, &element)
+}
+
+/// Insert an element as first child of the root, so that it is painted below the content
+fn insert_first(svg: &str, element: &str) -> Result<String> {
+    let end = root_tag(svg)?.end();
+
+    Ok(format!("{}{}{}", &svg[..end], element, &svg[end..]))
+}
+
+fn insert_rect(svg: &str, (x, y, width, height): (f32, f32, f32, f32), fill: &str) -> Result<String> {
+    let rect = format!("<rect x='{}' y='{}' width='{}' height='{}' fill='{}'/>", x, y, width, height, escape(fill));
+
+    insert_first(svg, &rect)
+}
+
+/// Rotate the content counterclockwise around its center, growing the view box to fit it
+pub fn rotate(svg: &str, degrees: f32) -> Result<String> {
+    let (x, y, width, height) = view_box(svg)?;
+    let (cx, cy) = (x + width / 2.0, y + height / 2.0);
+
+    // bounding box of the rotated view box, the y axis of SVG points downwards
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let new_width = (width * cos).abs() + (height * sin).abs();
+    let new_height = (width * sin).abs() + (height * cos).abs();
+
+    let svg = set_view_box(svg, (cx - new_width / 2.0, cy - new_height / 2.0, new_width, new_height))?;
+    let svg = insert_first(&svg, &format!("<g transform='rotate({} {} {})'>", -degrees, cx, cy))?;
+
+    insert_last(&svg, "</g>")
+}
+
+/// Center the content in a fixed canvas of the given size in pt
+pub fn place_on_canvas(svg: &str, width: f32, height: f32, background: &str) -> Result<String> {
+    let (x, y, content_width, content_height) = view_box(svg)?;
+
+    // grow the view box evenly on all sides, dvisvgm sets one user unit to one pt
+    let view_box = (x - (width - content_width) / 2.0, y - (height - content_height) / 2.0, width, height);
+
+    let mut svg = set_view_box(svg, view_box)?;
+
+    if !background.is_empty() {
+        svg = insert_rect(&svg, view_box, background)?;
+    }
+
+    Ok(svg)
+}
+
+/// Whether resvg draws a file completely
+///
+/// Text of graphviz, PlantUML or plots is drawn with the system fonts, but the embedded fonts of
+/// `fonts = "woff2"` are ignored by resvg and left to imagemagick.
+pub fn is_native(config: &Config) -> bool {
+    config.fonts != FontMode::Woff2
+}
+
+/// Fonts installed on the system, loaded once since scanning them takes a while
+fn fonts() -> Arc<usvg::fontdb::Database> {
+    static FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
+
+    FONTS.get_or_init(|| {
+        let mut fonts = usvg::fontdb::Database::new();
+        fonts.load_system_fonts();
+
+        Arc::new(fonts)
+    }).clone()
+}
+
+/// Parse an SVG for rasterizing it with resvg
+pub fn load_tree(path: &Path) -> Result<usvg::Tree> {
+    let data = fs::read(path).map_err(Error::Io)?;
+    let options = usvg::Options { fontdb: fonts(), ..usvg::Options::default() };
+
+    usvg::Tree::from_data(&data, &options)
+        .map_err(|_| Error::InvalidImage(path.to_string_lossy().to_string()))
+}
+
+/// Rasterize an SVG at a height in pixels, keeping the aspect ratio, onto white unless transparent
+///
+/// Rendering at the final size keeps thin lines crisp, instead of scaling a 600 dpi raster down.
+/// resvg ignores embedded fonts, text is drawn with the system fonts.
+pub fn rasterize(tree: &usvg::Tree, height: usize, transparent: bool) -> RgbaImage {
+    let size = tree.size();
+    let height = height.max(1) as u32;
+    let scale = height as f32 / size.height();
+    let width = ((size.width() * scale).round() as u32).max(1);
+
+    let mut pixmap = match tiny_skia::Pixmap::new(width, height) {
+        Some(pixmap) => pixmap,
+        None => return RgbaImage::new(1, 1),
+    };
+    if !transparent {
+        pixmap.fill(tiny_skia::Color::WHITE);
+    }
+    resvg::render(tree, tiny_skia::Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+
+    // tiny-skia stores premultiplied alpha
+    let pixels = pixmap.pixels().iter()
+        .flat_map(|x| {
+            let x = x.demultiply();
+            [x.red(), x.green(), x.blue(), x.alpha()]
+        })
+        .collect();
+
+    RgbaImage::from_raw(width, height, pixels).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SVG: &str = "<svg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 10 20'><path fill='black'/></svg>";
+
+    #[test]
+    fn options_cannot_add_markup() {
+        let color = "red'/><image href='file:///etc/passwd";
+        let frame = FrameStyle { color: color.to_string(), width: 1.0, padding: 0.0 };
+        let backdrop = Backdrop { top: color.to_string(), bottom: color.to_string(), opacity: 1.0, radius: 0.0 };
+
+        for svg in [
+            recolor(SVG, color).unwrap(),
+            add_frame(SVG, &frame).unwrap(),
+            add_backdrop(SVG, &backdrop).unwrap(),
+            insert_rect(SVG, (0.0, 0.0, 10.0, 20.0), color).unwrap(),
+        ] {
+            assert!(!svg.contains("<image"), "{}", svg);
+            assert!(svg.contains("red&apos;/&gt;&lt;image"), "{}", svg);
+        }
+    }
+}
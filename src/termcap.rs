@@ -0,0 +1,183 @@
+use std::env;
+#[cfg(unix)]
+use std::fs::OpenOptions;
+#[cfg(unix)]
+use std::io::{Read, Write};
+use std::sync::OnceLock;
+
+#[cfg(unix)]
+use nix::sys::termios::{self, SetArg, SpecialCharacterIndices};
+
+use crate::render::BackendKind;
+use crate::tmux;
+
+/// Kitty graphics query of a single pixel, answered with `OK` by terminals understanding it
+const KITTY_QUERY: &[u8] = b"\x1b_Gi=31,s=1,v=1,a=q,t=d,f=24;AAAA\x1b\\";
+
+/// XTVERSION, answered with the name and version of the terminal
+const XTVERSION: &[u8] = b"\x1b[>0q";
+
+/// Primary device attributes, answered by virtually every terminal
+const DA1: &[u8] = b"\x1b[c";
+
+/// Longest answer read, terminals sending more are not waited for
+#[cfg(unix)]
+const MAX_ANSWER: usize = 1024;
+
+/// Graphics protocols the terminal claims to support
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// `4` among the device attributes
+    pub sixel: bool,
+    /// graphics query answered with `OK`
+    pub kitty: bool,
+    /// iTerm2 or WezTerm by the XTVERSION answer
+    pub iterm: bool,
+}
+
+impl Capabilities {
+    /// Best protocol supported, iTerm2 before kitty as WezTerm implements kitty only partially
+    pub fn best(&self) -> BackendKind {
+        if self.iterm {
+            BackendKind::Iterm
+        } else if self.kitty {
+            BackendKind::Kitty
+        } else {
+            BackendKind::Sixel
+        }
+    }
+}
+
+/// Whether the editor runs inside GNU Screen
+#[cfg_attr(windows, allow(dead_code))]
+fn in_screen() -> bool {
+    env::var_os("STY").is_some_and(|x| !x.is_empty())
+}
+
+/// Send escape sequences to the terminal and collect the answer until `done` returns true
+///
+/// The terminal is in raw mode meanwhile, reading gives up after a tenth of a second of silence.
+/// Inside tmux and GNU Screen the sequences are passed through to the outer terminal.
+#[cfg(unix)]
+pub fn query(sequences: &[&[u8]], done: impl Fn(&[u8]) -> bool) -> Option<Vec<u8>> {
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty").ok()?;
+
+    let old = termios::tcgetattr(&tty).ok()?;
+    let mut raw = old.clone();
+    termios::cfmakeraw(&mut raw);
+    raw.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
+    raw.control_chars[SpecialCharacterIndices::VTIME as usize] = 1;
+    termios::tcsetattr(&tty, SetArg::TCSANOW, &raw).ok()?;
+
+    // Screen ends its passthrough at the first string terminator, so every sequence gets its own
+    let mut request = Vec::new();
+    for sequence in sequences {
+        if tmux::active() {
+            request.extend(tmux::passthrough(sequence));
+        } else if in_screen() {
+            request.extend_from_slice(b"\x1bP");
+            request.extend_from_slice(sequence);
+            request.extend_from_slice(b"\x1b\\");
+        } else {
+            request.extend_from_slice(sequence);
+        }
+    }
+    let _ = tty.write_all(&request).and_then(|_| tty.flush());
+
+    let mut answer = Vec::new();
+    let mut byte = [0];
+    while !done(&answer) && answer.len() < MAX_ANSWER {
+        match tty.read(&mut byte) {
+            Ok(1) => answer.push(byte[0]),
+            _ => break,
+        }
+    }
+
+    let _ = termios::tcsetattr(&tty, SetArg::TCSANOW, &old);
+
+    (!answer.is_empty()).then_some(answer)
+}
+
+/// The Windows console has no raw mode with a timeout, everything is guessed from the environment
+#[cfg(windows)]
+pub fn query(_: &[&[u8]], _: impl Fn(&[u8]) -> bool) -> Option<Vec<u8>> {
+    None
+}
+
+/// Parameters of the device attributes in an answer, `CSI ? <params> c`
+fn da1_params(answer: &[u8]) -> Option<String> {
+    let start = answer.windows(3).position(|x| x == b"\x1b[?")? + 3;
+    let len = answer[start..].iter().position(|x| *x == b'c')?;
+
+    Some(String::from_utf8_lossy(&answer[start..start + len]).to_string())
+}
+
+/// Ask the terminal which graphics protocols it supports, none if it doesn't answer
+///
+/// This works over SSH, where the environment variables of the terminal are missing. Terminals
+/// ignore the kitty and XTVERSION queries they don't know, the device attributes come last.
+pub fn probe() -> Option<Capabilities> {
+    // the kitty protocol can't pass through Screen, which strips the string terminator
+    let sequences = if in_screen() { vec![XTVERSION, DA1] } else { vec![KITTY_QUERY, XTVERSION, DA1] };
+    let answer = query(&sequences, |x| da1_params(x).is_some())?;
+
+    capabilities(&answer)
+}
+
+/// Protocols claimed by the answers to the queries, none without device attributes
+fn capabilities(answer: &[u8]) -> Option<Capabilities> {
+    let params = da1_params(answer)?;
+    let text = String::from_utf8_lossy(answer);
+
+    Some(Capabilities {
+        sixel: params.split(';').any(|x| x == "4"),
+        kitty: text.contains("\x1b_Gi=31;OK"),
+        iterm: text.contains("iTerm2") || text.contains("WezTerm"),
+    })
+}
+
+/// Guess the protocol from the environment, inside tmux from the terminal it is attached to
+fn from_env() -> BackendKind {
+    let term = tmux::active().then(tmux::client_term).flatten()
+        .unwrap_or_else(|| env::var("TERM").unwrap_or_default());
+    let program = env::var("TERM_PROGRAM").unwrap_or_default();
+    // forwarded by the SSH configuration of iTerm2
+    let lc_terminal = env::var("LC_TERMINAL").unwrap_or_default();
+
+    if term.contains("kitty") || env::var_os("KITTY_WINDOW_ID").is_some() {
+        BackendKind::Kitty
+    } else if program == "iTerm.app" || program == "WezTerm" || term.contains("wezterm") || lc_terminal == "iTerm2" {
+        BackendKind::Iterm
+    } else {
+        BackendKind::Sixel
+    }
+}
+
+/// Backend chosen by `auto`, probed on first use and kept for the session
+///
+/// Falls back to the environment if the terminal doesn't answer or supports none of the protocols.
+pub fn detect_backend() -> BackendKind {
+    static DETECTED: OnceLock<BackendKind> = OnceLock::new();
+
+    *DETECTED.get_or_init(|| match probe() {
+        Some(capabilities) if capabilities != Capabilities::default() => capabilities.best(),
+        _ => from_env(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backend_from_answers() {
+        let best = |answer: &[u8]| capabilities(answer).map(|x| x.best());
+
+        assert_eq!(best(b"\x1b[?62;4;22c"), Some(BackendKind::Sixel));
+        assert_eq!(best(b"\x1b_Gi=31;OK\x1b\\\x1bP>|kitty(0.32.2)\x1b\\\x1b[?62;c"), Some(BackendKind::Kitty));
+        assert_eq!(best(b"\x1b_Gi=31;OK\x1b\\\x1bP>|WezTerm 20240203\x1b\\\x1b[?65;4;6;18;22c"), Some(BackendKind::Iterm));
+        assert_eq!(capabilities(b"\x1b[?1;2c"), Some(Capabilities::default()));
+        // kitty answered, the device attributes are still missing
+        assert_eq!(best(b"\x1b_Gi=31;OK\x1b\\"), None);
+    }
+}
@@ -0,0 +1,72 @@
+use std::env;
+use std::process::Command;
+
+/// Bytes of the payload wrapped in a single passthrough sequence
+const CHUNK_SIZE: usize = 4096;
+
+/// Whether the editor runs inside tmux
+pub fn active() -> bool {
+    env::var_os("TMUX").is_some_and(|x| !x.is_empty())
+}
+
+/// Output of `tmux display -p` for a format, none if tmux isn't reachable
+fn display(format: &str) -> Option<String> {
+    let output = Command::new("tmux").args(["display", "-p", format]).output().ok()?;
+
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// `TERM` of the terminal tmux is attached to, the one inside a pane names tmux itself
+pub fn client_term() -> Option<String> {
+    display("#{client_termname}").filter(|x| !x.is_empty())
+}
+
+/// Row and column of the top left corner of the current pane in the terminal, starting at zero
+pub fn pane_offset() -> Option<(usize, usize)> {
+    let offset = display("#{pane_top} #{pane_left}")?;
+    let (top, left) = offset.split_once(' ')?;
+
+    Some((top.parse().ok()?, left.parse().ok()?))
+}
+
+/// Wrap escape sequences, so that tmux forwards them to the outer terminal instead of eating them
+///
+/// Every escape character in the payload is doubled. Long payloads are split over several
+/// passthrough sequences, the outer terminal receives them in one piece. Needs `set -g
+/// allow-passthrough on` since tmux 3.3.
+pub fn passthrough(buf: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(buf.len() + buf.len() / CHUNK_SIZE * 8 + 8);
+
+    for chunk in buf.chunks(CHUNK_SIZE) {
+        out.extend_from_slice(b"\x1bPtmux;");
+        for byte in chunk {
+            if *byte == 0x1b {
+                out.push(0x1b);
+            }
+            out.push(*byte);
+        }
+        out.extend_from_slice(b"\x1b\\");
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_are_doubled() {
+        assert_eq!(passthrough(b"\x1b_Ga=q\x1b\\"), b"\x1bPtmux;\x1b\x1b_Ga=q\x1b\x1b\\\x1b\\");
+    }
+
+    #[test]
+    fn long_payloads_are_split() {
+        let out = passthrough(&[b'a'; CHUNK_SIZE + 1]);
+
+        let mut expected = b"\x1bPtmux;".to_vec();
+        expected.extend([b'a'; CHUNK_SIZE]);
+        expected.extend_from_slice(b"\x1b\\\x1bPtmux;a\x1b\\");
+        assert_eq!(out, expected);
+    }
+}
@@ -1,25 +1,58 @@
+#[cfg(unix)]
 use nix::{ioctl_read_bad, pty::Winsize};
+use regex::Regex;
 use sha2::{Digest, Sha256};
+use std::fmt;
 use std::fs::File;
-use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::{io::Write, str, usize};
+use std::process::Command;
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+use std::{io::Write, str};
 
+use crate::cache::{self, FreshnessPolicy};
+use crate::config::{BboxPolicy, Config, FontMode, LatexEngine};
+use crate::content::ContentType;
 use crate::error::{Error, Result};
-use crate::render::ART_PATH;
+use crate::render::art_path;
+use crate::runner::{self, CommandRunner};
+use crate::{render, svg, termcap};
 
 pub fn hash(input: &str) -> String {
+    hash_bytes(input.as_bytes())
+}
+
+/// Replace a leading `~` by the home directory
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Digest of binary content, like an image file
+pub fn hash_bytes(input: &[u8]) -> String {
     let mut hasher = Sha256::new();
-    hasher.update(input.as_bytes());
+    hasher.update(input);
     let result = hasher.finalize();
     let mut x = format!("{:x}", &result);
     x.truncate(24);
     x
 }
 
-/// Get pixel height of a character
-pub fn char_pixel_height() -> usize {
+/// Size of the terminal in cells and pixels, zero where unknown
+#[derive(Debug, Default)]
+struct WindowSize {
+    ws_row: u16,
+    ws_col: u16,
+    ws_xpixel: u16,
+    ws_ypixel: u16,
+}
+
+/// Size of the terminal receiving the images as reported by the `TIOCGWINSZ` ioctl, zero if
+/// it isn't a terminal
+#[cfg(unix)]
+fn window_size() -> WindowSize {
     ioctl_read_bad! { tiocgwinsz, 21523, Winsize }
 
     let mut size = Winsize {
@@ -29,190 +62,1272 @@ pub fn char_pixel_height() -> usize {
         ws_ypixel: 0,
     };
 
-    unsafe { tiocgwinsz(0, &mut size).unwrap() };
+    if unsafe { tiocgwinsz(crate::render::output_fd(), &mut size) }.is_err() {
+        return WindowSize::default();
+    }
+
+    WindowSize { ws_row: size.ws_row, ws_col: size.ws_col, ws_xpixel: size.ws_xpixel, ws_ypixel: size.ws_ypixel }
+}
+
+/// Size of the console window, pixels from the font of the console
+///
+/// ConPTY, as used by Windows Terminal and WezTerm, makes the font up, there the pixels are left
+/// to the `cell_size` option.
+#[cfg(windows)]
+fn window_size() -> WindowSize {
+    use windows_sys::Win32::System::Console::{
+        GetConsoleScreenBufferInfo, GetCurrentConsoleFontEx, GetStdHandle, CONSOLE_FONT_INFOEX,
+        CONSOLE_SCREEN_BUFFER_INFO, STD_OUTPUT_HANDLE,
+    };
+
+    let conpty = std::env::var_os("WT_SESSION").is_some() || std::env::var_os("TERM_PROGRAM").is_some();
+
+    unsafe {
+        let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+        let mut info: CONSOLE_SCREEN_BUFFER_INFO = std::mem::zeroed();
+        if GetConsoleScreenBufferInfo(handle, &mut info) == 0 {
+            return WindowSize::default();
+        }
+
+        let rows = (info.srWindow.Bottom - info.srWindow.Top + 1).max(0) as u16;
+        let cols = (info.srWindow.Right - info.srWindow.Left + 1).max(0) as u16;
+
+        let mut font: CONSOLE_FONT_INFOEX = std::mem::zeroed();
+        font.cbSize = std::mem::size_of::<CONSOLE_FONT_INFOEX>() as u32;
+        let (width, height) = if !conpty && GetCurrentConsoleFontEx(handle, 0, &mut font) != 0 {
+            (font.dwFontSize.X.max(0) as u16, font.dwFontSize.Y.max(0) as u16)
+        } else {
+            (0, 0)
+        };
+
+        WindowSize { ws_row: rows, ws_col: cols, ws_xpixel: cols.saturating_mul(width), ws_ypixel: rows.saturating_mul(height) }
+    }
+}
+
+/// Cell size in pixels assumed if the terminal reports none
+const DEFAULT_CELL_SIZE: (f32, f32) = (14.0, 28.0);
+
+/// Cell size set by the configuration, overriding what the terminal reports
+static CELL_SIZE: RwLock<Option<(f32, f32)>> = RwLock::new(None);
+
+/// Override the cell size reported by the terminal, none to detect it again
+pub fn set_cell_size(size: Option<(f32, f32)>) {
+    *CELL_SIZE.write().unwrap() = size;
+}
+
+/// Width and height of a terminal cell in pixels, keeping the fraction
+///
+/// Taken from the configuration, the `TIOCGWINSZ` ioctl, or an XTWINOPS query if the ioctl reports
+/// no pixels, as over some SSH connections. The query is answered once, the result kept.
+pub fn cell_size() -> (f32, f32) {
+    if let Some(size) = *CELL_SIZE.read().unwrap() {
+        return size;
+    }
+
+    let size = window_size();
+    if size.ws_xpixel > 2 && size.ws_ypixel > 2 && size.ws_col > 0 && size.ws_row > 0 {
+        return (size.ws_xpixel as f32 / size.ws_col as f32, size.ws_ypixel as f32 / size.ws_row as f32);
+    }
+
+    static QUERIED: OnceLock<Option<(f32, f32)>> = OnceLock::new();
+    QUERIED.get_or_init(query_cell_size).unwrap_or(DEFAULT_CELL_SIZE)
+}
+
+/// Factor of the cell height over the default, like 2 on a screen scaled to 200%
+pub fn dpi_scale() -> f32 {
+    cell_size().1 / DEFAULT_CELL_SIZE.1
+}
+
+/// Ask the terminal for the cell size, with `CSI 16 t` or else the text area of `CSI 14 t`
+fn query_cell_size() -> Option<(f32, f32)> {
+    if let Some((height, width)) = xtwinops(16).filter(|(height, width)| *height > 0.0 && *width > 0.0) {
+        return Some((width, height));
+    }
+
+    let (height, width) = xtwinops(14)?;
+    let size = window_size();
+
+    (size.ws_col > 0 && size.ws_row > 0 && height > 0.0 && width > 0.0)
+        .then(|| (width / size.ws_col as f32, height / size.ws_row as f32))
+}
+
+/// Send an XTWINOPS query and read the height and width in the answer `CSI <op - 10> ; h ; w t`
+fn xtwinops(op: usize) -> Option<(f32, f32)> {
+    let answer = termcap::query(&[format!("\x1b[{}t", op).as_bytes()], |x| x.ends_with(b"t"))?;
+    let answer = str::from_utf8(&answer).ok()?;
+    let answer = answer[answer.rfind("\x1b[")? + 2..].strip_suffix('t')?;
+    let mut fields = answer.split(';');
+    if fields.next()? != (op - 10).to_string() {
+        return None;
+    }
+
+    Some((fields.next()?.parse().ok()?, fields.next()?.parse().ok()?))
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 encoding with padding, as used by terminal graphics protocols
+pub fn base64(input: &[u8]) -> String {
+    let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+
+    for chunk in input.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let triple = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                out.push(BASE64_ALPHABET[(triple >> (18 - 6 * idx) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
 
-    if size.ws_ypixel > 2 {
-        size.ws_ypixel as usize / size.ws_row as usize
+/// Get pixel height of a character
+pub fn char_pixel_height() -> usize {
+    (char_pixel_height_exact() as usize).max(1)
+}
+
+/// Get pixel height of a character, keeping the fraction
+///
+/// Terminals spread the remainder of the window height over the rows, with the truncated height
+/// images spanning many rows drift away from their lines.
+pub fn char_pixel_height_exact() -> f32 {
+    cell_size().1
+}
+
+/// Pixel height of a number of rows, rounded once instead of per row
+pub fn rows_pixel_height(rows: usize) -> usize {
+    (rows as f32 * char_pixel_height_exact()).round() as usize
+}
+
+/// Get pixel width of the terminal window
+pub fn window_pixel_width() -> usize {
+    let size = window_size();
+
+    if size.ws_xpixel > 2 {
+        size.ws_xpixel as usize
     } else {
-        28
+        (size.ws_col as f32 * cell_size().0) as usize
     }
 }
 
+/// Get pixel width of a character
+pub fn char_pixel_width() -> usize {
+    // sizes are divided by it, terminals without a tty report zero
+    (cell_size().0 as usize).max(1)
+}
+
+/// Environments whose content is taken literally and may contain `%`
+const VERBATIM_ENVS: [&str; 5] = ["verbatim", "verbatim*", "Verbatim", "lstlisting", "minted"];
+
+/// Remove latex comments, leaving the typeset output unchanged
+///
+/// Lines consisting of a comment only are dropped, trailing comments are cut after the `%`, which
+/// still swallows the line break. Escaped `\%`, inline `\verb` and verbatim environments are kept.
+pub fn strip_latex_comments(content: &str) -> String {
+    let mut res = Vec::new();
+    let mut verbatim: Option<&str> = None;
+
+    for line in content.lines() {
+        if let Some(env) = verbatim {
+            if line.contains(&format!("\\end{{{}}}", env)) {
+                verbatim = None;
+            }
+            res.push(line.to_string());
+            continue;
+        }
+
+        if line.trim_start().starts_with('%') {
+            continue;
+        }
+
+        verbatim = VERBATIM_ENVS.iter()
+            .find(|env| line.contains(&format!("\\begin{{{}}}", env)) && !line.contains(&format!("\\end{{{}}}", env)))
+            .copied();
+
+        let line = match comment_start(line) {
+            Some(idx) if verbatim.is_none() => &line[..=idx],
+            _ => line,
+        };
+
+        res.push(line.to_string());
+    }
+
+    let mut res = res.join("\n");
+    if content.ends_with('\n') {
+        res.push('\n');
+    }
+
+    res
+}
+
+/// Byte offset of the `%` starting a comment in a line
+fn comment_start(line: &str) -> Option<usize> {
+    let mut chars = line.char_indices();
+
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '%' => return Some(idx),
+            '\\' => {
+                // skip the escaped character, `\verb` spans up to its closing delimiter
+                if line[idx..].starts_with("\\verb") {
+                    chars.nth(3);
+                    let delim = match chars.next() {
+                        Some((_, '*')) => chars.next(),
+                        delim => delim,
+                    };
+                    if let Some((_, delim)) = delim {
+                        chars.find(|x| x.1 == delim);
+                    }
+                } else {
+                    chars.next();
+                }
+            },
+            _ => {},
+        }
+    }
+
+    None
+}
+
+/// Extract the first error of a latex log as reason, context and line number
+///
+/// The reason is the message of the first `! ...` line other than the emergency stop, the element
+/// and line come from the `l.<line> <element>` context following it, for example
+/// `("Undefined control sequence.", "\\foo", 5)` for `l.5 \foo`. Accepts arbitrary input and never
+/// panics; missing parts are empty and an unknown line is reported as `usize::MAX`.
+pub fn parse_latex_log(log: &str) -> (String, String, usize) {
+    let mut reason = None;
+    let mut element = String::new();
+    let mut line = usize::MAX;
+
+    for elm in log.lines() {
+        match reason {
+            None => {
+                if let Some(msg) = elm.strip_prefix("! ") {
+                    if !msg.trim().is_empty() && !msg.contains("Emergency stop") {
+                        reason = Some(msg.trim().to_string());
+                    }
+                }
+            },
+            Some(_) => {
+                // the context line `l.<nr> <element>` follows the error message
+                if let Some(elms) = elm.strip_prefix("l.") {
+                    let mut elms = elms.splitn(2, ' ').map(|x| x.trim());
+                    if let Some(Ok(val)) = elms.next().map(|x| x.parse::<usize>()) {
+                        line = val;
+                    }
+                    if let Some(val) = elms.next() {
+                        element = val.to_string();
+                    }
+
+                    break;
+                }
+            }
+        }
+    }
+
+    (reason.unwrap_or_default(), element, line)
+}
+
+/// Line of a snippet, counted from zero, for a line of the latex document it was embedded in
+///
+/// The snippet is found by its first non-blank line, lines outside of it are clamped to it.
+pub fn snippet_line(document: &str, snippet: &str, line: usize) -> Option<usize> {
+    let lines = snippet.lines().collect::<Vec<_>>();
+    let skipped = lines.iter().position(|x| !x.trim().is_empty())?;
+    let start = document.lines().position(|x| x.contains(lines[skipped].trim()))?;
+
+    let offset = line.checked_sub(start + 1)? + skipped;
+
+    Some(offset.min(lines.len() - 1))
+}
+
+/// Depth below the baseline in pt, as reported by the preview package
+///
+/// With tightpage the log contains "Preview: Snippet 1 ended.(<height>+<depth>/<width>)." in sp.
+pub fn preview_depth(log: &str) -> Option<f32> {
+    let line = log.lines().find(|x| x.starts_with("Preview: Snippet"))?;
+    let dims = line.split_once(".(")?.1;
+    let depth = dims.split_once('+')?.1.split_once('/')?.0;
+
+    depth.parse::<f32>().ok().map(|sp| sp / 65536.0)
+}
+
+/// Name of the package if the error reports a missing style file
+///
+/// latex reports this as "LaTeX Error: File `circuitikz.sty' not found."
+fn missing_package(reason: &str) -> Option<String> {
+    let start = reason.find("File `")? + "File `".len();
+    let (name, rest) = reason[start..].split_once(".sty'")?;
+
+    rest.contains("not found").then(|| name.to_string())
+}
+
+/// Version of the installed dvisvgm as major and minor number, queried once per session
+fn dvisvgm_version(config: &Config) -> Option<(u32, u32)> {
+    static VERSION: OnceLock<Option<(u32, u32)>> = OnceLock::new();
+
+    *VERSION.get_or_init(|| parse_dvisvgm_version(&runner::version(&*runner::runner(config), "dvisvgm")?))
+}
+
+/// Major and minor number of the version output of dvisvgm, the first line reads `dvisvgm 3.1.2`
+fn parse_dvisvgm_version(version: &str) -> Option<(u32, u32)> {
+    let mut nrs = version.split_whitespace().nth(1)?.split('.').map(|x| x.parse::<u32>());
+
+    match (nrs.next(), nrs.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) => Some((major, minor)),
+        _ => None,
+    }
+}
+
+/// TeX distribution providing the binaries
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TexDistribution {
+    /// TeX Live with its release year, if known
+    TexLive(Option<u32>),
+    /// MiKTeX with its version
+    MikTex(String),
+    /// Tectonic with its version
+    Tectonic(String),
+}
+
+impl fmt::Display for TexDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TexDistribution::TexLive(Some(year)) => write!(f, "TeX Live {}", year),
+            TexDistribution::TexLive(None) => write!(f, "TeX Live"),
+            TexDistribution::MikTex(version) => write!(f, "MiKTeX {}", version),
+            TexDistribution::Tectonic(version) => write!(f, "Tectonic {}", version),
+        }
+    }
+}
+
+/// Classify the first line of `--version` of a TeX binary
+///
+/// TeX Live reports "pdfTeX 3.14... (TeX Live 2023/Debian)", MiKTeX "MiKTeX-pdfTeX 4.10
+/// (MiKTeX 22.7)" and tectonic "Tectonic 0.14.1".
+fn parse_tex_distribution(version: &str) -> Option<TexDistribution> {
+    let line = version.lines().next()?;
+
+    if let Some(rest) = line.split_once("(TeX Live").map(|x| x.1) {
+        let year = rest.trim_start().get(..4).and_then(|x| x.parse().ok());
+        return Some(TexDistribution::TexLive(year));
+    }
+
+    if let Some(rest) = line.split_once("(MiKTeX").map(|x| x.1) {
+        let version = rest.trim_start().trim_end_matches(')');
+        return Some(TexDistribution::MikTex(version.to_string()));
+    }
+
+    line.strip_prefix("Tectonic ")
+        .map(|version| TexDistribution::Tectonic(version.trim().to_string()))
+}
+
+/// Distribution of the latex binary on the path, or of tectonic if there is none
+///
+/// The binaries are queried once per session.
+pub fn detect_tex_distribution(config: &Config) -> Option<TexDistribution> {
+    static DISTRIBUTION: OnceLock<Option<TexDistribution>> = OnceLock::new();
+
+    DISTRIBUTION.get_or_init(|| tex_distribution(&*runner::runner(config))).clone()
+}
+
+/// Query the binaries for their distribution
+fn tex_distribution(runner: &dyn CommandRunner) -> Option<TexDistribution> {
+    ["latex", "tectonic"].iter()
+        .filter_map(|binary| runner::version(runner, binary))
+        .find_map(|out| parse_tex_distribution(&out))
+}
+
+/// Font arguments passed to dvisvgm
+///
+/// WOFF2 is supported since dvisvgm 2.2, older or unknown versions fall back to paths.
+fn font_args(config: &Config) -> &'static str {
+    match config.fonts {
+        FontMode::Woff2 if dvisvgm_version(config).is_some_and(|x| x >= (2, 2)) => "--font-format=woff2",
+        _ => "--no-fonts",
+    }
+}
+
+/// Tighten a PDF in place with the configured cropper
+///
+/// The cropper is called like `pdfcrop --margins <margin> <input> <output>`.
+fn crop_pdf(path: &Path, timeout: Duration, config: &Config) -> Result<()> {
+    let mut args = config.pdf_crop.split_whitespace();
+    let binary = which::which(args.next().unwrap_or_default()).map_err(Error::BinaryNotFound)?;
+    let cropped = path.with_extension("crop.pdf");
+
+    let cmd = runner::runner(config).output(Command::new(binary)
+        .args(args)
+        .arg("--margins")
+        .arg(config.pdf_crop_margin.to_string())
+        .arg(path)
+        .arg(&cropped), timeout)?;
+
+    if !cmd.status.success() || !cropped.exists() {
+        let _ = std::fs::remove_file(path);
+        return Err(Error::CropFailed(String::from_utf8_lossy(&cmd.stderr).to_string()));
+    }
+
+    std::fs::rename(&cropped, path).map_err(Error::Io)
+}
+
+/// Bounding box passed to dvisvgm for a document declaring the box `declared`
+///
+/// By default the box is expanded to all visible content plus a margin of 1pt, unless it carries
+/// the border option. With the clip policy content outside of the declared box is cut off.
+pub fn bbox(declared: &'static str, bordered: bool, config: &Config) -> &'static str {
+    match config.bbox {
+        BboxPolicy::Clip => declared,
+        BboxPolicy::Expand if bordered && config.border_lrtb.is_some() => declared,
+        BboxPolicy::Expand => "1",
+    }
+}
+
+/// Latex source of some processed content in the cache, named by `Config::source_key`
+pub fn source_path(kind: &ContentType, content: &str, config: &Config) -> PathBuf {
+    art_path()
+        .join(config.source_key(&kind.tagged(content)))
+        .with_extension("tex")
+}
+
+/// SVG of some processed content in the cache, converted at a zoom
+///
+/// The zoom is part of the name, so that all zoom levels convert the same DVI of `source_path`.
+pub fn svg_path(kind: &ContentType, content: &str, zoom: f32, config: &Config) -> PathBuf {
+    let key = config.key(&kind.tagged(content));
+    let key = if zoom == 1.0 { key } else { format!("{}-zoom{}", key, (zoom * 100.0).round()) };
+
+    art_path().join(key).with_extension("svg")
+}
+
 /// Generate SVG file from latex file with given zoom
-pub fn generate_svg_from_latex(path: &Path, zoom: f32) -> Result<PathBuf> {
+///
+/// The bounding box is passed to dvisvgm with `-b`, for example a margin like `1` or `preview`.
+/// The DVI is compiled from `source` unless cached, SVGs of other options or zoom levels only run
+/// dvisvgm again.
+pub fn generate_svg_from_latex(source: &Path, svg_path: &Path, zoom: f32, bbox: &str, timeout: Duration, config: &Config) -> Result<PathBuf> {
+    let dest_path = source.parent().unwrap();
+    let out_path = compile_latex(source, None, timeout, config)?;
+
+    // convert the dvi to a svg file, with glyphs either as paths or embedded fonts
+    if !svg_path.exists() && out_path.exists() {
+        let dvisvgm_path = which::which("dvisvgm").map_err(Error::BinaryNotFound)?;
+
+        let mut dvisvgm = Command::new(dvisvgm_path);
+        if config.engine.output() == "pdf" {
+            dvisvgm.arg("--pdf");
+        }
+        if !config.libgs.is_empty() {
+            dvisvgm.arg(format!("--libgs={}", config.libgs));
+        }
+
+        // the preview box is only known from DVI specials, a PDF page already has the size of
+        // the box by the preview option of standalone
+        let bbox = if config.engine.output() == "pdf" && bbox == "preview" { "papersize" } else { bbox };
+
+        let cmd = runner::runner(config).output(dvisvgm
+            .current_dir(dest_path)
+            .arg("-b")
+            .arg(bbox)
+            .arg(font_args(config))
+            .arg(format!("--zoom={}", zoom))
+            .arg("-o").arg(svg_path.file_name().unwrap())
+            .arg(out_path), timeout)?;
+
+        let buf = String::from_utf8_lossy(&cmd.stderr);
+
+        // without Ghostscript dvisvgm silently drops PostScript specials, producing partial output
+        if buf.contains("PostScript specials") && buf.contains("Ghostscript") {
+            let _ = std::fs::remove_file(svg_path);
+            return Err(Error::GhostscriptMissing);
+        }
+
+        if !cmd.status.success() || buf.contains("error:") {
+            return Err(Error::InvalidDvisvgm(buf.to_string()));
+        }
+
+        // latex succeeded but typeset nothing, e.g. everything was commented out
+        if svg::is_empty(&std::fs::read_to_string(svg_path).map_err(Error::Io)?) {
+            std::fs::remove_file(svg_path).map_err(Error::Io)?;
+            return Err(Error::NothingTypeset);
+        }
+
+        svg::postprocess(svg_path, config)?;
+
+        // metrics of a previous SVG are stale now
+        let _ = std::fs::remove_file(svg_path.with_extension("meta"));
+    }
+
+    Ok(svg_path.to_path_buf())
+}
+
+/// Compile the latex file of `path` to a dvi, or a pdf with some engines, unless done already
+///
+/// Files included by the source, like images, are also looked up in `include_dir` if given.
+pub fn compile_latex(path: &Path, include_dir: Option<&Path>, timeout: Duration, config: &Config) -> Result<PathBuf> {
     let dest_path = path.parent().unwrap();
     let file: &Path = path.file_name().unwrap().as_ref();
 
-    // use latex to generate a dvi
-    let dvi_path = path.with_extension("dvi");
-    if !dvi_path.exists() {
-        let latex_path = which::which("latex").map_err(Error::BinaryNotFound)?;
+    // use latex to generate a dvi, or a pdf with some engines
+    let out_path = path.with_extension(config.engine.output());
+    if !out_path.exists() {
+        let latex_path = which::which(config.engine.binary()).map_err(Error::BinaryNotFound)?;
+
+        let mut cmd = Command::new(latex_path);
+        if let Some(dir) = include_dir {
+            // the empty entry keeps the default search path of the distribution
+            cmd.env("TEXINPUTS", std::env::join_paths([dir, Path::new("")]).map_err(|_| Error::FileNotFound(dir.to_path_buf()))?);
+        }
 
-        let cmd = Command::new(latex_path)
-            .current_dir(&dest_path)
+        let cmd = runner::runner(config).output(cmd
+            .current_dir(dest_path)
             //.arg("--jobname").arg(&dvi_path)
-            .arg(&file.with_extension("tex"))
-            .output()
-            .expect("Could not spawn latex");
+            .args(config.engine.args(config.shell_escape))
+            .arg(file.with_extension("tex")), timeout)?;
 
         if !cmd.status.success() {
-            let buf = String::from_utf8_lossy(&cmd.stdout);
+            // tectonic only reports a summary, the log has the usual format
+            let buf = match config.engine {
+                LatexEngine::Tectonic => std::fs::read_to_string(path.with_extension("log"))
+                    .unwrap_or_else(|_| String::from_utf8_lossy(&cmd.stderr).to_string()),
+                _ => String::from_utf8_lossy(&cmd.stdout).to_string(),
+            };
 
             // latex prints error to the stdout, if this is empty, then something is fundamentally
-            // wrong with the latex binary (for example shared library error). In this case just
-            // exit the program
+            // wrong with the latex binary (for example shared library error)
             if buf.is_empty() {
-                let buf = String::from_utf8_lossy(&cmd.stderr);
-                panic!("Latex exited with `{}`", buf);
+                let err = String::from_utf8_lossy(&cmd.stderr).trim().to_string();
+                return Err(Error::LatexCrashed(if err.is_empty() { cmd.status.to_string() } else { err }));
             }
 
-            let err = buf
-                .split('\n')
-                .filter(|x| {
-                    (x.starts_with("! ") || x.starts_with("l.")) && !x.contains("Emergency stop")
-                })
-                .fold(("", "", usize::MAX), |mut err, elm| {
-                    if elm.starts_with("! ") {
-                        err.0 = elm;
-                    } else if let Some(elms) = elm.strip_prefix("1.") {
-                        let mut elms = elms.splitn(2, ' ').map(|x| x.trim());
-                        if let Some(Ok(val)) = elms.next().map(|x| x.parse::<usize>()) {
-                            err.2 = val;
-                        }
-                        if let Some(val) = elms.next() {
-                            err.1 = val;
-                        }
-                    }
+            let (reason, element, line) = parse_latex_log(&buf);
 
-                    err
-                });
+            if let Some(package) = missing_package(&reason) {
+                return Err(Error::MissingPackage(package));
+            }
+
+            if reason.contains("-shell-escape") || reason.contains("--shell-escape") {
+                return Err(Error::ShellEscapeRequired(reason));
+            }
 
-            return Err(Error::InvalidMath(
-                err.0.to_string(),
-                err.1.to_string(),
-                err.2,
-            ));
+            return Err(Error::InvalidMath(reason, element, line));
+        }
+
+        if config.engine.output() == "pdf" && !config.pdf_crop.is_empty() {
+            crop_pdf(&out_path, timeout, config)?;
         }
     }
 
-    // convert the dvi to a svg file with the woff font format
-    let svg_path = path.with_extension("svg");
-    if !svg_path.exists() && dvi_path.exists() {
-        let dvisvgm_path = which::which("dvisvgm").map_err(Error::BinaryNotFound)?;
+    Ok(out_path)
+}
 
-        let cmd = Command::new(dvisvgm_path)
-            .current_dir(&dest_path)
-            .arg("-b")
-            .arg("1")
-            //.arg("--font-format=woff")
-            .arg("--no-fonts")
-            .arg(&format!("--zoom={}", zoom))
-            .arg(&dvi_path)
-            .output()
-            .expect("Couldn't run svisvgm properly!");
+/// Per side border in the syntax of standalone, which orders the sides left, bottom, right, top
+fn standalone_border(config: &Config) -> Option<String> {
+    let [left, right, top, bottom] = config.border_lrtb?;
 
-        let buf = String::from_utf8_lossy(&cmd.stderr);
-        if !cmd.status.success() || buf.contains("error:") {
-            return Err(Error::InvalidDvisvgm(buf.to_string()));
+    Some(format!("{{{}pt {}pt {}pt {}pt}}", left, bottom, right, top))
+}
+
+/// Control sequences of plain latex math, available without amsmath and amsfonts
+const KERNEL_MATH: &[&str] = &[
+    "alpha", "beta", "gamma", "delta", "epsilon", "varepsilon", "zeta", "eta", "theta", "vartheta",
+    "iota", "kappa", "lambda", "mu", "nu", "xi", "pi", "varpi", "rho", "varrho", "sigma", "varsigma",
+    "tau", "upsilon", "phi", "varphi", "chi", "psi", "omega", "Gamma", "Delta", "Theta", "Lambda",
+    "Xi", "Pi", "Sigma", "Upsilon", "Phi", "Psi", "Omega",
+    "frac", "sqrt", "sum", "prod", "int", "oint", "lim", "sup", "inf", "max", "min", "log", "ln",
+    "exp", "sin", "cos", "tan", "cot", "sec", "csc", "arcsin", "arccos", "arctan", "sinh", "cosh",
+    "tanh", "det", "dim", "ker", "deg", "gcd", "Pr", "arg",
+    "cdot", "cdots", "ldots", "vdots", "ddots", "times", "div", "pm", "mp", "circ", "ast", "star",
+    "le", "leq", "ge", "geq", "ne", "neq", "approx", "equiv", "sim", "simeq", "cong", "propto",
+    "ll", "gg", "subset", "subseteq", "supset", "supseteq", "in", "notin", "ni", "cup", "cap",
+    "setminus", "emptyset", "forall", "exists", "neg", "wedge", "vee", "infty", "partial", "nabla",
+    "to", "rightarrow", "leftarrow", "leftrightarrow", "Rightarrow", "Leftarrow", "Leftrightarrow",
+    "mapsto", "uparrow", "downarrow", "left", "right", "big", "Big", "bigg", "Bigg", "langle",
+    "rangle", "lfloor", "rfloor", "lceil", "rceil", "vert", "Vert", "mid", "parallel", "perp",
+    "hat", "bar", "vec", "dot", "ddot", "tilde", "overline", "underline", "mathrm", "mathit",
+    "mathbf", "mathcal", "mathsf", "mathtt", "prime", "hbar", "ell", "Re", "Im", "aleph",
+    "quad", "qquad", "displaystyle", "textstyle", "over", "choose",
+    ",", ";", ":", "!", " ", "{", "}", "%", "$", "&", "#", "_", "|",
+];
+
+/// Whether an equation only uses constructs of plain latex math
+///
+/// The decision depends on the content alone, so that the cache key determines the preamble.
+fn is_simple_equation(content: &str) -> bool {
+    let control = Regex::new(r"\\([A-Za-z]+|.)").unwrap();
+
+    // a line break `\\` is no kernel command, so numbered environments always get amsmath
+    for name in control.captures_iter(content) {
+        if !KERNEL_MATH.contains(&&name[1]) {
+            return false;
         }
     }
 
-    Ok(path.to_path_buf())
+    true
+}
+
+/// Document class and packages wrapped around an equation
+pub fn equation_preamble(content: &str, config: &Config) -> String {
+    // the `preview` option of standalone implicitly loads the preview package with tightpage, be
+    // explicit about it when the user opts out
+    let class = match (config.tightpage, standalone_border(config)) {
+        (true, Some(border)) => format!("\\documentclass[20pt, preview, border={}]{{standalone}}\n", border),
+        (true, None) => "\\documentclass[20pt, preview]{standalone}\n".to_string(),
+        (false, _) => "\\documentclass[20pt]{standalone}\n\\usepackage[active, displaymath]{preview}\n".to_string(),
+    };
+
+    let mut preamble = if config.minimal_preamble && is_simple_equation(content) {
+        class
+    } else {
+        format!("{}\\usepackage{{amsmath}}\\usepackage{{amsfonts}}\n", class)
+    };
+
+    // only load xcolor when needed, this keeps plain equations byte-identical
+    if content.contains("\\color") || content.contains("\\textcolor") {
+        preamble.push_str("\\usepackage{xcolor}\n");
+    }
+
+    if !config.preamble.is_empty() {
+        preamble.push_str(&config.preamble);
+        preamble.push('\n');
+    }
+
+    for (register, value) in &config.math_spacing {
+        preamble.push_str(&format!("\\{}={}\n", register, value));
+    }
+
+    preamble
+}
+
+/// Numbered amsmath environment for multi-line equations, if line numbers are enabled
+///
+/// Equations with alignment points use `align`, others `gather`. Content bringing its own
+/// environments is left alone, as these can't be nested.
+fn numbered_environment(content: &str, config: &Config) -> Option<&'static str> {
+    if !config.line_numbers || !content.contains("\\\\") || content.contains("\\begin{") {
+        return None;
+    }
+
+    Some(if content.contains('&') { "align" } else { "gather" })
+}
+
+/// Complete latex source of an equation
+pub fn equation_document(content: &str, config: &Config) -> String {
+    let mut doc = equation_preamble(content, config);
+
+    if let Some(env) = numbered_environment(content, config) {
+        doc.push_str(&format!("\\begin{{document}}\n\\begin{{{0}}}\n{1}\n\\end{{{0}}}\n\\end{{document}}", env, content.trim_end()));
+
+        return doc;
+    }
+
+    doc.push_str("\\begin{document}\n$$\n");
+    if config.force_displaystyle {
+        doc.push_str("\\displaystyle ");
+    }
+    doc.push_str(content);
+
+    // a trailing backslash would turn the closing delimiter into a literal `\$`
+    if content.ends_with('\\') {
+        doc.push('\n');
+    }
+    doc.push_str("$$\n\\end{document}");
+
+    doc
 }
 
 /// Parse an equation with the given zoom
-pub fn parse_equation(content: &str, zoom: f32) -> Result<PathBuf> {
-    let path = Path::new(ART_PATH)
-        .join(hash(content))
-        .with_extension("svg");
+pub fn parse_equation(content: &str, zoom: f32, config: &Config) -> Result<PathBuf> {
+    let source = source_path(&ContentType::Math, content, config);
 
     // create a new tex file containing the equation
-    if !path.with_extension("tex").exists() {
-        let mut file = File::create(path.with_extension("tex")).map_err(Error::Io)?;
+    if !source.exists() {
+        let mut file = File::create(&source).map_err(Error::Io)?;
 
-        file.write_all("\\documentclass[20pt, preview]{standalone}\n\\usepackage{amsmath}\\usepackage{amsfonts}\n\\begin{document}\n$$\n".as_bytes())
+        file.write_all(equation_document(content, config).as_bytes())
             .map_err(Error::Io)?;
+    }
 
-        file.write_all(content.as_bytes()).map_err(Error::Io)?;
+    // the per side border is stored in the tightpage information of the preview package, without
+    // tightpage there is no box to respect
+    let bbox = bbox(if config.tightpage { "preview" } else { "1" }, true, config);
+
+    generate_svg_from_latex(&source, &svg_path(&ContentType::Math, content, zoom, config), zoom, bbox, config.timeout_equation, config)
+}
 
-        file.write_all("$$\n\\end{document}".as_bytes())
+/// Parse inline math, given with its `\(...\)` delimiters
+///
+/// A strut gives every expression the height and depth of a text line, so that the baseline sits at
+/// the same height in all images and lines up with the surrounding text once scaled to a row.
+pub fn parse_inline_equation(content: &str, config: &Config) -> Result<PathBuf> {
+    let source = source_path(&ContentType::InlineMath, content, config);
+
+    if !source.exists() {
+        let mut file = File::create(&source).map_err(Error::Io)?;
+
+        let mut doc = equation_preamble(content, config);
+        doc.push_str(&format!("\\begin{{document}}\n\\strut{}\n\\end{{document}}", content));
+
+        file.write_all(doc.as_bytes()).map_err(Error::Io)?;
+    }
+
+    let bbox = bbox(if config.tightpage { "preview" } else { "1" }, true, config);
+
+    generate_svg_from_latex(&source, &svg_path(&ContentType::InlineMath, content, 1.0, config), 1.0, bbox, config.timeout_equation, config)
+}
+
+/// Compile a typst snippet to a SVG file
+///
+/// The page shrinks to the content, the text size matches the 20pt of equations typeset by latex.
+pub fn parse_typst(content: &str, zoom: f32, config: &Config) -> Result<PathBuf> {
+    let path = art_path()
+        .join(config.key(&ContentType::Typst.tagged(content)))
+        .with_extension("svg");
+
+    if !path.with_extension("typ").exists() {
+        let mut file = File::create(path.with_extension("typ")).map_err(Error::Io)?;
+
+        file.write_all(format!("#set page(width: auto, height: auto, margin: 1pt, fill: none)\n#set text(size: {}pt)\n", 20.0 * zoom).as_bytes())
             .map_err(Error::Io)?;
+
+        file.write_all(content.as_bytes()).map_err(Error::Io)?;
+    }
+
+    if !path.exists() {
+        let typst_path = which::which("typst").map_err(Error::BinaryNotFound)?;
+
+        let cmd = runner::runner(config).output(Command::new(typst_path)
+            .current_dir(art_path())
+            .arg("compile")
+            .arg("--format").arg("svg")
+            .arg(path.with_extension("typ").file_name().unwrap())
+            .arg(path.file_name().unwrap()), config.timeout_equation)?;
+
+        if !cmd.status.success() {
+            let _ = std::fs::remove_file(&path);
+            return Err(Error::ToolFailed("typst".to_string(), String::from_utf8_lossy(&cmd.stderr).to_string()));
+        }
+
+        finish_svg(&path, config)?;
+    }
+
+    Ok(path)
+}
+
+/// Reject empty output of a converter and apply the SVG options
+pub fn finish_svg(path: &Path, config: &Config) -> Result<()> {
+    if svg::is_empty(&std::fs::read_to_string(path).map_err(Error::Io)?) {
+        std::fs::remove_file(path).map_err(Error::Io)?;
+        return Err(Error::NothingTypeset);
+    }
+
+    svg::postprocess(path, config)
+}
+
+/// Lay out a graphviz graph with `dot` and convert it to a SVG file
+pub fn parse_dot(content: &str, config: &Config) -> Result<PathBuf> {
+    let path = art_path()
+        .join(config.key(&ContentType::Dot.tagged(content)))
+        .with_extension("svg");
+
+    if !path.with_extension("dot").exists() {
+        let mut file = File::create(path.with_extension("dot")).map_err(Error::Io)?;
+
+        file.write_all(content.as_bytes()).map_err(Error::Io)?;
+    }
+
+    if !path.exists() {
+        let dot_path = which::which("dot").map_err(Error::BinaryNotFound)?;
+
+        let cmd = runner::runner(config).output(Command::new(dot_path)
+            .current_dir(art_path())
+            .arg("-Tsvg")
+            .arg("-o").arg(path.file_name().unwrap())
+            .arg(path.with_extension("dot").file_name().unwrap()), config.timeout_plot)?;
+
+        if !cmd.status.success() {
+            let _ = std::fs::remove_file(&path);
+            return Err(Error::ToolFailed("dot".to_string(), String::from_utf8_lossy(&cmd.stderr).to_string()));
+        }
+
+        finish_svg(&path, config)?;
+    }
+
+    Ok(path)
+}
+
+/// Render a PlantUML diagram to a SVG file
+///
+/// The `@startuml` and `@enduml` markers are added unless present. PlantUML is run from the jar
+/// given in `plantuml_jar`, otherwise the `plantuml` binary is used.
+pub fn parse_plantuml(content: &str, config: &Config) -> Result<PathBuf> {
+    let path = art_path()
+        .join(config.key(&ContentType::PlantUml.tagged(content)))
+        .with_extension("svg");
+
+    if !path.with_extension("puml").exists() {
+        let mut file = File::create(path.with_extension("puml")).map_err(Error::Io)?;
+
+        if content.trim_start().starts_with("@start") {
+            file.write_all(content.as_bytes()).map_err(Error::Io)?;
+        } else {
+            file.write_all(format!("@startuml\n{}\n@enduml\n", content.trim_end()).as_bytes())
+                .map_err(Error::Io)?;
+        }
+    }
+
+    if !path.exists() {
+        let mut plantuml = if config.plantuml_jar.is_empty() {
+            Command::new(which::which("plantuml").map_err(Error::BinaryNotFound)?)
+        } else {
+            let mut java = Command::new(which::which("java").map_err(Error::BinaryNotFound)?);
+            java.arg("-jar").arg(&config.plantuml_jar);
+            java
+        };
+
+        // the output is named after the input file, as long as the diagram is not named itself
+        let cmd = runner::runner(config).output(plantuml
+            .current_dir(art_path())
+            .arg("-tsvg")
+            .arg("-nometadata")
+            .arg(path.with_extension("puml").file_name().unwrap()), config.timeout_plot)?;
+
+        if !cmd.status.success() {
+            // PlantUML draws syntax errors into the output, which should not be cached
+            let _ = std::fs::remove_file(&path);
+            let log = format!("{}{}", String::from_utf8_lossy(&cmd.stderr), String::from_utf8_lossy(&cmd.stdout));
+            return Err(Error::ToolFailed("plantuml".to_string(), log));
+        }
+
+        if !path.exists() {
+            return Err(Error::ToolFailed("plantuml".to_string(), "no SVG written, named diagrams are not supported".to_string()));
+        }
+
+        finish_svg(&path, config)?;
+    }
+
+    Ok(path)
+}
+
+/// Lay out a linked graphviz file, which is rendered again according to `freshness`
+pub fn parse_dot_from_file(path: &Path, config: &Config) -> Result<PathBuf> {
+    let content = std::fs::read_to_string(path).map_err(Error::Io)?;
+
+    refresh(&config.key(&ContentType::Dot.tagged(&content)), path, config.freshness)?;
+
+    parse_dot(&content, config)
+}
+
+/// Definition selecting the dvisvgm driver of pgf, so that no Ghostscript is needed for DVI files
+///
+/// PDF engines are left to the driver pgf picks for them, the DVI one would break their output.
+fn pgf_driver(config: &Config) -> &'static str {
+    match config.engine.output() {
+        "dvi" => "\\def\\pgfsysdriver{pgfsys-dvisvgm.def}\n",
+        _ => "",
+    }
+}
+
+/// Standalone document of a circuitikz diagram
+///
+/// The diagram is wrapped in a `circuitikz` environment, using the dvisvgm driver of pgf for DVI
+/// engines.
+fn circuit_document(content: &str, config: &Config) -> String {
+    let border = standalone_border(config).unwrap_or_else(|| "1pt".to_string());
+
+    format!("{}\\documentclass[border={}]{{standalone}}\n\\usepackage{{circuitikz}}\n\\begin{{document}}\n\\begin{{circuitikz}}\n{}\\end{{circuitikz}}\n\\end{{document}}",
+        pgf_driver(config), border, content)
+}
+
+/// Parse a circuitikz diagram and convert it to a SVG file
+pub fn parse_circuit(content: &str, config: &Config) -> Result<PathBuf> {
+    let source = source_path(&ContentType::Circuit, content, config);
+
+    if !source.exists() {
+        std::fs::write(&source, circuit_document(content, config)).map_err(Error::Io)?;
     }
 
-    generate_svg_from_latex(&path, zoom)
+    // standalone sets the page size to the bordered content
+    let bbox = bbox("papersize", true, config);
+
+    generate_svg_from_latex(&source, &svg_path(&ContentType::Circuit, content, 1.0, config), 1.0, bbox, config.timeout_plot, config)
+}
+
+/// Whether an environment written in the document is a TikZ picture
+fn is_tikz(content: &str) -> bool {
+    content.starts_with("\\begin{tikzpicture}")
+}
+
+/// Compile an environment found in the document, like `align` or `tikzpicture`, standalone
+///
+/// The `varwidth` option allows display environments in the box of standalone. TikZ pictures load
+/// tikz, with the dvisvgm driver for DVI engines, and get the longer timeout of plots, they can
+/// take a while.
+pub fn parse_environment(content: &str, config: &Config) -> Result<PathBuf> {
+    let source = source_path(&ContentType::Environment, content, config);
+
+    if !source.exists() {
+        let mut file = File::create(&source).map_err(Error::Io)?;
+        let border = standalone_border(config).unwrap_or_else(|| "1pt".to_string());
+
+        let mut doc = format!("\\documentclass[20pt, varwidth, border={}]{{standalone}}\n\\usepackage{{amsmath}}\\usepackage{{amsfonts}}\n", border);
+        if is_tikz(content) {
+            doc = format!("{}{}\\usepackage{{tikz}}\n", pgf_driver(config), doc);
+        }
+        if !config.preamble.is_empty() {
+            doc.push_str(&config.preamble);
+            doc.push('\n');
+        }
+        doc.push_str(&format!("\\begin{{document}}\n{}\n\\end{{document}}", content.trim_end()));
+
+        file.write_all(doc.as_bytes()).map_err(Error::Io)?;
+    }
+
+    let timeout = if is_tikz(content) { config.timeout_plot } else { config.timeout_equation };
+
+    generate_svg_from_latex(&source, &svg_path(&ContentType::Environment, content, 1.0, config), 1.0, bbox("papersize", true, config), timeout, config)
 }
 
 /// Generate latex file from gnuplot
 ///
 /// This function generates a latex file with gnuplot `epslatex` backend and then source it into
 /// the generate latex function
-pub fn generate_latex_from_gnuplot(content: &str) -> Result<PathBuf> {
-    let path = Path::new(ART_PATH)
-        .join(hash(content))
-        .with_extension("tex");
+pub fn generate_latex_from_gnuplot(content: &str, config: &Config) -> Result<PathBuf> {
+    let path = source_path(&ContentType::Gnuplot, content, config);
 
     let gnuplot_path = which::which("gnuplot").map_err(Error::BinaryNotFound)?;
 
-    let cmd = Command::new(gnuplot_path)
-        .stdin(Stdio::piped())
-        .current_dir(ART_PATH)
-        .arg("-p")
-        .spawn()
-        .unwrap();
-    //.expect("Could not spawn gnuplot");
-
-    let mut stdin = cmd.stdin.unwrap();
-
-    stdin
-        .write_all(
-            format!(
-                "set output '{}'\n",
-                path.file_name().unwrap().to_str().unwrap()
-            )
-            .as_bytes(),
-        )
-        .map_err(Error::Io)?;
-    stdin
-        .write_all("set terminal epslatex color standalone\n".as_bytes())
-        .map_err(Error::Io)?;
-    stdin.write_all(content.as_bytes()).map_err(Error::Io)?;
+    // the script is run from a file, so that gnuplot exits with an error status on mistakes
+    let mut script = format!("set output '{}'\n", path.file_name().unwrap().to_str().unwrap());
+    script.push_str("set terminal epslatex color standalone\n");
+
+    // put before the script, so that an explicit format of the user wins
+    if let Some(digits) = config.plot_significant_figures {
+        script.push_str(&format!("set format '%.{}g'\n", digits));
+    }
+    script.push_str(content);
+    script.push('\n');
+
+    std::fs::write(path.with_extension("gp"), script).map_err(Error::Io)?;
+
+    let cmd = runner::runner(config).output(Command::new(gnuplot_path)
+        .current_dir(art_path())
+        .arg(path.with_extension("gp").file_name().unwrap()), config.timeout_plot)?;
+
+    if !cmd.status.success() || !path.exists() {
+        let _ = std::fs::remove_file(&path);
+        return Err(Error::InvalidGnuplot(String::from_utf8_lossy(&cmd.stderr).to_string()));
+    }
 
     Ok(path)
 }
 
-pub fn generate_latex_from_gnuplot_file(path: &Path) -> Result<PathBuf> {
-    let mut content = String::new();
-    let mut f = File::open(path).map_err(Error::Io)?;
-    f.read_to_string(&mut content).unwrap();
+/// Evict the artifacts of `key` if they are stale according to the policy
+fn refresh(key: &str, source: &Path, policy: FreshnessPolicy) -> Result<()> {
+    let artifact = art_path().join(key).with_extension("svg");
+
+    if artifact.exists() && !policy.is_fresh(&artifact, source) {
+        cache::evict(key)?;
+    }
+
+    Ok(())
+}
+
+/// Rasterize a page of a PDF to a PNG file in the cache
+///
+/// The page is `pdf_page`, counted from one, at a resolution of `pdf_dpi`. `pdftocairo` of poppler
+/// is preferred, `mutool` of MuPDF is used otherwise. The artifact is named after the file, page
+/// and resolution, edits of the PDF are picked up according to the freshness policy.
+pub fn parse_pdf(path: &Path, config: &Config) -> Result<PathBuf> {
+    let (page, dpi) = (config.pdf_page, config.pdf_dpi);
+    let source = std::fs::canonicalize(path).map_err(|_| Error::FileNotFound(path.to_path_buf()))?;
+    let png_path = art_path()
+        .join(hash(&format!("{}:{}:{}", source.display(), page, dpi)))
+        .with_extension("png");
+
+    if png_path.exists() && !config.freshness.is_fresh(&png_path, &source) {
+        std::fs::remove_file(&png_path).map_err(Error::Io)?;
+    }
+
+    if png_path.exists() {
+        return Ok(png_path);
+    }
+
+    let (binary, mut cmd) = if let Ok(pdftocairo) = which::which("pdftocairo") {
+        let mut cmd = Command::new(pdftocairo);
+        cmd.arg("-png").arg("-singlefile").arg("-transp")
+            .arg("-f").arg(page.to_string())
+            .arg("-l").arg(page.to_string())
+            .arg("-r").arg(dpi.to_string())
+            .arg(&source)
+            // the extension is appended by pdftocairo
+            .arg(png_path.with_extension(""));
+
+        ("pdftocairo", cmd)
+    } else {
+        let mut cmd = Command::new(which::which("mutool").map_err(Error::BinaryNotFound)?);
+        cmd.arg("draw")
+            .arg("-r").arg(dpi.to_string())
+            .arg("-o").arg(&png_path)
+            .arg(&source)
+            .arg(page.to_string());
+
+        ("mutool", cmd)
+    };
+
+    let output = runner::runner(config).output(&mut cmd, config.timeout_plot)?;
+
+    if !output.status.success() || !png_path.exists() {
+        let _ = std::fs::remove_file(&png_path);
+        return Err(Error::ToolFailed(binary.to_string(), String::from_utf8_lossy(&output.stderr).to_string()));
+    }
+
+    Ok(png_path)
+}
+
+/// Extensions of linked videos, shown as a thumbnail
+pub const VIDEO_EXTENSIONS: [&str; 4] = ["mp4", "webm", "mkv", "mov"];
+
+/// Extract a frame of a video with `ffmpeg` to a PNG file in the cache, with a play button on it
+///
+/// The frame is taken `video_seek` into the video, or from its start for shorter ones. Like PDF
+/// pages the artifact is named after the file and edits are picked up by the freshness policy.
+pub fn parse_video(path: &Path, config: &Config) -> Result<PathBuf> {
+    let source = std::fs::canonicalize(path).map_err(|_| Error::FileNotFound(path.to_path_buf()))?;
+    let png_path = art_path()
+        .join(hash(&format!("video:{}:{}", source.display(), config.video_seek.as_secs_f32())))
+        .with_extension("png");
+
+    if png_path.exists() && !config.freshness.is_fresh(&png_path, &source) {
+        std::fs::remove_file(&png_path).map_err(Error::Io)?;
+    }
+
+    if png_path.exists() {
+        return Ok(png_path);
+    }
+
+    let ffmpeg = which::which("ffmpeg").map_err(Error::BinaryNotFound)?;
+    // the frame gets its button in a file of its own, the cached thumbnail appears complete or not
+    let frame_path = png_path.with_extension("frame.png");
+    // seeking before the input jumps to the closest keyframe instead of decoding up to it
+    let extract = |seek: Duration| runner::runner(config).output(Command::new(&ffmpeg)
+        .arg("-nostdin").arg("-y")
+        .arg("-loglevel").arg("error")
+        .arg("-ss").arg(format!("{:.3}", seek.as_secs_f32()))
+        .arg("-i").arg(&source)
+        .arg("-frames:v").arg("1")
+        .arg("-vf").arg("scale=-2:'min(720,ih)'")
+        .arg(&frame_path), config.timeout_plot);
+
+    // past the end ffmpeg succeeds without writing a frame
+    let mut output = extract(config.video_seek)?;
+    if output.status.success() && !frame_path.exists() {
+        output = extract(Duration::ZERO)?;
+    }
+
+    if !output.status.success() || !frame_path.exists() {
+        let _ = std::fs::remove_file(&frame_path);
+        return Err(Error::ToolFailed("ffmpeg".to_string(), String::from_utf8_lossy(&output.stderr).to_string()));
+    }
+
+    let thumbnail = render::load_raster(&frame_path).and_then(|mut thumbnail| {
+        render::play_button(&mut thumbnail);
+        thumbnail.save(&frame_path)
+            .map_err(|_| Error::InvalidImage(frame_path.to_string_lossy().to_string()))
+    });
+    if let Err(err) = thumbnail {
+        let _ = std::fs::remove_file(&frame_path);
+        return Err(err);
+    }
+
+    std::fs::rename(&frame_path, &png_path).map_err(Error::Io)?;
+
+    Ok(png_path)
+}
+
+pub fn generate_latex_from_gnuplot_file(path: &Path, config: &Config) -> Result<PathBuf> {
+    let content = std::fs::read_to_string(path).map_err(Error::Io)?;
+
+    refresh(&config.key(&ContentType::Gnuplot.tagged(&content)), path, config.freshness)?;
 
-    let path = generate_latex_from_gnuplot(&content)?;
-    generate_svg_from_latex(&path, 1.0)
+    let source = generate_latex_from_gnuplot(&content, config)?;
+    generate_svg_from_latex(&source, &svg_path(&ContentType::Gnuplot, &content, 1.0, config), 1.0, bbox("papersize", false, config), config.timeout_plot, config)
 }
 
 /// Parse a latex content and convert it to a SVG file
-pub fn parse_latex(content: &str) -> Result<PathBuf> {
-    let path = Path::new(ART_PATH)
-        .join(hash(content))
-        .with_extension("svg");
+pub fn parse_latex(content: &str, config: &Config) -> Result<PathBuf> {
+    let source = source_path(&ContentType::Tex, content, config);
+    let path = svg_path(&ContentType::Tex, content, 1.0, config);
 
     // create a new tex file containing the equation
-    if !path.with_extension("tex").exists() {
-        let mut file = File::create(&path.with_extension("tex")).map_err(Error::Io)?;
+    if !source.exists() {
+        let mut file = File::create(&source).map_err(Error::Io)?;
 
         file.write_all(content.as_bytes()).map_err(Error::Io)?;
     }
 
     if !path.exists() {
-        generate_svg_from_latex(&path, 1.0)?;
+        generate_svg_from_latex(&source, &path, 1.0, bbox("papersize", false, config), config.timeout_plot, config)?;
     }
 
     Ok(path)
 }
 
-pub fn parse_latex_from_file(path: &Path) -> Result<PathBuf> {
-    let mut content = String::new();
-    let mut f = File::open(path).map_err(Error::Io)?;
-    f.read_to_string(&mut content).unwrap();
+pub fn parse_latex_from_file(path: &Path, config: &Config) -> Result<PathBuf> {
+    let content = std::fs::read_to_string(path).map_err(Error::Io)?;
+
+    refresh(&config.key(&ContentType::Tex.tagged(&content)), path, config.freshness)?;
+
+    parse_latex(&content, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pieces of latex logs, glued together at random by the property tests
+    const LOG_PIECES: [&str; 14] = [
+        "! ", "l.", "l.12 ", "12", " ", "\n", "\\foo", "Emergency stop.", "Undefined control sequence.",
+        "! Missing $ inserted.", "l.3 $x", "ü", "\r\n", "(./snippet.tex",
+    ];
+
+    /// Deterministic xorshift generator, tests stay reproducible without a dependency
+    fn random_logs(count: usize) -> impl Iterator<Item = String> {
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        (0..count).map(move |_| {
+            let len = next() % 40;
+            (0..len).map(|_| LOG_PIECES[(next() % LOG_PIECES.len() as u64) as usize]).collect()
+        })
+    }
+
+    #[test]
+    fn pgf_driver_of_dvi_engines() {
+        let mut config = Config::new();
+        assert!(pgf_driver(&config).contains("pgfsys-dvisvgm.def"));
+
+        for engine in ["pdflatex", "lualatex", "xelatex", "tectonic"] {
+            config.set("engine", engine).unwrap();
+            assert_eq!(pgf_driver(&config), "");
+        }
+    }
+
+    /// Circuit of the example in the README, a battery driving a resistor
+    const CIRCUIT: &str = "\\draw (0,0) to[battery1, l=$U$] (0,2) to[R, l=$R$] (2,2) -- (2,0) -- (0,0);\n";
+
+    #[test]
+    fn circuit_documents() {
+        let mut config = Config::new();
+        let doc = circuit_document(CIRCUIT, &config);
+
+        assert!(doc.starts_with("\\def\\pgfsysdriver{pgfsys-dvisvgm.def}\n\\documentclass[border=1pt]{standalone}"));
+        assert!(doc.contains("\\usepackage{circuitikz}"));
+        assert!(doc.contains(&format!("\\begin{{circuitikz}}\n{}\\end{{circuitikz}}", CIRCUIT)));
+
+        config.set("border_lrtb", "1,2,3,4").unwrap();
+        config.set("engine", "pdflatex").unwrap();
+        assert!(circuit_document(CIRCUIT, &config).starts_with("\\documentclass[border={1pt 4pt 2pt 3pt}]{standalone}"));
+    }
 
-    parse_latex(&content)
+    /// Renders the example end to end, where latex and dvisvgm are installed
+    #[test]
+    fn circuit_end_to_end() {
+        if which::which("latex").is_err() || which::which("dvisvgm").is_err() {
+            return;
+        }
+        render::test_art_path();
+
+        match ContentType::Circuit.render(CIRCUIT, &Config::new()) {
+            Ok(info) => assert!(info.width > 0.0 && info.height > 0.0),
+            Err(err) => assert!(matches!(err, Error::MissingPackage(ref x) if x == "circuitikz"), "{}", err),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn versions_of_binaries() {
+        let runner = runner::VersionRunner(vec![
+            ("latex", "MiKTeX-pdfTeX 4.10 (MiKTeX 22.7)\n"),
+            ("tectonic", "Tectonic 0.14.1\n"),
+        ]);
+        assert_eq!(tex_distribution(&runner), Some(TexDistribution::MikTex("22.7".to_string())));
+
+        let runner = runner::VersionRunner(vec![("tectonic", "Tectonic 0.14.1\n")]);
+        assert_eq!(tex_distribution(&runner), Some(TexDistribution::Tectonic("0.14.1".to_string())));
+
+        assert_eq!(parse_dvisvgm_version("dvisvgm 3.1.2\n"), Some((3, 1)));
+        assert_eq!(parse_dvisvgm_version("dvisvgm\n"), None);
+    }
+
+    /// A script stands in for pdfcrop, the cropper runs between a PDF engine and dvisvgm
+    #[cfg(unix)]
+    #[test]
+    fn pdfs_are_cropped() {
+        let dir = render::test_art_path().join("crop");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("crop.sh"), "test \"$1 $2\" = '--margins 3' && printf cropped > \"$4\"").unwrap();
+        let pdf = dir.join("page.pdf");
+        std::fs::write(&pdf, "page").unwrap();
+
+        let mut config = Config::new();
+        config.set("engine", "pdflatex").unwrap();
+        config.set("pdf_crop", &format!("sh {}", dir.join("crop.sh").display())).unwrap();
+        config.set("pdf_crop_margin", "3").unwrap();
+        assert_eq!(config.engine.output(), "pdf");
+
+        crop_pdf(&pdf, Duration::from_secs(10), &config).unwrap();
+        assert_eq!(std::fs::read_to_string(&pdf).unwrap(), "cropped");
+
+        // a failed crop leaves no uncropped PDF behind, which would be converted next time
+        config.set("pdf_crop", "false").unwrap();
+        assert!(matches!(crop_pdf(&pdf, Duration::from_secs(10), &config), Err(Error::CropFailed(_))));
+        assert!(!pdf.exists());
+    }
+
+    #[test]
+    fn latex_log_error() {
+        let log = "This is pdfTeX\n! Undefined control sequence.\nl.5 \\foo\n               {x}\n! Emergency stop.\n";
+
+        assert_eq!(parse_latex_log(log), ("Undefined control sequence.".to_string(), "\\foo".to_string(), 5));
+    }
+
+    #[test]
+    fn latex_log_without_error() {
+        assert_eq!(parse_latex_log(""), (String::new(), String::new(), usize::MAX));
+        assert_eq!(parse_latex_log("! Emergency stop.\nl.1 x"), (String::new(), String::new(), usize::MAX));
+        assert_eq!(parse_latex_log("! Missing $ inserted.\n"), ("Missing $ inserted.".to_string(), String::new(), usize::MAX));
+    }
+
+    #[test]
+    fn latex_log_properties() {
+        for log in random_logs(5000) {
+            let (reason, element, line) = parse_latex_log(&log);
+
+            if reason.is_empty() {
+                assert!(element.is_empty() && line == usize::MAX, "context without error in {:?}", log);
+                continue;
+            }
+
+            assert!(log.lines().any(|x| x.strip_prefix("! ").is_some_and(|x| x.trim() == reason)), "reason {:?} not in {:?}", reason, log);
+            assert!(!reason.contains("Emergency stop"));
+            if line != usize::MAX {
+                assert!(log.contains(&format!("l.{}", line)), "line {} not in {:?}", line, log);
+            }
+            if !element.is_empty() {
+                assert!(log.lines().any(|x| x.starts_with("l.") && x.contains(&element)), "element {:?} not in {:?}", element, log);
+            }
+        }
+    }
 }